@@ -2,11 +2,13 @@ extern crate file_system_lib;
 extern crate param_lib;
 
 use file_system_lib::{
-    iblock, Dirent, INodeDisk, SuperBlock, BLOCK_SIZE, DIRECTORY_SIZE, DIRECT_COUNT, FSMAGIC,
-    INDIRECT_COUNT, IPB, MAX_FILE_COUNT, ROOT_INO, TYPE_DIR, TYPE_FILE,
+    iblock, record_length, Dirent, INodeDisk, LongDirent, SuperBlock, BLOCK_SIZE, DIRECTORY_SIZE, DIRECT_COUNT,
+    DEFAULT_DIR_MODE, DEFAULT_FILE_MODE, DEFAULT_SYMLINK_MODE, DOUBLE_INDIRECT, FEATURE_LONG_NAMES, FSMAGIC, INDIRECT,
+    INDIRECT_COUNT, IPB, MAX_FILE_COUNT, MAX_NAME_LENGTH, MODE_MASK, ROOT_INO, ROOT_UID, TYPE_DIR, TYPE_FILE,
+    TYPE_SYMLINK,
 };
 use lazy_static::lazy_static;
-use param_lib::{FILE_SYSTEM_SIZE, LOG_SIZE};
+use param_lib::{FILE_SYSTEM_SIZE, LOG_SIZE, SWAP_SIZE};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::CString;
@@ -16,6 +18,7 @@ use std::mem;
 use std::process;
 use std::ptr;
 use std::slice;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::sync::{
     atomic::{AtomicU32, Ordering},
     Mutex,
@@ -25,9 +28,10 @@ const NINODES: u32 = 200;
 const NBITMAP: u32 = FILE_SYSTEM_SIZE / (BLOCK_SIZE as u32 * 8) + 1;
 const NINODEBLOCKS: u32 = NINODES / IPB + 1;
 const NLOG: u32 = LOG_SIZE as u32;
+const NSWAP: u32 = SWAP_SIZE;
 
 const NMETA: u32 = 2 + NLOG + NINODEBLOCKS + NBITMAP;
-const NBLOCKS: u32 = FILE_SYSTEM_SIZE - NMETA;
+const NBLOCKS: u32 = FILE_SYSTEM_SIZE - NMETA - NSWAP;
 
 #[allow(non_upper_case_globals)]
 static freeinode: AtomicU32 = AtomicU32::new(1);
@@ -37,21 +41,27 @@ static freeblock: AtomicU32 = AtomicU32::new(NMETA);
 lazy_static! {
     static ref SUPERBLOCK: Mutex<SuperBlock> = Mutex::new(SuperBlock {
         magic: FSMAGIC,
-        size: xint(FILE_SYSTEM_SIZE),
+        size: xint(FILE_SYSTEM_SIZE - NSWAP),
         blocks_number: xint(NBLOCKS),
         inode_number: xint(NINODES),
         log_number: xint(NLOG),
         log_start: xint(2),
         inode_start: xint(2 + NLOG),
-        block_map_start: xint(2 + NLOG + NINODEBLOCKS)
+        block_map_start: xint(2 + NLOG + NINODEBLOCKS),
+        swap_start: xint(FILE_SYSTEM_SIZE - NSWAP),
+        swap_number: xint(NSWAP),
+        features: xint(if *FIXED_NAMES { 0 } else { FEATURE_LONG_NAMES })
     });
     static ref ARGS: Vec<String> = {
         if env::args().len() < 2 {
-            eprintln!("Usage: mkfs fs.img files...");
+            eprintln!("Usage: mkfs fs.img [--fixed-names] files...");
             process::exit(1);
         }
         env::args().collect()
     };
+    // Directories of 16-byte entries with names of up to 13 bytes,
+    // for kernels that predate FEATURE_LONG_NAMES.
+    static ref FIXED_NAMES: bool = ARGS.get(2).map_or(false, |arg| arg == "--fixed-names");
     static ref FSFD: Mutex<File> = {
         assert_eq!(0, BLOCK_SIZE % mem::size_of::<INodeDisk>());
         assert_eq!(0, BLOCK_SIZE % mem::size_of::<Dirent>());
@@ -90,8 +100,8 @@ fn xint(x: u32) -> u32 {
 }
 
 fn main() {
-    println!("nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) blocks {} total {}",
-            NMETA, NLOG, NINODEBLOCKS, NBITMAP, NBLOCKS, FILE_SYSTEM_SIZE);
+    println!("nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) blocks {} swap {} total {}",
+            NMETA, NLOG, NINODEBLOCKS, NBITMAP, NBLOCKS, NSWAP, FILE_SYSTEM_SIZE);
     let mut buf = [0u8; BLOCK_SIZE];
     for i in 0..FILE_SYSTEM_SIZE {
         wsect(i, &buf);
@@ -106,72 +116,90 @@ fn main() {
     }
     wsect(1, &buf);
 
-    let mut root_tree = DirectoryTree::new();
+    let mut root_tree = DirectoryTree::new(None);
     assert_eq!(root_tree.ino, ROOT_INO);
 
-    let mut de = new_dirent_with_inum_name(root_tree.ino, ".");
-    iappend(root_tree.ino, &mut de, mem::size_of::<Dirent>());
-
-    de = new_dirent_with_inum_name(root_tree.ino, "..");
-    iappend(root_tree.ino, &mut de, mem::size_of::<Dirent>());
-
-    for i in 2..ARGS.len() {
+    for i in (2 + *FIXED_NAMES as usize)..ARGS.len() {
         let mut filename = String::from("target/riscv64gc-unknown-none-elf/debug/");
-        let mut args_iter = ARGS[i].split(':');
+        // a trailing =mode[,uid[,gid]] sets a file's octal mode and owner.
+        let (arg, owner) = match ARGS[i].split_once('=') {
+            Some((arg, owner)) => { (arg, Some(parse_owner(owner))) }
+            None => { (ARGS[i].as_str(), None) }
+        };
+        let mut args_iter = arg.split(':');
         let shortname = args_iter.next().unwrap();
+        // name@target makes a symbolic link instead of copying a file.
+        let link = shortname.split_once('@');
         filename.push_str(shortname);
-        if let Ok(mut fd) = File::open(&filename) {
-            let mut father_tree = &mut root_tree;
-
-            if let Some(x) = args_iter.next() {
-                args_iter = x.split('/');
-                while let Some(x) = args_iter.next() {
-                    if x != "" {
-                        let dir_name = String::from(x);
-
-                        if father_tree.subdirectory.contains_key(&dir_name) {
-                            father_tree = father_tree.subdirectory.get_mut(&dir_name).unwrap();
-                        } else {
-                            father_tree = father_tree.subdirectory.entry(dir_name).or_insert({
-                                let dir = DirectoryTree::new();
-
-                                de = new_dirent_with_inum_name(dir.ino, x);
-                                iappend(father_tree.ino, &mut de, mem::size_of::<Dirent>());
-                                de = new_dirent_with_inum_name(dir.ino, ".");
-                                iappend(dir.ino, &mut de, mem::size_of::<Dirent>());
-                                de = new_dirent_with_inum_name(father_tree.ino, "..");
-                                iappend(dir.ino, &mut de, mem::size_of::<Dirent>());
-
-                                dir
-                            });
-                        }
-                    }
+        let mut fd = None;
+        if link.is_none() {
+            match File::open(&filename) {
+                Ok(file) => { fd = Some(file); }
+                Err(_) => {
+                    eprintln!("{}", ARGS[i]);
+                    process::exit(1);
                 }
             }
+        }
 
-            let inum = ialloc(TYPE_FILE);
+        let mut father_tree = &mut root_tree;
 
-            de = new_dirent_with_inum_name(inum, shortname);
-            iappend(father_tree.ino, &mut de, mem::size_of::<Dirent>());
+        if let Some(x) = args_iter.next() {
+            args_iter = x.split('/');
+            while let Some(x) = args_iter.next() {
+                if x != "" {
+                    let dir_name = String::from(x);
 
-            while let Ok(i) = fd.read(&mut buf) {
-                if i == 0 {
-                    break;
+                    if !father_tree.subdirectory.contains_key(&dir_name) {
+                        let dir = DirectoryTree::new(Some(father_tree.ino));
+                        father_tree.link(x, dir.ino, TYPE_DIR);
+                        father_tree.subdirectory.insert(dir_name.clone(), dir);
+                    }
+                    father_tree = father_tree.subdirectory.get_mut(&dir_name).unwrap();
                 }
-                iappend(inum, &mut buf, i);
             }
-        } else {
-            eprintln!("{}", ARGS[i]);
-            process::exit(1);
         }
+
+        if let Some((name, target)) = link {
+            assert_eq!(true, !target.is_empty() && target.len() <= BLOCK_SIZE);
+            let inum = ialloc(TYPE_SYMLINK, now());
+            father_tree.link(name, inum, TYPE_SYMLINK);
+
+            buf[..target.len()].copy_from_slice(target.as_bytes());
+            iappend(inum, &mut buf, target.len());
+            continue;
+        }
+
+        let mut fd = fd.unwrap();
+        let modified = fd.metadata().and_then(|it| it.modified()).map_or(now(), seconds_since_epoch);
+        let inum = ialloc(TYPE_FILE, modified);
+        father_tree.link(shortname, inum, TYPE_FILE);
+
+        let mut elf = None;
+        while let Ok(i) = fd.read(&mut buf) {
+            if i == 0 {
+                break;
+            }
+            elf.get_or_insert(buf[..i].starts_with(b"\x7fELF"));
+            iappend(inum, &mut buf, i);
+        }
+
+        // programs are executable by everyone unless told otherwise.
+        let default_mode = if elf == Some(true) { 0o755 } else { DEFAULT_FILE_MODE };
+        let (mode, uid, gid) = owner.unwrap_or((default_mode, ROOT_UID, ROOT_UID));
+        ichown(inum, mode, uid, gid);
     }
 
-    let mut din = INodeDisk::new();
-    rinode(root_tree.ino, &mut din);
-    let mut off = xint(din.size);
-    off = ((off / BLOCK_SIZE as u32) + 1) * BLOCK_SIZE as u32;
-    din.size = xint(off);
-    winode(root_tree.ino, &din);
+    root_tree.write();
+
+    if *FIXED_NAMES {
+        let mut din = INodeDisk::new();
+        rinode(root_tree.ino, &mut din);
+        let mut off = xint(din.size);
+        off = ((off / BLOCK_SIZE as u32) + 1) * BLOCK_SIZE as u32;
+        din.size = xint(off);
+        winode(root_tree.ino, &din);
+    }
 
     balloc(freeblock.load(Ordering::Relaxed) as usize);
 
@@ -222,7 +250,16 @@ fn rsect<T>(sec: u32, buf: &mut [T]) {
     .expect("read");
 }
 
-fn ialloc(t: u16) -> u32 {
+fn now() -> u64 {
+    seconds_since_epoch(SystemTime::now())
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |it| it.as_secs())
+}
+
+// time stamps the inode's atime, mtime and ctime.
+fn ialloc(t: u16, time: u64) -> u32 {
     let inum: u32 = freeinode.load(Ordering::Relaxed);
     freeinode.store(inum + 1, Ordering::Relaxed);
 
@@ -230,22 +267,77 @@ fn ialloc(t: u16) -> u32 {
     din.types = xshort(t);
     din.nlink = xshort(1);
     din.size = xint(0);
+    din.atime = time.to_le();
+    din.mtime = time.to_le();
+    din.ctime = time.to_le();
+    din.mode = xint(match t {
+        TYPE_DIR => { DEFAULT_DIR_MODE }
+        TYPE_SYMLINK => { DEFAULT_SYMLINK_MODE }
+        _ => { DEFAULT_FILE_MODE }
+    });
+    din.uid = xint(ROOT_UID);
+    din.gid = xint(ROOT_UID);
     winode(inum, &din);
     inum
 }
 
+fn ichown(inum: u32, mode: u32, uid: u32, gid: u32) {
+    let mut din = INodeDisk::new();
+    rinode(inum, &mut din);
+    din.mode = xint(mode);
+    din.uid = xint(uid);
+    din.gid = xint(gid);
+    winode(inum, &din);
+}
+
+// "mode[,uid[,gid]]" with an octal mode; the group defaults to the owner's id.
+fn parse_owner(owner: &str) -> (u32, u32, u32) {
+    let mut fields = owner.split(',');
+    let mode = fields.next().and_then(|it| u32::from_str_radix(it, 8).ok()).filter(|&it| it & !MODE_MASK == 0);
+    let uid = fields.next().map_or(Some(ROOT_UID), |it| it.parse().ok());
+    let gid = fields.next().map_or(uid, |it| it.parse().ok());
+    match (mode, uid, gid, fields.next()) {
+        (Some(mode), Some(uid), Some(gid), None) => { (mode, uid, gid) }
+        _ => {
+            eprintln!("bad mode or owner {}", owner);
+            process::exit(1);
+        }
+    }
+}
+
 fn balloc(used: usize) {
     println!("balloc: first {} blocks have been allocated", used);
-    assert_eq!(true, used < BLOCK_SIZE * 8);
-    let mut buf = [0u8; BLOCK_SIZE];
-    for i in 0..used {
-        buf[i / 8] = buf[i / 8] | (0x1 << (i % 8));
+    assert_eq!(true, used < BLOCK_SIZE * 8 * NBITMAP as usize);
+    let bits = BLOCK_SIZE * 8;
+    for block in 0..((used + bits - 1) / bits) {
+        let mut buf = [0u8; BLOCK_SIZE];
+        for i in (block * bits)..used.min((block + 1) * bits) {
+            let bi = i - block * bits;
+            buf[bi / 8] = buf[bi / 8] | (0x1 << (bi % 8));
+        }
+        let sector = xint(SUPERBLOCK.lock().unwrap().block_map_start) + block as u32;
+        println!("balloc: write bitmap block at sector {}", sector);
+        wsect(sector, &buf);
     }
-    println!(
-        "balloc: write bitmap block at sector {}",
-        SUPERBLOCK.lock().unwrap().block_map_start
-    );
-    wsect(SUPERBLOCK.lock().unwrap().block_map_start, &buf);
+}
+
+// Take the next free block.
+fn next_block() -> u32 {
+    let b = freeblock.load(Ordering::Relaxed);
+    freeblock.store(b + 1, Ordering::Relaxed);
+    b
+}
+
+// Return entry index of the indirect block at sector,
+// allocating a block for it if it is 0.
+fn indirect_entry(sector: u32, index: usize) -> u32 {
+    let mut indirect = [0u32; INDIRECT_COUNT];
+    rsect(sector, &mut indirect);
+    if indirect[index] == 0 {
+        indirect[index] = xint(next_block());
+        wsect(sector, &indirect);
+    }
+    xint(indirect[index])
 }
 
 fn iappend<T>(inum: u32, xp: &mut T, mut n: usize) {
@@ -262,23 +354,21 @@ fn iappend<T>(inum: u32, xp: &mut T, mut n: usize) {
         assert_eq!(true, fbn < MAX_FILE_COUNT);
         if fbn < DIRECT_COUNT {
             if xint(din.addr[fbn]) == 0 {
-                din.addr[fbn] = xint(freeblock.load(Ordering::Relaxed));
-                freeblock.store(freeblock.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                din.addr[fbn] = xint(next_block());
             }
             x = xint(din.addr[fbn]);
-        } else {
-            if xint(din.addr[DIRECT_COUNT]) == 0 {
-                din.addr[DIRECT_COUNT] = xint(freeblock.load(Ordering::Relaxed));
-                freeblock.store(freeblock.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        } else if fbn < DIRECT_COUNT + INDIRECT_COUNT {
+            if xint(din.addr[INDIRECT]) == 0 {
+                din.addr[INDIRECT] = xint(next_block());
             }
-            let mut indirect = [0u32; INDIRECT_COUNT];
-            rsect(xint(din.addr[DIRECT_COUNT]), &mut indirect);
-            if indirect[fbn - DIRECT_COUNT] == 0 {
-                indirect[fbn - DIRECT_COUNT] = xint(freeblock.load(Ordering::Relaxed));
-                freeblock.store(freeblock.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
-                wsect(xint(din.addr[DIRECT_COUNT]), &indirect);
+            x = indirect_entry(xint(din.addr[INDIRECT]), fbn - DIRECT_COUNT);
+        } else {
+            if xint(din.addr[DOUBLE_INDIRECT]) == 0 {
+                din.addr[DOUBLE_INDIRECT] = xint(next_block());
             }
-            x = xint(indirect[fbn - DIRECT_COUNT]);
+            let index = fbn - DIRECT_COUNT - INDIRECT_COUNT;
+            let indirect = indirect_entry(xint(din.addr[DOUBLE_INDIRECT]), index / INDIRECT_COUNT);
+            x = indirect_entry(indirect, index % INDIRECT_COUNT);
         }
         let n1 = n.min((fbn + 1) * BLOCK_SIZE - off);
         rsect(x, &mut buf);
@@ -300,18 +390,76 @@ fn iappend<T>(inum: u32, xp: &mut T, mut n: usize) {
 
 struct DirectoryTree {
     ino: u32,
+    // name, inode number and type of each entry, written out by write()
+    entries: Vec<(String, u32, u16)>,
     subdirectory: HashMap<String, DirectoryTree>,
 }
 
 impl DirectoryTree {
-    fn new() -> DirectoryTree {
-        DirectoryTree {
-            ino: ialloc(TYPE_DIR),
+    // The root directory if parent is None.
+    fn new(parent: Option<u32>) -> DirectoryTree {
+        let mut dir = DirectoryTree {
+            ino: ialloc(TYPE_DIR, now()),
+            entries: Vec::new(),
             subdirectory: HashMap::new(),
+        };
+        dir.link(".", dir.ino, TYPE_DIR);
+        dir.link("..", parent.unwrap_or(dir.ino), TYPE_DIR);
+        dir
+    }
+
+    fn link(&mut self, name: &str, inum: u32, types: u16) {
+        let max = if *FIXED_NAMES { DIRECTORY_SIZE - 1 } else { MAX_NAME_LENGTH };
+        if name.is_empty() || name.len() > max {
+            eprintln!("{}: name too long", name);
+            process::exit(1);
+        }
+        self.entries.push((String::from(name), inum, types));
+    }
+
+    // Write the entries of this directory and of those below it.
+    fn write(&self) {
+        if *FIXED_NAMES {
+            for (name, inum, _) in &self.entries {
+                let mut de = new_dirent_with_inum_name(*inum, name);
+                iappend(self.ino, &mut de, mem::size_of::<Dirent>());
+            }
+        } else {
+            // Records fill whole blocks: the last one in a block gets its slack.
+            let mut block = [0u8; BLOCK_SIZE];
+            let mut used = 0;
+            let mut last = 0;
+            for (name, inum, types) in &self.entries {
+                let length = record_length(name.len());
+                if used + length > BLOCK_SIZE {
+                    set_record_length(&mut block, last, BLOCK_SIZE - last);
+                    iappend(self.ino, &mut block, BLOCK_SIZE);
+                    block = [0u8; BLOCK_SIZE];
+                    used = 0;
+                }
+                let header = mem::size_of::<LongDirent>();
+                block[used..used + 4].copy_from_slice(&inum.to_le_bytes());
+                set_record_length(&mut block, used, length);
+                block[used + 6] = name.len() as u8;
+                block[used + 7] = *types as u8;
+                block[used + header..used + header + name.len()].copy_from_slice(name.as_bytes());
+                last = used;
+                used += length;
+            }
+            set_record_length(&mut block, last, BLOCK_SIZE - last);
+            iappend(self.ino, &mut block, BLOCK_SIZE);
+        }
+
+        for dir in self.subdirectory.values() {
+            dir.write();
         }
     }
 }
 
+fn set_record_length(block: &mut [u8; BLOCK_SIZE], off: usize, length: usize) {
+    block[off + 4..off + 6].copy_from_slice(&(length as u16).to_le_bytes());
+}
+
 pub fn new_dirent_with_inum_name(i: u32, str: &str) -> Dirent {
     Dirent {
         inum: xshort(i as u16),
@@ -7,7 +7,8 @@ extern "Rust" {
 }
 
 #[no_mangle]
-pub extern "C" fn _start(argc: usize, argv: *const *const u8) -> ! {
+pub extern "C" fn _start(argc: usize, argv: *const *const u8, envp: *const *const u8) -> ! {
+    env::init(envp);
     unsafe {
         main(
             from_raw_parts(argv, argc)
@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.len() < 2 {
+        println!("Usage: chmod mode file...");
+        exit(1);
+    }
+    let mode = match u32::from_str_radix(_args[0], 8) {
+        Ok(mode) => { mode }
+        Err(_) => {
+            eprintln!("chmod: invalid mode {}", _args[0]);
+            exit(1);
+        }
+    };
+    for &path in &_args[1..] {
+        if chmod(path, mode) < 0 {
+            eprintln!("chmod {}: failed", path);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.len() < 2 {
+        println!("Usage: chown uid[:gid] file...");
+        exit(1);
+    }
+    // without a group, the one with the same id as the user.
+    let mut ids = _args[0].splitn(2, ':');
+    let uid = ids.next().unwrap().parse::<u32>();
+    let gid = ids.next().map_or(uid.clone(), |it| it.parse::<u32>());
+    let (uid, gid) = match (uid, gid) {
+        (Ok(uid), Ok(gid)) => { (uid, gid) }
+        _ => {
+            eprintln!("chown: invalid owner {}", _args[0]);
+            exit(1);
+        }
+    };
+    for &path in &_args[1..] {
+        if chown(path, uid, gid) < 0 {
+            eprintln!("chown {}: failed", path);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+// Print the environment, one NAME=value per line.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    for (name, value) in env::vars() {
+        println!("{}={}", name, value);
+    }
+}
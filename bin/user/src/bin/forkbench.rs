@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+use core::ptr::write_volatile;
+
+use user::*;
+
+// pages each child touches before it exits
+const CHILD_PAGES: usize = 16;
+
+fn worker(rounds: usize) {
+    for _ in 0..rounds {
+        let pid = fork();
+        if pid < 0 {
+            eprintln!("forkbench: fork failed");
+            exit(1);
+        }
+        if pid == 0 {
+            let memory = sbrk(CHILD_PAGES * 4096);
+            if memory as isize == -1 {
+                exit(1);
+            }
+            for i in 0..CHILD_PAGES {
+                unsafe { write_volatile(memory.add(i * 4096), i as u8); }
+            }
+            exit(0);
+        }
+        let mut status: usize = 0;
+        wait(&mut status as *mut usize);
+    }
+}
+
+// Fork children from several workers at once to stress page allocation.
+// Type ^P afterwards to see how often the per-CPU page lists were contended.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    let workers = _args.get(0).and_then(|it| it.parse().ok()).unwrap_or(3);
+    let rounds = _args.get(1).and_then(|it| it.parse().ok()).unwrap_or(100);
+
+    let start = uptime();
+    for _ in 0..workers {
+        let pid = fork();
+        if pid < 0 {
+            eprintln!("forkbench: fork failed");
+            exit(1);
+        }
+        if pid == 0 {
+            worker(rounds);
+            exit(0);
+        }
+    }
+    for _ in 0..workers {
+        let mut status: usize = 0;
+        wait(&mut status as *mut usize);
+    }
+
+    println!("forkbench: {} workers x {} forks in {} ticks", workers, rounds, uptime() - start);
+}
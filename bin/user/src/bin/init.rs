@@ -5,7 +5,7 @@ use file_control_lib::CONSOLE_ID;
 use user::*;
 
 #[allow(non_upper_case_globals)]
-const exec_argv: [*const u8; 2] = ["sh".as_ptr(), 0 as *const u8];
+const exec_argv: [*const u8; 2] = ["login\0".as_ptr(), 0 as *const u8];
 
 #[no_mangle]
 pub fn main(_args: Vec<&str>) {
@@ -18,24 +18,24 @@ pub fn main(_args: Vec<&str>) {
     dup(0); // stderr
 
     loop {
-        println!("init: starting sh");
+        println!("init: starting login");
         let pid = fork();
         if pid < 0 {
             println!("init: fork failed");
             exit(1);
         }
         if pid == 0 {
-            exec("sh", &exec_argv);
-            println!("init: exec sh failed");
+            exec("login", &exec_argv);
+            println!("init: exec login failed");
             exit(1);
         }
 
         loop {
-            // this call to wait() returns if the shell exits,
+            // this call to wait() returns if the session exits,
             // or if a parentless process exits.
             let wpid = wait(0 as *mut usize);
             if wpid == pid {
-                // the shell exited; restart it.
+                // the session ended; log in again.
                 break;
             } else if wpid < 0 {
                 println!("init: wait returned an error");
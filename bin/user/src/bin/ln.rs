@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.len() == 3 && _args[0] == "-s" {
+        if symlink(_args[1], _args[2]) < 0 {
+            eprintln!("symlink {} {}: failed", _args[1], _args[2]);
+            exit(1);
+        }
+        return;
+    }
+    if _args.len() != 2 {
+        println!("Usage: ln [-s] old new");
+        exit(1);
+    }
+    if link(_args[0], _args[1]) < 0 {
+        eprintln!("link {} {}: failed", _args[0], _args[1]);
+        exit(1);
+    }
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+
+use cstr_core::CString;
+use user::passwd::*;
+use user::*;
+
+fn exists(path: &str) -> bool {
+    let fd = open(path, OPEN_READ_ONLY);
+    if fd < 0 {
+        return false;
+    }
+    close(fd as usize);
+    true
+}
+
+// Make the home directory of account, and any missing
+// directory above it, if it doesn't exist yet.
+fn make_home(account: &Account) {
+    let home = account.home.trim_end_matches('/');
+    let mut end = 0;
+    while end < home.len() {
+        end = home[end + 1..].find('/').map_or(home.len(), |it| it + end + 1);
+        let dir = &home[..end];
+        if dir.is_empty() || exists(dir) {
+            continue;
+        }
+        if mkdir(dir) < 0 {
+            return;
+        }
+        // only the home directory itself belongs to the account.
+        if end == home.len() {
+            chown(dir, account.uid, account.gid);
+        }
+    }
+}
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if getuid() != ROOT_UID {
+        eprintln!("login: must be run by root");
+        exit(1);
+    }
+    loop {
+        let name = match read_line("login: ") {
+            Some(name) => { name }
+            None => { exit(1); }
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let password = match read_line("password: ") {
+            Some(password) => { password }
+            None => { exit(1); }
+        };
+        let account = match account_by_name(&name) {
+            Some(account) if check_password(&name, &password) => { account }
+            _ => {
+                println!("login incorrect");
+                continue;
+            }
+        };
+
+        make_home(&account);
+        if !become_account(&account) {
+            eprintln!("login: cannot become {}", name);
+            exit(1);
+        }
+        let shell = CString::new(account.shell.as_str()).expect("login: CString::new failed");
+        let argv = [shell.as_ptr() as *const u8, 0 as *const u8];
+        exec(&account.shell, &argv);
+        eprintln!("login: exec {} failed", account.shell);
+        exit(1);
+    }
+}
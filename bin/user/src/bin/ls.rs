@@ -1,12 +1,16 @@
 #![no_std]
 #![no_main]
 
+use core::fmt;
 use core::mem::size_of;
 use core::ptr;
 use core::slice::from_raw_parts;
 use core::str::from_utf8_unchecked;
 
-use file_system_lib::{Dirent, FileStatus, DIRECTORY_SIZE, TYPE_DEVICE, TYPE_DIR, TYPE_FILE};
+use file_system_lib::{
+    is_long_name_directory, record_length, Dirent, FileStatus, LongDirent, BLOCK_SIZE, DIRECTORY_SIZE,
+    MAX_NAME_LENGTH, MODE_SETGID, MODE_SETUID, TYPE_DEVICE, TYPE_DIR, TYPE_FILE, TYPE_SYMLINK,
+};
 use user::*;
 
 fn fmtname(path: &str) -> &str {
@@ -37,9 +41,8 @@ fn fmtname(path: &str) -> &str {
     }
 }
 
-fn ls(path: &str) {
+fn ls(path: &str, long: bool) {
     let mut buf = [0u8; 512];
-    let mut de = Dirent::new();
     let mut st = FileStatus::new();
 
     let fd = open(path, OPEN_READ_ONLY);
@@ -56,60 +59,48 @@ fn ls(path: &str) {
     }
 
     match st.types {
-        TYPE_FILE => println!("{} {} {} {}", fmtname(path), st.types, st.ino, st.size),
+        TYPE_FILE => println!("{} {} {} {}{}", fmtname(path), st.types, st.ino, st.size, Details(long, &st)),
         TYPE_DIR => {
-            if path.len() + 1 + DIRECTORY_SIZE + 1 > size_of::<[u8; 512]>() {
+            if path.len() + 1 + MAX_NAME_LENGTH + 1 > size_of::<[u8; 512]>() {
                 println!("ls: path too long");
             } else {
-                let mut p: *mut u8;
-                unsafe {
-                    ptr::copy(path.as_ptr(), buf.as_mut_ptr(), path.len());
-                    p = (buf.as_mut_ptr() as *mut u8).add(path.len());
-                    *p = b'/';
-                    p = p.add(1);
-                }
+                buf[..path.len()].copy_from_slice(path.as_bytes());
+                buf[path.len()] = b'/';
+                let prefix = path.len() + 1;
 
-                while read(fd, &mut de as *mut _ as *mut u8, size_of::<Dirent>())
-                    == size_of::<Dirent>() as isize
-                {
-                    if de.inum != 0 {
-                        unsafe {
-                            ptr::copy(de.name.as_ptr(), p, DIRECTORY_SIZE);
-                            *(p.add(DIRECTORY_SIZE)) = 0;
-                        }
-                        if stat(buf.as_ptr(), &mut st) < 0 {
-                            println!("ls: cannot stat {}", unsafe { from_utf8_unchecked(&buf) });
+                // Both formats of directory are read a block at a time:
+                // long-name records don't cross blocks.
+                let mut block = [0u8; BLOCK_SIZE];
+                let mut long_names = None;
+                loop {
+                    let n = read(fd, block.as_mut_ptr(), BLOCK_SIZE);
+                    if n <= 0 {
+                        break;
+                    }
+                    let block = &block[..n as usize];
+                    let long_names = *long_names.get_or_insert_with(|| is_long_name_directory(block));
+                    let mut off = 0;
+                    while off < block.len() {
+                        let (inum, name) = if long_names {
+                            let header = size_of::<LongDirent>();
+                            let inum = u32::from_le_bytes([block[off], block[off + 1], block[off + 2], block[off + 3]]);
+                            let length = u16::from_le_bytes([block[off + 4], block[off + 5]]) as usize;
+                            let name_length = block[off + 6] as usize;
+                            let name = &block[off + header..off + header + name_length];
+                            off += length.max(record_length(name_length));
+                            (inum, name)
                         } else {
-                            match st.types {
-                                TYPE_DIR => println!(
-                                    "\x1b[34m{}\x1b[0m {} {} {}",
-                                    fmtname(unsafe {
-                                        from_utf8_unchecked(&buf[..strlen(buf.as_ptr())])
-                                    }),
-                                    st.types,
-                                    st.ino,
-                                    st.size
-                                ),
-                                TYPE_FILE => println!(
-                                    "{} {} {} {}",
-                                    fmtname(unsafe {
-                                        from_utf8_unchecked(&buf[..strlen(buf.as_ptr())])
-                                    }),
-                                    st.types,
-                                    st.ino,
-                                    st.size
-                                ),
-                                TYPE_DEVICE => println!(
-                                    "\x1b[33m{}\x1b[0m {} {} {}",
-                                    fmtname(unsafe {
-                                        from_utf8_unchecked(&buf[..strlen(buf.as_ptr())])
-                                    }),
-                                    st.types,
-                                    st.ino,
-                                    st.size
-                                ),
-                                _ => unreachable!(),
-                            }
+                            let de = &block[off..off + size_of::<Dirent>()];
+                            let inum = u16::from_le_bytes([de[0], de[1]]) as u32;
+                            let name = &de[2..];
+                            let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(DIRECTORY_SIZE)];
+                            off += size_of::<Dirent>();
+                            (inum, name)
+                        };
+                        if inum != 0 {
+                            buf[prefix..prefix + name.len()].copy_from_slice(name);
+                            buf[prefix + name.len()] = 0;
+                            ls_entry(&buf, &mut st, long);
                         }
                     }
                 }
@@ -117,15 +108,93 @@ fn ls(path: &str) {
         }
         _ => panic!(),
     }
+    close(fd);
+}
+
+// Print the entry whose '\0' terminated path is in buf.
+fn ls_entry(buf: &[u8; 512], st: &mut FileStatus, long: bool) {
+    let name = unsafe { from_utf8_unchecked(&buf[..strlen(buf.as_ptr())]) };
+    if lstat(buf.as_ptr(), st) < 0 {
+        println!("ls: cannot stat {}", name);
+        return;
+    }
+    let details = Details(long, st);
+    match st.types {
+        TYPE_DIR => println!("\x1b[34m{}\x1b[0m {} {} {}{}", fmtname(name), st.types, st.ino, st.size, details),
+        TYPE_FILE => println!("{} {} {} {}{}", fmtname(name), st.types, st.ino, st.size, details),
+        TYPE_DEVICE => println!("\x1b[33m{}\x1b[0m {} {} {}{}", fmtname(name), st.types, st.ino, st.size, details),
+        TYPE_SYMLINK => {
+            let mut target = [0u8; BLOCK_SIZE];
+            let n = readlink(name, &mut target).max(0) as usize;
+            println!(
+                "\x1b[36m{}\x1b[0m {} {} {}{} -> {}",
+                fmtname(name),
+                st.types,
+                st.ino,
+                st.size,
+                details,
+                unsafe { from_utf8_unchecked(&target[..n]) }
+            )
+        }
+        _ => unreachable!(),
+    }
+}
+
+// With ls -l, the mode, owner, group and modification
+// time as " rwxr-xr-x uid gid YYYY-MM-DD hh:mm" in UTC.
+struct Details<'a>(bool, &'a FileStatus);
+
+impl fmt::Display for Details<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.0 {
+            return Ok(());
+        }
+        let st = self.1;
+        write!(f, " ")?;
+        for (i, c) in b"rwxrwxrwx".iter().enumerate() {
+            let bit = 1 << (8 - i);
+            let special = match i {
+                2 => { MODE_SETUID }
+                5 => { MODE_SETGID }
+                _ => { 0 }
+            };
+            let c = match (st.mode & bit != 0, st.mode & special != 0) {
+                (true, true) => { 's' }
+                (false, true) => { 'S' }
+                (true, false) => { *c as char }
+                (false, false) => { '-' }
+            };
+            write!(f, "{}", c)?;
+        }
+        write!(f, " {} {}", st.uid, st.gid)?;
+
+        let days = (st.mtime / 86400) as i64;
+        let seconds = st.mtime % 86400;
+
+        // days since 1970-01-01 to a civil date, after Howard Hinnant.
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        write!(f, " {:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds / 3600, seconds / 60 % 60)
+    }
 }
 
 #[no_mangle]
 pub fn main(_args: Vec<&str>) {
-    if _args.len() == 0 {
-        ls(".");
+    let long = _args.first() == Some(&"-l");
+    let paths = if long { &_args[1..] } else { &_args[..] };
+    if paths.is_empty() {
+        ls(".", long);
     } else {
-        for i in _args {
-            ls(i);
+        for &path in paths {
+            ls(path, long);
         }
     }
 }
@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.len() != 2 {
+        println!("Usage: mv old new");
+        exit(1);
+    }
+    if rename(_args[0], _args[1]) < 0 {
+        eprintln!("mv {} {}: failed", _args[0], _args[1]);
+        exit(1);
+    }
+}
@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+
+use cstr_core::CString;
+use user::*;
+
+// Run a program with address space randomization turned off,
+// so that every run gets the same layout.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.is_empty() {
+        println!("Usage: norandom program args...");
+        exit(1);
+    }
+
+    let strings: Vec<CString> = _args.iter()
+        .map(|it| CString::new(*it).expect("norandom: CString::new failed"))
+        .collect();
+    let mut argv: Vec<*const u8> = strings.iter().map(|it| it.as_ptr() as *const u8).collect();
+    argv.push(0 as *const u8);
+
+    randomize(0);
+    exec(_args[0], argv.as_slice());
+    eprintln!("norandom: exec {} failed", _args[0]);
+    exit(1);
+}
@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+// Both need a relocation when the program is built as a PIE,
+// so they are only right if exec applied it.
+static MAIN: fn(Vec<&str>) = main;
+static MESSAGE: &str = "relocated";
+
+// Built with -C relocation-model=pie, so exec loads it at a random base
+// unless run under norandom.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    let main_address = main as fn(Vec<&str>) as usize;
+    if MAIN as usize != main_address || MESSAGE != "relocated" {
+        eprintln!("pietest: relocations not applied");
+        exit(1);
+    }
+    println!("pietest: main at {:#x}, {}", main_address, MESSAGE);
+}
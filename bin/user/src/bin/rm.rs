@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.is_empty() {
+        println!("Usage: rm files...");
+        exit(1);
+    } else {
+        for i in 0.._args.len() {
+            if unlink(_args[i]) < 0 {
+                eprintln!("rm: {} failed to delete", _args[i]);
+                break;
+            }
+        }
+    }
+}
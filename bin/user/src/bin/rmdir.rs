@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use file_system_lib::{FileStatus, TYPE_DIR};
+use user::*;
+
+fn is_dir(path: &str) -> bool {
+    let fd = open(path, OPEN_READ_ONLY);
+    if fd < 0 {
+        return false;
+    }
+    let mut st = FileStatus::new();
+    let r = fstat(fd as usize, &mut st as *mut FileStatus);
+    close(fd as usize);
+    r >= 0 && st.types == TYPE_DIR
+}
+
+// Unlike rm, only removes directories; the kernel
+// refuses ones that still have entries.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.is_empty() {
+        println!("Usage: rmdir directories...");
+        exit(1);
+    } else {
+        for i in 0.._args.len() {
+            if !is_dir(_args[i]) {
+                eprintln!("rmdir: {} is not a directory", _args[i]);
+                break;
+            }
+            if unlink(_args[i]) < 0 {
+                eprintln!("rmdir: {} failed to delete", _args[i]);
+                break;
+            }
+        }
+    }
+}
@@ -52,16 +52,44 @@ fn runcmd(cmd: &CMD) {
 
     match cmd {
         CMD::ExecCMD(ecmd) => {
-            if ecmd.borrow().argv[0] == 0 as *const u8 {
+            let ecmd = ecmd.borrow();
+            // leading NAME=value words set the environment of the command.
+            let mut first = 0;
+            while ecmd.argv[first] != 0 as *const u8 {
+                let word = unsafe {
+                    from_utf8_unchecked(from_raw_parts(
+                        ecmd.argv[first],
+                        strlen(ecmd.argv[first]),
+                    ))
+                };
+                match word.find('=') {
+                    Some(i) if i > 0 => {
+                        env::set_var(&word[..i], &word[i + 1..]);
+                        first += 1;
+                    }
+                    _ => break,
+                }
+            }
+            if ecmd.argv[first] == 0 as *const u8 {
                 exit(1);
             }
             let name = unsafe {
                 from_utf8_unchecked(from_raw_parts(
-                    ecmd.borrow().argv[0],
-                    strlen(ecmd.borrow().argv[0]),
+                    ecmd.argv[first],
+                    strlen(ecmd.argv[first]),
                 ))
             };
-            exec(name, &ecmd.borrow().argv);
+            exec(name, &ecmd.argv[first..]);
+            // a bare name is also looked for in the directories of PATH.
+            if !name.contains('/') {
+                let path = env::var("PATH").unwrap_or(String::from("/"));
+                for dir in path.split(':').filter(|it| !it.is_empty()) {
+                    let mut full = String::from(dir.trim_end_matches('/'));
+                    full.push('/');
+                    full.push_str(name);
+                    exec(&full, &ecmd.argv[first..]);
+                }
+            }
             fprintln!(1, "exec {} failed", name);
         }
         CMD::RedirCMD(rcmd) => {
@@ -159,6 +187,12 @@ pub fn main(_args: Vec<&str>) {
             }
             continue;
         }
+        if buf.starts_with(b"export") && strchr(WHITESPACE, buf[6]) {
+            // so is export, for the environment to reach later commands.
+            buf[strlen(buf.as_ptr()) - 1] = 0; // chop \n
+            export(unsafe { from_utf8_unchecked(&buf[6..strlen(buf.as_ptr())]) });
+            continue;
+        }
         if fork1() == 0 {
             unsafe {
                 runcmd(&parsecmd(buf.as_mut_ptr()));
@@ -168,6 +202,23 @@ pub fn main(_args: Vec<&str>) {
     }
 }
 
+// export NAME=value... sets variables; export alone lists them.
+fn export(args: &str) {
+    let mut words = args.split_whitespace().peekable();
+    if words.peek().is_none() {
+        for (name, value) in env::vars() {
+            fprintln!(1, "export {}={}", name, value);
+        }
+        return;
+    }
+    for word in words {
+        match word.find('=') {
+            Some(i) if i > 0 => { env::set_var(&word[..i], &word[i + 1..]); }
+            _ => { fprintln!(1, "export: bad assignment {}", word); }
+        }
+    }
+}
+
 fn fork1() -> isize {
     let pid = fork();
     if pid == -1 {
@@ -322,7 +373,7 @@ unsafe fn parseredirs(mut cmd: CMD, ps: &mut *mut u8, es: *const u8) -> CMD {
                 cmd = redircmd(cmd, q, eq, OPEN_WRITE_ONLY | OPEN_CREATE | OPEN_TRUNC, 1);
             }
             b'+' => {
-                cmd = redircmd(cmd, q, eq, OPEN_WRITE_ONLY | OPEN_CREATE, 1);
+                cmd = redircmd(cmd, q, eq, OPEN_WRITE_ONLY | OPEN_CREATE | OPEN_APPEND, 1);
             }
             _ => (),
         }
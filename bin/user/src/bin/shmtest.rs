@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+use core::ptr::{read_volatile, write_volatile};
+
+use user::*;
+
+const WORKERS: usize = 3;
+const SLOTS: usize = 1024;
+
+// Workers fill their part of a shared buffer, then the parent checks it
+// without any data being copied between the processes.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    let id = shmget(IPC_PRIVATE, WORKERS * SLOTS * 8, IPC_CREAT);
+    if id < 0 {
+        eprintln!("shmtest: shmget failed");
+        exit(1);
+    }
+    let buffer = shmat(id as usize, 0, 0) as *mut usize;
+    if buffer as isize == -1 {
+        eprintln!("shmtest: shmat failed");
+        exit(1);
+    }
+
+    for worker in 0..WORKERS {
+        let pid = fork();
+        if pid < 0 {
+            eprintln!("shmtest: fork failed");
+            exit(1);
+        }
+        if pid == 0 {
+            // the attach survives fork.
+            for i in 0..SLOTS {
+                unsafe { write_volatile(buffer.add(worker * SLOTS + i), worker * SLOTS + i); }
+            }
+            exit(0);
+        }
+    }
+    for _ in 0..WORKERS {
+        let mut status: usize = 0;
+        wait(&mut status as *mut usize);
+    }
+
+    for i in 0..(WORKERS * SLOTS) {
+        let value = unsafe { read_volatile(buffer.add(i)) };
+        if value != i {
+            eprintln!("shmtest: slot {} holds {}", i, value);
+            exit(1);
+        }
+    }
+
+    let mut status = SharedMemoryStatus::new();
+    shmctl(id as usize, IPC_STAT, &mut status as *mut SharedMemoryStatus);
+    if status.attach_number != 1 {
+        eprintln!("shmtest: {} attaches left", status.attach_number);
+        exit(1);
+    }
+
+    // the segment goes away with the last detach.
+    shmctl(id as usize, IPC_RMID, 0 as *mut SharedMemoryStatus);
+    shmdt(buffer as *mut u8);
+    if shmctl(id as usize, IPC_STAT, &mut status as *mut SharedMemoryStatus) >= 0 {
+        eprintln!("shmtest: segment still exists");
+        exit(1);
+    }
+
+    println!("shmtest: ok");
+}
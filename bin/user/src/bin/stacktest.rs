@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+
+use core::ptr::{read_volatile, write_volatile};
+
+use user::*;
+
+// stack used by each level of recurse()
+const FRAME_SIZE: usize = 4096;
+
+// Use about depth * FRAME_SIZE bytes of stack.
+fn recurse(depth: usize) -> usize {
+    let mut frame = [0u8; FRAME_SIZE];
+    unsafe { write_volatile(&mut frame[depth % FRAME_SIZE], depth as u8); }
+    if depth == 0 {
+        return 0;
+    }
+    recurse(depth - 1) + unsafe { read_volatile(&frame[depth % FRAME_SIZE]) } as usize
+}
+
+// The stack grows on demand up to the stack limit,
+// and a process that goes past its limit is killed.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    let depth = 256;
+    recurse(depth);
+    println!("stacktest: {} KiB of stack ok", depth * FRAME_SIZE / 1024);
+
+    let pid = fork();
+    if pid < 0 {
+        eprintln!("stacktest: fork failed");
+        exit(1);
+    }
+    if pid == 0 {
+        let mut limit = ResourceLimit::new();
+        getrlimit(RLIMIT_STACK, &mut limit as *mut ResourceLimit);
+        limit.current = 64 * 1024;
+        if setrlimit(RLIMIT_STACK, &limit as *const ResourceLimit) < 0 {
+            eprintln!("stacktest: setrlimit failed");
+            exit(1);
+        }
+        recurse(depth);
+        exit(0);
+    }
+    let mut status: usize = 0;
+    wait(&mut status as *mut usize);
+    if status == 0 {
+        eprintln!("stacktest: child outgrew its stack limit");
+        exit(1);
+    }
+
+    println!("stacktest: ok");
+}
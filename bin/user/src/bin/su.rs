@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+use cstr_core::CString;
+use user::passwd::*;
+use user::*;
+
+// su is installed setuid root: it runs as root for
+// whoever started it, who must know the password
+// of the account unless they are root themselves.
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.len() > 1 {
+        println!("Usage: su [user]");
+        exit(1);
+    }
+    let name = _args.first().copied().unwrap_or("root");
+    let account = match account_by_name(name) {
+        Some(account) => { account }
+        None => {
+            eprintln!("su: no user {}", name);
+            exit(1);
+        }
+    };
+    if geteuid() != ROOT_UID {
+        eprintln!("su: must be setuid root");
+        exit(1);
+    }
+
+    if getuid() != ROOT_UID {
+        let password = read_line("password: ").unwrap_or_default();
+        if !check_password(name, &password) {
+            eprintln!("su: authentication failure");
+            exit(1);
+        }
+    }
+
+    if !become_account(&account) {
+        eprintln!("su: cannot become {}", name);
+        exit(1);
+    }
+    let shell = CString::new(account.shell.as_str()).expect("su: CString::new failed");
+    let argv = [shell.as_ptr() as *const u8, 0 as *const u8];
+    exec(&account.shell, &argv);
+    eprintln!("su: exec {} failed", account.shell);
+    exit(1);
+}
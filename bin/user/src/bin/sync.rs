@@ -0,0 +1,12 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if sync() < 0 {
+        eprintln!("sync: failed");
+        exit(1);
+    }
+}
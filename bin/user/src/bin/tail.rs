@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+
+use core::mem::size_of;
+
+use user::*;
+
+static mut BUF: [u8; 512] = [0; 512];
+
+// Offset of the start of the last lines lines of fd, searching
+// backwards from the end a buffer at a time.
+fn last_lines(fd: usize, lines: usize) -> usize {
+    let size = lseek(fd, 0, SEEK_END);
+    if size < 0 {
+        eprintln!("tail: seek error");
+        exit(1);
+    }
+    let mut end = size as usize;
+    let mut count = 0;
+    while end > 0 {
+        let start = end.saturating_sub(size_of::<[u8; 512]>());
+        let n = unsafe { pread(fd, BUF.as_mut_ptr(), end - start, start) };
+        if n != (end - start) as isize {
+            eprintln!("tail: read error");
+            exit(1);
+        }
+        for i in (start..end).rev() {
+            // a newline ending the file doesn't start a line
+            if unsafe { BUF[i - start] } == b'\n' && i + 1 != size as usize {
+                count += 1;
+                if count == lines {
+                    return i + 1;
+                }
+            }
+        }
+        end = start;
+    }
+    0
+}
+
+fn tail(fd: usize, lines: usize) {
+    let start = last_lines(fd, lines);
+    lseek(fd, start as isize, SEEK_SET);
+    let mut n: isize;
+    unsafe {
+        n = read(fd, BUF.as_mut_ptr(), size_of::<[u8; 512]>());
+        while n > 0 {
+            if write(1, BUF.as_ptr(), n as usize) != n {
+                eprintln!("tail: write error");
+                exit(1);
+            }
+            n = read(fd, BUF.as_mut_ptr(), size_of::<[u8; 512]>());
+        }
+    }
+    if n < 0 {
+        eprintln!("tail: read error");
+        exit(1);
+    }
+}
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    let mut lines = 10;
+    let mut files = &_args[..];
+    if files.len() >= 2 && files[0] == "-n" {
+        lines = match files[1].parse() {
+            Ok(n) => { n }
+            Err(_) => {
+                println!("Usage: tail [-n lines] file...");
+                exit(1);
+            }
+        };
+        files = &files[2..];
+    }
+    if files.is_empty() {
+        println!("Usage: tail [-n lines] file...");
+        exit(1);
+    }
+    for file in files {
+        let fd = open(file, OPEN_READ_ONLY);
+        if fd < 0 {
+            eprintln!("tail: cannot open {}", file);
+            exit(1);
+        }
+        if files.len() > 1 {
+            println!("==> {} <==", file);
+        }
+        if lines > 0 {
+            tail(fd as usize, lines);
+        }
+        close(fd as usize);
+    }
+}
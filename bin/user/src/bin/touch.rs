@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    if _args.is_empty() {
+        println!("Usage: touch file...");
+        exit(1);
+    }
+    for path in _args {
+        if utimes(path, None) == 0 {
+            continue;
+        }
+        // it doesn't exist yet.
+        let fd = open(path, OPEN_CREATE | OPEN_WRITE_ONLY);
+        if fd < 0 {
+            eprintln!("touch: cannot touch {}", path);
+            exit(1);
+        }
+        close(fd as usize);
+    }
+}
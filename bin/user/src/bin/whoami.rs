@@ -0,0 +1,14 @@
+#![no_std]
+#![no_main]
+
+use user::passwd::account_by_uid;
+use user::*;
+
+#[no_mangle]
+pub fn main(_args: Vec<&str>) {
+    let uid = geteuid();
+    match account_by_uid(uid) {
+        Some(account) => println!("{}", account.name),
+        None => println!("{}", uid),
+    }
+}
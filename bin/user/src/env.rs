@@ -0,0 +1,81 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::slice::from_raw_parts;
+use core::str::from_utf8_unchecked;
+
+use cstr_core::CString;
+
+use crate::strlen;
+
+// The environment of this process, as "NAME=value" strings.
+// exec() hands it on to the next program.
+static mut ENVIRONMENT: Vec<String> = Vec::new();
+
+// Called by _start() with the envp array exec() left on the stack.
+pub(crate) fn init(envp: *const *const u8) {
+    if envp.is_null() {
+        return;
+    }
+    let mut i = 0;
+    loop {
+        let s = unsafe { *envp.add(i) };
+        if s.is_null() {
+            break;
+        }
+        let string = unsafe { from_utf8_unchecked(from_raw_parts(s, strlen(s))) };
+        unsafe { ENVIRONMENT.push(String::from(string)); }
+        i += 1;
+    }
+}
+
+fn split(string: &String) -> (&str, &str) {
+    match string.find('=') {
+        Some(i) => { (&string[..i], &string[i + 1..]) }
+        None => { (string.as_str(), "") }
+    }
+}
+
+fn position(name: &str) -> Option<usize> {
+    unsafe { ENVIRONMENT.iter().position(|it| split(it).0 == name) }
+}
+
+pub fn var(name: &str) -> Option<String> {
+    let i = position(name)?;
+    Some(String::from(unsafe { split(&ENVIRONMENT[i]).1 }))
+}
+
+pub fn vars() -> Vec<(String, String)> {
+    unsafe { ENVIRONMENT.iter() }
+        .map(|it| {
+            let (name, value) = split(it);
+            (String::from(name), String::from(value))
+        })
+        .collect()
+}
+
+// A name may not be empty or contain '='; such calls are ignored.
+pub fn set_var(name: &str, value: &str) {
+    if name.is_empty() || name.contains('=') {
+        return;
+    }
+    let mut string = String::from(name);
+    string.push('=');
+    string.push_str(value);
+    match position(name) {
+        Some(i) => unsafe { ENVIRONMENT[i] = string; }
+        None => unsafe { ENVIRONMENT.push(string); }
+    }
+}
+
+pub fn remove_var(name: &str) {
+    if let Some(i) = position(name) {
+        unsafe { ENVIRONMENT.remove(i); }
+    }
+}
+
+// The environment as C strings, for building the envp array of execve().
+pub fn c_strings() -> Vec<CString> {
+    unsafe { ENVIRONMENT.iter() }
+        .map(|it| CString::new(it.as_str()).expect("env: CString::new failed"))
+        .collect()
+}
@@ -10,7 +10,10 @@ pub extern crate file_system_lib;
 
 #[macro_use]
 pub mod _start;
+pub mod env;
+pub mod passwd;
 pub mod print;
+pub mod sha256;
 pub mod syscall;
 pub mod ulib;
 pub mod umalloc;
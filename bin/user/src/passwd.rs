@@ -0,0 +1,144 @@
+// The account database.
+//
+// /etc/passwd, readable by everyone, has a line
+// "name:uid:gid:home:shell" per account; /etc/shadow,
+// readable by root only, has "name:salt:hash", the hash
+// being hex of password_hash(salt, password).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::mem::size_of;
+
+use cstr_core::CString;
+
+use crate::sha256::Sha256;
+use crate::*;
+
+pub const PASSWD_PATH: &str = "/etc/passwd";
+pub const SHADOW_PATH: &str = "/etc/shadow";
+
+// Rounds of SHA-256, to make guessing passwords slow.
+const HASH_ROUNDS: usize = 1000;
+
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+    pub shell: String,
+}
+
+fn read_file(path: &str) -> Option<String> {
+    let fd = open(path, OPEN_READ_ONLY);
+    if fd < 0 {
+        return None;
+    }
+    let fd = fd as usize;
+    let mut content = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = read(fd, buf.as_mut_ptr(), buf.len());
+        if n <= 0 {
+            break;
+        }
+        content.extend_from_slice(&buf[..n as usize]);
+    }
+    close(fd);
+    String::from_utf8(content).ok()
+}
+
+// Lines of path split into fields, skipping blank lines and comments.
+fn records(path: &str) -> Vec<Vec<String>> {
+    let content = read_file(path).unwrap_or_default();
+    content
+        .lines()
+        .filter(|it| !it.is_empty() && !it.starts_with('#'))
+        .map(|it| it.split(':').map(String::from).collect())
+        .collect()
+}
+
+pub fn accounts() -> Vec<Account> {
+    records(PASSWD_PATH)
+        .into_iter()
+        .filter_map(|fields| {
+            if fields.len() != 5 {
+                return None;
+            }
+            Some(Account {
+                name: fields[0].clone(),
+                uid: fields[1].parse().ok()?,
+                gid: fields[2].parse().ok()?,
+                home: fields[3].clone(),
+                shell: fields[4].clone(),
+            })
+        })
+        .collect()
+}
+
+pub fn account_by_name(name: &str) -> Option<Account> {
+    accounts().into_iter().find(|it| it.name == name)
+}
+
+pub fn account_by_uid(uid: u32) -> Option<Account> {
+    accounts().into_iter().find(|it| it.uid == uid)
+}
+
+pub fn password_hash(salt: &str, password: &str) -> String {
+    let mut hash = Sha256::new();
+    hash.update(salt.as_bytes());
+    hash.update(password.as_bytes());
+    let mut digest = hash.finish();
+    for _ in 1..HASH_ROUNDS {
+        let mut hash = Sha256::new();
+        hash.update(&digest);
+        hash.update(salt.as_bytes());
+        hash.update(password.as_bytes());
+        digest = hash.finish();
+    }
+
+    let mut hex = String::new();
+    for byte in digest.iter() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+// Only root can read /etc/shadow, so this fails for everyone else.
+pub fn check_password(name: &str, password: &str) -> bool {
+    records(SHADOW_PATH)
+        .iter()
+        .find(|fields| fields.len() == 3 && fields[0] == name)
+        .map_or(false, |fields| password_hash(&fields[1], password) == fields[2])
+}
+
+// Read a line from the console without its newline.
+pub fn read_line(prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    let mut buf = [0u8; 100];
+    gets(&mut buf, size_of::<[u8; 100]>());
+    let n = strlen(buf.as_ptr());
+    if n == 0 {
+        // EOF
+        return None;
+    }
+    let line = String::from_utf8_lossy(&buf[..n]);
+    Some(String::from(line.trim_end_matches(|c| c == '\n' || c == '\r')))
+}
+
+// Become account: its group and user ids, home directory and
+// environment. The caller must be root; returns false on failure.
+pub fn become_account(account: &Account) -> bool {
+    if setgid(account.gid) < 0 || setuid(account.uid) < 0 {
+        return false;
+    }
+    let home = CString::new(account.home.as_str()).expect("passwd: CString::new failed");
+    if chdir(home.as_ptr() as *const u8) < 0 {
+        eprintln!("no home directory {}, using /", account.home);
+        chdir("/\0".as_ptr());
+    }
+    env::set_var("USER", &account.name);
+    env::set_var("HOME", &account.home);
+    env::set_var("SHELL", &account.shell);
+    true
+}
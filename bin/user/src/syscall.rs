@@ -1,9 +1,15 @@
+use alloc::vec::Vec;
+use core::ptr;
 use cstr_core::CString;
 
+use crate::env;
+
 pub use file_control_lib::{
-    OPEN_CREATE, OPEN_READ_ONLY, OPEN_READ_WRITE, OPEN_TRUNC, OPEN_WRITE_ONLY,
+    IPC_CREAT, IPC_EXCL, IPC_PRIVATE, IPC_RMID, IPC_STAT, OPEN_APPEND, OPEN_CREATE, OPEN_NOFOLLOW, OPEN_READ_ONLY,
+    OPEN_READ_WRITE, OPEN_TRUNC, OPEN_WRITE_ONLY, SEEK_CUR, SEEK_END, SEEK_SET, SharedMemoryStatus, SHM_RDONLY,
 };
-pub use file_system_lib::FileStatus;
+pub use file_system_lib::{FileStatus, ROOT_UID};
+pub use param_lib::{ResourceLimit, RLIMIT_STACK};
 
 pub fn fork() -> isize {
     unsafe {
@@ -63,20 +69,30 @@ pub fn kill(_pid: usize) -> isize {
     }
 }
 
-fn _exec(_path: *const u8, _argv: *const [*const u8]) -> isize {
+fn _execve(_path: *const u8, _argv: *const *const u8, _envp: *const *const u8) -> isize {
     unsafe {
         let mut x: isize;
-        llvm_asm!("li a7, 7"::::"volatile");
+        llvm_asm!("li a7, 30"::::"volatile");
         llvm_asm!("ecall"::::"volatile");
         llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
         return x;
     }
 }
 
+// Run path with argv and the environment envp,
+// both null terminated arrays of C strings.
 #[inline]
+pub fn execve(_path: &str, _argv: *const [*const u8], _envp: *const [*const u8]) -> isize {
+    let _path = CString::new(_path).expect("execve syscall: CString::new failed");
+    _execve(_path.as_ptr(), _argv as *const *const u8, _envp as *const *const u8)
+}
+
+// Run path with argv, passing on the environment of this process.
 pub fn exec(_path: &str, _argv: *const [*const u8]) -> isize {
-    let _path = CString::new(_path).expect("open syscall: CString::new failed");
-    _exec(_path.as_ptr(), _argv)
+    let strings = env::c_strings();
+    let mut envp: Vec<*const u8> = strings.iter().map(|it| it.as_ptr() as *const u8).collect();
+    envp.push(0 as *const u8);
+    execve(_path, _argv, envp.as_slice())
 }
 
 pub fn fstat(_fd: usize, _addr: *mut FileStatus) -> isize {
@@ -191,7 +207,7 @@ pub fn mknod(_path: &str, _major: usize, _minor: usize) -> isize {
     _mknod(_path.as_ptr(), _major, _minor)
 }
 
-pub fn unlink(_path: *const u8) -> isize {
+fn _unlink(_path: *const u8) -> isize {
     unsafe {
         let mut x: isize;
         llvm_asm!("li a7, 18"::::"volatile");
@@ -201,7 +217,13 @@ pub fn unlink(_path: *const u8) -> isize {
     }
 }
 
-pub fn link(_old: *const u8, _new: *const u8) -> isize {
+#[inline]
+pub fn unlink(_path: &str) -> isize {
+    let _path = CString::new(_path).expect("unlink syscall: CString::new failed");
+    _unlink(_path.as_ptr())
+}
+
+fn _link(_old: *const u8, _new: *const u8) -> isize {
     unsafe {
         let mut x: isize;
         llvm_asm!("li a7, 19"::::"volatile");
@@ -211,6 +233,13 @@ pub fn link(_old: *const u8, _new: *const u8) -> isize {
     }
 }
 
+#[inline]
+pub fn link(_old: &str, _new: &str) -> isize {
+    let _old = CString::new(_old).expect("link syscall: CString::new failed");
+    let _new = CString::new(_new).expect("link syscall: CString::new failed");
+    _link(_old.as_ptr(), _new.as_ptr())
+}
+
 fn _mkdir(_path: *const u8) -> isize {
     unsafe {
         let mut x: isize;
@@ -236,3 +265,302 @@ pub fn close(_fd: usize) -> isize {
         return x;
     }
 }
+
+// Turn address space randomization of later exec()s on (1) or off (0).
+// Returns the previous setting.
+pub fn randomize(_enable: usize) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 22"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn shmget(_key: usize, _size: usize, _flags: usize) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 23"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+// _addr must be 0; the kernel picks the address.
+pub fn shmat(_id: usize, _addr: usize, _flags: usize) -> *mut u8 {
+    unsafe {
+        let mut x: *mut u8;
+        llvm_asm!("li a7, 24"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn shmdt(_addr: *mut u8) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 25"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn shmctl(_id: usize, _cmd: usize, _buf: *mut SharedMemoryStatus) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 26"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn vmprint() -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 27"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn getrlimit(_resource: usize, _limit: *mut ResourceLimit) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 28"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn setrlimit(_resource: usize, _limit: *const ResourceLimit) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 29"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+fn _symlink(_target: *const u8, _path: *const u8) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 31"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+#[inline]
+pub fn symlink(_target: &str, _path: &str) -> isize {
+    let _target = CString::new(_target).expect("symlink syscall: CString::new failed");
+    let _path = CString::new(_path).expect("symlink syscall: CString::new failed");
+    _symlink(_target.as_ptr(), _path.as_ptr())
+}
+
+fn _readlink(_path: *const u8, _buf: *mut u8, _size: usize) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 32"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+// Returns the length of the target put in buf, which is not 0 terminated.
+#[inline]
+pub fn readlink(_path: &str, _buf: &mut [u8]) -> isize {
+    let _path = CString::new(_path).expect("readlink syscall: CString::new failed");
+    _readlink(_path.as_ptr(), _buf.as_mut_ptr(), _buf.len())
+}
+
+fn _rename(_old: *const u8, _new: *const u8) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 33"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+#[inline]
+pub fn rename(_old: &str, _new: &str) -> isize {
+    let _old = CString::new(_old).expect("rename syscall: CString::new failed");
+    let _new = CString::new(_new).expect("rename syscall: CString::new failed");
+    _rename(_old.as_ptr(), _new.as_ptr())
+}
+
+// Returns the new offset.
+pub fn lseek(_fd: usize, _offset: isize, _whence: usize) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 34"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn pread(_fd: usize, _addr: *mut u8, _size: usize, _off: usize) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 35"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn pwrite(_fd: usize, _str: *const u8, _size: usize, _off: usize) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 36"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+fn _utimes(_path: *const u8, _times: *const [u64; 2]) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 37"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+// Set the access and modification times of path, in seconds
+// since the epoch, or both to now if times is None.
+#[inline]
+pub fn utimes(_path: &str, _times: Option<&[u64; 2]>) -> isize {
+    let _path = CString::new(_path).expect("utimes syscall: CString::new failed");
+    _utimes(_path.as_ptr(), _times.map_or(ptr::null(), |it| it as *const [u64; 2]))
+}
+
+fn _setuid(_uid: u32) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 38"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+// Set the real and effective user id if root, or
+// only the effective one back to the real one.
+#[inline]
+pub fn setuid(_uid: u32) -> isize {
+    _setuid(_uid)
+}
+
+pub fn getuid() -> u32 {
+    unsafe {
+        let mut x: u32;
+        llvm_asm!("li a7, 39"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+pub fn geteuid() -> u32 {
+    unsafe {
+        let mut x: u32;
+        llvm_asm!("li a7, 40"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+fn _setgid(_gid: u32) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 41"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+#[inline]
+pub fn setgid(_gid: u32) -> isize {
+    _setgid(_gid)
+}
+
+pub fn getgid() -> u32 {
+    unsafe {
+        let mut x: u32;
+        llvm_asm!("li a7, 42"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+fn _chmod(_path: *const u8, _mode: u32) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 43"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+#[inline]
+pub fn chmod(_path: &str, _mode: u32) -> isize {
+    let _path = CString::new(_path).expect("chmod syscall: CString::new failed");
+    _chmod(_path.as_ptr(), _mode)
+}
+
+fn _chown(_path: *const u8, _uid: u32, _gid: u32) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 44"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+#[inline]
+pub fn chown(_path: &str, _uid: u32, _gid: u32) -> isize {
+    let _path = CString::new(_path).expect("chown syscall: CString::new failed");
+    _chown(_path.as_ptr(), _uid, _gid)
+}
+
+// Wait until the writes to fd so far are on disk.
+pub fn fsync(_fd: usize) -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 45"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
+
+// Write everything the file system has cached to disk.
+pub fn sync() -> isize {
+    unsafe {
+        let mut x: isize;
+        llvm_asm!("li a7, 46"::::"volatile");
+        llvm_asm!("ecall"::::"volatile");
+        llvm_asm!("mv $0, a0":"=r"(x):::"volatile");
+        return x;
+    }
+}
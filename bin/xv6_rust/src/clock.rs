@@ -0,0 +1,30 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::device_tree::machine;
+use crate::riscv::read_time;
+
+// Wall-clock time, in seconds since the Unix epoch.
+// Hart 0 reads qemu's goldfish RTC once, in machine mode with
+// paging off; from then on the time CSR counts on from there,
+// so no page table has to map the RTC.
+static BOOT_SECONDS: AtomicU64 = AtomicU64::new(0);
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+// goldfish RTC registers; reading TIME_LOW latches TIME_HIGH.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+// Called by hart 0 in start(), after device_tree_init().
+pub unsafe fn clock_init() {
+    let rtc = machine().rtc;
+    let low = ptr::read_volatile((rtc + TIME_LOW) as *const u32) as u64;
+    let high = ptr::read_volatile((rtc + TIME_HIGH) as *const u32) as u64;
+    BOOT_TIME.store(read_time() as u64, Ordering::SeqCst);
+    BOOT_SECONDS.store(((high << 32) | low) / 1_000_000_000, Ordering::SeqCst);
+}
+
+pub fn now() -> u64 {
+    let elapsed = unsafe { read_time() as u64 } - BOOT_TIME.load(Ordering::Relaxed);
+    BOOT_SECONDS.load(Ordering::Relaxed) + elapsed / machine().timebase_frequency as u64
+}
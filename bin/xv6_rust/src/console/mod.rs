@@ -1,6 +1,6 @@
 use crate::console::uart::{uart_init, uart_put_char, uart_put_char_sync};
 use crate::file_system::device::DEVICES;
-use crate::memory::{either_copy_in, either_copy_out};
+use crate::memory::{either_copy_in, either_copy_out, kernel_heap, PHYSICAL_MEMORY};
 use crate::process::{CPU_MANAGER, PROCESS_MANAGER};
 use crate::spin_lock::SpinLock;
 use crate::file_control_lib::CONSOLE_ID;
@@ -24,6 +24,8 @@ struct Console {
     read: usize,
     write: usize,
     edit: usize,
+    // the pid typed so far after ^V
+    vmprint_pid: Option<usize>,
 }
 
 static CONSOLE: SpinLock<Console> = SpinLock::new(Console {
@@ -31,12 +33,14 @@ static CONSOLE: SpinLock<Console> = SpinLock::new(Console {
     read: 0,
     write: 0,
     edit: 0,
+    vmprint_pid: None,
 }, "console");
 
 const CTRL_P: u8 = b'P' - b'@';
 const CTRL_U: u8 = b'U' - b'@';
 const CTRL_H: u8 = b'H' - b'@';
 const CTRL_D: u8 = b'D' - b'@';
+const CTRL_V: u8 = b'V' - b'@';
 
 //
 // user write()s to the console go here.
@@ -87,10 +91,13 @@ pub fn console_read(user_dst: bool, mut dst: usize, mut n: usize) -> usize {
             break;
         }
 
-        // copy the input byte to the user-space buffer.
+        // copy the input byte to the user-space buffer,
+        // without the lock: a swapped-out page is read back in,
+        // which sleeps.
+        drop(guard);
         let c_buf = c;
         if !either_copy_out(user_dst, dst, &c_buf as *const u8 as usize, 1) {
-            break;
+            return target - n;
         }
 
         dst += 1;
@@ -99,8 +106,9 @@ pub fn console_read(user_dst: bool, mut dst: usize, mut n: usize) -> usize {
         if c == b'\n' {
             // a whole line has arrived, return to
             // the user-level read().
-            break;
+            return target - n;
         }
+        guard = CONSOLE.lock();
     }
 
     drop(guard);
@@ -116,9 +124,31 @@ pub fn console_intr(char: u8) {
     let mut lock = CONSOLE.lock();
     let console = &mut *lock;
 
+    // after ^V, digits up to any other key name the process
+    // whose page table to print; they don't go to the input buffer.
+    if let Some(pid) = console.vmprint_pid {
+        if char >= b'0' && char <= b'9' {
+            console.vmprint_pid = Some(pid * 10 + (char - b'0') as usize);
+            console_put_char(char);
+        } else {
+            console.vmprint_pid = None;
+            console_put_char(b'\n');
+            if !PROCESS_MANAGER.print_page_table(pid) {
+                println!("no process {}", pid);
+            }
+        }
+        return;
+    }
+
     match char {
         CTRL_P => {
             PROCESS_MANAGER.print_processes();
+            PHYSICAL_MEMORY.print_statistics();
+            kernel_heap::print_statistics();
+        }
+        CTRL_V => {
+            console.vmprint_pid = Some(0);
+            print!("\nvmprint pid: ");
         }
         CTRL_U => {
             while console.edit != console.write
@@ -4,12 +4,11 @@ use core::ptr;
 use core::sync::atomic::Ordering;
 
 use crate::console::console_intr;
+use crate::device_tree::machine;
 use crate::print::PANICKED;
 use crate::process::{CPU_MANAGER, PROCESS_MANAGER};
 use crate::spin_lock::SpinLock;
 
-const UART0: usize = 0x10000000;
-
 const RHR: usize = 0; /* receive holding register (for input bytes) */
 const THR: usize = 0; /* transmit holding register (for output bytes) */
 const IER: usize = 1; /* interrupt enable register */
@@ -34,14 +33,14 @@ static mut UART_TX_R: usize = 0; /* read next from uart_tx_buf[uar_tx_r++] */
 
 macro_rules! read_reg {
     ($reg: expr) => {
-        unsafe { ptr::read_volatile((UART0 + $reg) as *const u8) }
+        unsafe { ptr::read_volatile((machine().uart + $reg) as *const u8) }
     };
 }
 
 macro_rules! write_reg {
     ($reg: expr, $value: expr) => {
         unsafe {
-            ptr::write_volatile((UART0 + $reg) as *mut u8, $value);
+            ptr::write_volatile((machine().uart + $reg) as *mut u8, $value);
         }
     };
 }
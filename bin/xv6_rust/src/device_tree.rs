@@ -0,0 +1,350 @@
+use core::ptr;
+use core::slice::from_raw_parts;
+use core::str::from_utf8;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use param_lib::{BUFFER_SIZE, MAX_CPU_NUMBER, MIN_BUFFER_SIZE};
+
+use crate::file_system::buffer_cache::MAX_BUFFER_COUNT;
+use crate::memory::layout::{CLINT, KERNEL_BASE, PHY_STOP, PLIC, PLIC_SIZE, RTC, TIMEBASE_FREQUENCY, UART0, UART0_IRQ, USER_STACK_BASE, VIRTIO0, VIRTIO0_IRQ};
+
+// Flattened device tree, as in the devicetree specification.
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// nodes nested deeper than this make the tree unusable;
+// qemu's goes three levels below the root.
+const MAX_DEPTH: usize = 8;
+
+pub const MAX_MEMORY_RANGES: usize = 4;
+
+// The machine the kernel runs on.
+// qemu describes it in a flattened device tree, whose address
+// it passes in a1 at _entry; whatever the tree doesn't say
+// comes from the constants in memory/layout.rs and param_lib.
+pub struct Machine {
+    // RAM the kernel may use, [start, end) each
+    memory: [(usize, usize); MAX_MEMORY_RANGES],
+    memory_count: usize,
+    // at most MAX_CPU_NUMBER
+    pub hart_count: usize,
+    pub uart: usize,
+    pub uart_irq: usize,
+    // the first virtio mmio slot, which holds the disk
+    pub virtio: usize,
+    pub virtio_irq: usize,
+    pub plic: usize,
+    pub plic_size: usize,
+    pub clint: usize,
+    // only read in machine mode, by clock_init()
+    pub rtc: usize,
+    pub timebase_frequency: usize,
+    // blocks the buffer cache holds
+    pub buffer_count: usize,
+    // buffers=N, if N was more than the kernel heap can hold;
+    // noted for print(), since there is no console yet.
+    buffers_asked: usize,
+}
+
+// Written once by hart 0 in start(), read-only afterwards.
+static mut MACHINE: Machine = Machine::new();
+static PARSED: AtomicBool = AtomicBool::new(false);
+
+pub fn machine() -> &'static Machine {
+    unsafe { &MACHINE }
+}
+
+impl Machine {
+    const fn new() -> Machine {
+        Machine {
+            memory: [(0, 0); MAX_MEMORY_RANGES],
+            memory_count: 0,
+            hart_count: 0,
+            uart: 0,
+            uart_irq: 0,
+            virtio: 0,
+            virtio_irq: 0,
+            plic: 0,
+            plic_size: 0,
+            clint: 0,
+            rtc: 0,
+            timebase_frequency: 0,
+            buffer_count: 0,
+            buffers_asked: 0,
+        }
+    }
+
+    pub fn memory(&self) -> &[(usize, usize)] {
+        &self.memory[..self.memory_count]
+    }
+
+    // End of the highest RAM range.
+    pub fn memory_end(&self) -> usize {
+        self.memory().iter().map(|&(_, end)| end).max().unwrap()
+    }
+
+    // Only RAM above the kernel is used, and below the user stack
+    // region, where the kernel page table could not map it.
+    fn add_memory(&mut self, start: usize, size: usize) {
+        let start = start.max(KERNEL_BASE);
+        let end = start.saturating_add(size).min(USER_STACK_BASE);
+        if start < end && self.memory_count < MAX_MEMORY_RANGES {
+            self.memory[self.memory_count] = (start, end);
+            self.memory_count += 1;
+        }
+    }
+
+    // Use the built-in layout for anything the tree didn't describe.
+    fn fill_defaults(&mut self) {
+        if self.memory_count == 0 {
+            self.add_memory(KERNEL_BASE, PHY_STOP - KERNEL_BASE);
+        }
+        if self.hart_count == 0 || self.hart_count > MAX_CPU_NUMBER {
+            self.hart_count = MAX_CPU_NUMBER;
+        }
+        if self.uart == 0 || self.uart_irq == 0 {
+            self.uart = UART0;
+            self.uart_irq = UART0_IRQ;
+        }
+        if self.virtio == 0 || self.virtio_irq == 0 {
+            self.virtio = VIRTIO0;
+            self.virtio_irq = VIRTIO0_IRQ;
+        }
+        if self.plic == 0 {
+            self.plic = PLIC;
+            self.plic_size = PLIC_SIZE;
+        }
+        if self.clint == 0 {
+            self.clint = CLINT;
+        }
+        if self.rtc == 0 {
+            self.rtc = RTC;
+        }
+        if self.timebase_frequency == 0 {
+            self.timebase_frequency = TIMEBASE_FREQUENCY;
+        }
+        if self.buffer_count == 0 {
+            self.buffer_count = BUFFER_SIZE;
+        }
+        if self.buffer_count > MAX_BUFFER_COUNT {
+            self.buffers_asked = self.buffer_count;
+            self.buffer_count = MAX_BUFFER_COUNT;
+        }
+        self.buffer_count = self.buffer_count.max(MIN_BUFFER_SIZE);
+    }
+
+    // /chosen has the command line of qemu's -append,
+    // whose buffers=N sets the size of the buffer cache.
+    fn parse_bootargs(&mut self, bootargs: &[u8]) {
+        for arg in bootargs.split(|&it| it == b' ' || it == 0) {
+            if arg.starts_with(b"buffers=") {
+                self.buffer_count = from_utf8(&arg[8..]).ok().and_then(|it| it.parse().ok()).unwrap_or(0);
+            }
+        }
+    }
+
+    // Record what a node says about the machine, once all its properties are known.
+    fn add_node(&mut self, node: &Node, parent: &Node) {
+        let entry_size = (parent.address_cells + parent.size_cells) * 4;
+        if entry_size == 0 {
+            return;
+        }
+        let mut reg = node.reg.chunks_exact(entry_size).map(|entry| {
+            let (address, size) = entry.split_at(parent.address_cells * 4);
+            (read_cells(address), read_cells(size))
+        });
+
+        if node.status.starts_with(b"disabled") {
+            return;
+        }
+        // /cpus has it, for all harts.
+        if !node.timebase_frequency.is_empty() {
+            self.timebase_frequency = read_cells(node.timebase_frequency);
+        }
+        if !node.bootargs.is_empty() {
+            self.parse_bootargs(node.bootargs);
+        }
+        if is_string(node.device_type, "memory") {
+            for (address, size) in reg {
+                self.add_memory(address, size);
+            }
+        } else if is_string(node.device_type, "cpu") {
+            self.hart_count += 1;
+        } else if let Some((address, size)) = reg.next() {
+            let irq = node.interrupts.get(..4).map_or(0, read_cells);
+            if has_string(node.compatible, "ns16550a") && self.uart == 0 {
+                self.uart = address;
+                self.uart_irq = irq;
+            } else if has_string(node.compatible, "virtio,mmio") && (self.virtio == 0 || address < self.virtio) {
+                self.virtio = address;
+                self.virtio_irq = irq;
+            } else if has_string(node.compatible, "riscv,plic0") || has_string(node.compatible, "sifive,plic-1.0.0") {
+                self.plic = address;
+                self.plic_size = size;
+            } else if has_string(node.compatible, "riscv,clint0") || has_string(node.compatible, "sifive,clint0") {
+                self.clint = address;
+            } else if has_string(node.compatible, "google,goldfish-rtc") {
+                self.rtc = address;
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        for &(start, end) in self.memory() {
+            println!("memory: {:#x} - {:#x}", start, end);
+        }
+        println!("{} harts, uart {:#x} irq {}, virtio {:#x} irq {}, plic {:#x}, clint {:#x}, rtc {:#x}",
+                 self.hart_count, self.uart, self.uart_irq, self.virtio, self.virtio_irq, self.plic, self.clint, self.rtc);
+        if self.buffers_asked != 0 {
+            println!("buffers={} is too many for the kernel heap", self.buffers_asked);
+        }
+        println!("{} buffers", self.buffer_count);
+    }
+}
+
+// The properties of a node that matter here.
+#[derive(Clone, Copy)]
+struct Node {
+    // cell counts of the reg properties of the node's children
+    address_cells: usize,
+    size_cells: usize,
+    device_type: &'static [u8],
+    compatible: &'static [u8],
+    status: &'static [u8],
+    reg: &'static [u8],
+    interrupts: &'static [u8],
+    timebase_frequency: &'static [u8],
+    bootargs: &'static [u8],
+}
+
+impl Node {
+    const fn new() -> Node {
+        Node {
+            address_cells: 2,
+            size_cells: 1,
+            device_type: &[],
+            compatible: &[],
+            status: &[],
+            reg: &[],
+            interrupts: &[],
+            timebase_frequency: &[],
+            bootargs: &[],
+        }
+    }
+}
+
+unsafe fn read_u32(address: usize) -> u32 {
+    u32::from_be(ptr::read(address as *const u32))
+}
+
+// A big-endian number of one or two cells.
+fn read_cells(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as usize)
+}
+
+fn is_string(value: &[u8], s: &str) -> bool {
+    value.split(|&it| it == 0).next() == Some(s.as_bytes())
+}
+
+// compatible lists several '\0' terminated strings.
+fn has_string(value: &[u8], s: &str) -> bool {
+    value.split(|&it| it == 0).any(|it| it == s.as_bytes())
+}
+
+unsafe fn c_string(address: usize) -> &'static [u8] {
+    let mut len = 0;
+    while *((address + len) as *const u8) != 0 {
+        len += 1;
+    }
+    from_raw_parts(address as *const u8, len)
+}
+
+fn align4(address: usize) -> usize {
+    (address + 3) & !3
+}
+
+// Walk the structure block of the tree at dtb.
+// Returns false if it isn't a device tree the kernel understands.
+unsafe fn parse(dtb: usize, machine: &mut Machine) -> bool {
+    if dtb == 0 || dtb % 8 != 0 || read_u32(dtb) != FDT_MAGIC {
+        return false;
+    }
+    let total_size = read_u32(dtb + 4) as usize;
+    let structure = dtb + read_u32(dtb + 8) as usize;
+    let strings = dtb + read_u32(dtb + 12) as usize;
+
+    // nodes[depth - 1] is the node being read.
+    let mut nodes = [Node::new(); MAX_DEPTH];
+    let mut depth = 0;
+    let mut p = structure;
+    while p < dtb + total_size {
+        let token = read_u32(p);
+        p += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                if depth == MAX_DEPTH {
+                    return false;
+                }
+                p = align4(p + c_string(p).len() + 1);
+                nodes[depth] = Node::new();
+                depth += 1;
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+                if depth > 0 {
+                    let (parents, rest) = nodes.split_at(depth);
+                    machine.add_node(&rest[0], &parents[depth - 1]);
+                }
+            }
+            FDT_PROP => {
+                if depth == 0 {
+                    return false;
+                }
+                let len = read_u32(p) as usize;
+                let name = c_string(strings + read_u32(p + 4) as usize);
+                let value = from_raw_parts((p + 8) as *const u8, len);
+                p = align4(p + 8 + len);
+
+                let node = &mut nodes[depth - 1];
+                match name {
+                    b"#address-cells" => { node.address_cells = read_cells(value); }
+                    b"#size-cells" => { node.size_cells = read_cells(value); }
+                    b"device_type" => { node.device_type = value; }
+                    b"compatible" => { node.compatible = value; }
+                    b"status" => { node.status = value; }
+                    b"reg" => { node.reg = value; }
+                    b"interrupts" => { node.interrupts = value; }
+                    b"timebase-frequency" => { node.timebase_frequency = value; }
+                    b"bootargs" => { node.bootargs = value; }
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => { return true; }
+            _ => { return false; }
+        }
+    }
+    false
+}
+
+// Called by hart 0 in start(), in machine mode, before the other
+// harts look at the machine.
+pub unsafe fn device_tree_init(dtb: usize) {
+    let machine = &mut MACHINE;
+    if !parse(dtb, machine) {
+        *machine = Machine::new();
+    }
+    machine.fill_defaults();
+    PARSED.store(true, Ordering::SeqCst);
+}
+
+pub fn wait_for_device_tree() {
+    while !PARSED.load(Ordering::SeqCst) {}
+}
@@ -6,14 +6,15 @@ use core::ptr;
 use core::ptr::null_mut;
 use core::sync::atomic::{AtomicBool, fence, Ordering};
 
+use crate::device_tree::machine;
 use crate::memory::KERNEL_PAGETABLE;
-use crate::memory::layout::VIRTIO0;
 use crate::memory::PAGE_SIZE;
 use crate::process::{CPU_MANAGER, PROCESS_MANAGER};
-use crate::spin_lock::SpinLock;
+use crate::spin_lock::{SpinLock, SpinLockGuard};
 use file_system_lib::BLOCK_SIZE;
 
-const NUM: usize = 8;
+// descriptors, three per request in flight.
+const NUM: usize = 32;
 
 #[repr(C)]
 struct VRingDesc {
@@ -51,6 +52,9 @@ struct Info {
     buffer: *mut [u8; BLOCK_SIZE],
     status: u8,
     disk: AtomicBool,
+    // for a request started by start(), called with
+    // the token once it is finished.
+    done: Option<(fn(usize), usize)>,
 }
 
 impl Info {
@@ -59,6 +63,7 @@ impl Info {
             buffer: null_mut(),
             status: 0,
             disk: AtomicBool::new(false),
+            done: None,
         }
     }
 }
@@ -71,6 +76,16 @@ struct VirtioBlockRequest {
     sector: u64,
 }
 
+impl VirtioBlockRequest {
+    const fn new() -> VirtioBlockRequest {
+        VirtioBlockRequest {
+            op_type: 0,
+            reserved: 0,
+            sector: 0,
+        }
+    }
+}
+
 #[repr(C, align(4096))]
 pub struct Disk {
     pages: [u8; 2 * PAGE_SIZE],
@@ -82,6 +97,8 @@ pub struct Disk {
     used_idx: u16,
 
     info: [Info; NUM],
+    // the request header of each chain, by its first descriptor.
+    ops: [VirtioBlockRequest; NUM],
 }
 
 pub static mut DISK: Disk = Disk::new();
@@ -101,6 +118,7 @@ impl Disk {
             used_idx: 0,
 
             info: array![_ => Info::new(); NUM],
+            ops: array![_ => VirtioBlockRequest::new(); NUM],
         }
     }
 
@@ -156,7 +174,7 @@ impl Disk {
         write(VIRTIO_MMIO_QUEUE_PFN, u32::try_from(&self.pages as *const _ as usize >> 12).unwrap());
 
         // desc = pages -- num * VRingDesc
-        // avail = pages + 0x80 -- 2 * uint16, then num * uint16
+        // avail = pages + num * 16 -- 2 * uint16, then num * uint16
         // used = pages + 4096 -- 2 * uint16, then num * vRingUsedElem
 
         self.desc = &self.pages as *const _ as *mut VRingDesc;
@@ -165,14 +183,14 @@ impl Disk {
 
         assert_eq!(size_of::<VRingDesc>(), 16);
         assert_eq!(self.desc as usize, &self.pages as *const _ as usize);
-        assert_eq!(self.avail as usize, &self.pages as *const _ as usize + 0x80);
+        assert_eq!(self.avail as usize, &self.pages as *const _ as usize + NUM * 16);
         assert_eq!(self.used as usize, &self.pages as *const _ as usize + 4096);
 
         for i in 0..NUM {
             self.free[i] = true;
         }
 
-        // plic.c and trap.c arrange for interrupts from VIRTIO0_IRQ.
+        // plic.c and trap.c arrange for interrupts from the disk's irq.
     }
 
     pub unsafe fn read(&mut self, block_no: u32, data: *mut [u8; BLOCK_SIZE]) {
@@ -183,7 +201,33 @@ impl Disk {
         self.read_write(block_no, data, true);
     }
 
+    // Start reading or writing block_no and return without waiting;
+    // intr() calls done(token) once the request is finished.
+    pub unsafe fn start(&mut self, block_no: u32, data: *mut [u8; BLOCK_SIZE], is_write: bool, done: fn(usize), token: usize) {
+        let (_, guard) = self.submit(block_no, data, is_write, Some((done, token)));
+        drop(guard);
+    }
+
     unsafe fn read_write(&mut self, block_no: u32, data: *mut [u8; BLOCK_SIZE], is_write: bool) {
+        let (idx, mut guard) = self.submit(block_no, data, is_write, None);
+        let info = &mut self.info[idx];
+
+        // Wait for virtio_disk_intr() to say request has finished.
+        while info.disk.load(Ordering::SeqCst) {
+            CPU_MANAGER.my_cpu().sleep(data as usize, guard);
+            guard = DISK_LOCK.lock();
+        }
+
+        info.buffer = null_mut();
+        self.free_chain(idx);
+
+        drop(guard);
+    }
+
+    // Hand a request to the device, returning the index of its
+    // first descriptor, with the disk lock still held.
+    unsafe fn submit(&mut self, block_no: u32, data: *mut [u8; BLOCK_SIZE], is_write: bool,
+                     done: Option<(fn(usize), usize)>) -> (usize, SpinLockGuard<'static, ()>) {
         let sector = block_no * BLOCK_SIZE as u32 / 512;
 
         let mut guard = DISK_LOCK.lock();
@@ -197,16 +241,15 @@ impl Disk {
         }
         let idx = idx.unwrap();
 
-        let buf0 = VirtioBlockRequest {
-            op_type: if is_write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
-            reserved: 0,
-            sector: sector as u64,
-        };
+        // the header outlives this call for a request
+        // from start(), so it can't be on the stack.
+        let buf0 = &mut self.ops[idx[0]];
+        buf0.op_type = if is_write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN };
+        buf0.reserved = 0;
+        buf0.sector = sector as u64;
 
-        // buf0 is on a kernel stack, which is not direct mapped,
-        // thus the call to translate().
         let desc0 = self.desc.offset(idx[0] as isize);
-        (*desc0).addr = KERNEL_PAGETABLE.lock().translate(&buf0 as *const _ as usize).unwrap() as u64;
+        (*desc0).addr = buf0 as *const _ as usize as u64;
         (*desc0).len = size_of::<VirtioBlockRequest>() as u32;
         (*desc0).flags = VRING_DESC_F_NEXT;
         (*desc0).next = idx[1] as u16;
@@ -229,6 +272,7 @@ impl Disk {
         // record struct buf for virtio_disk_intr().
         info.disk.store(true, Ordering::SeqCst);
         info.buffer = data;
+        info.done = done;
 
         // avail[0] is flags
         // avail[1] tells the device how far to look in avail[2...].
@@ -243,16 +287,7 @@ impl Disk {
 
         write(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
 
-        // Wait for virtio_disk_intr() to say request has finished.
-        while info.disk.load(Ordering::SeqCst) {
-            CPU_MANAGER.my_cpu().sleep(data as usize, guard);
-            guard = DISK_LOCK.lock();
-        }
-
-        info.buffer = null_mut();
-        self.free_chain(idx[0]);
-
-        drop(guard);
+        (idx[0], guard)
     }
 
     unsafe fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
@@ -303,6 +338,11 @@ impl Disk {
     pub unsafe fn intr(&mut self) {
         let guard = DISK_LOCK.lock();
 
+        // requests from start() are finished after the lock is
+        // released, as done() may take other locks and wake others.
+        let mut finished: [Option<(fn(usize), usize)>; NUM] = [None; NUM];
+        let mut count = 0;
+
         while (self.used_idx as usize % NUM) != ((*self.used).id as usize % NUM) {
             let id = (*self.used).elems[self.used_idx as usize].id;
             let info = &mut self.info[id as usize];
@@ -310,7 +350,14 @@ impl Disk {
             assert_eq!(info.status, 0);
 
             info.disk.store(false, Ordering::SeqCst);
-            PROCESS_MANAGER.wake_up(info.buffer as usize);
+            if info.done.is_some() {
+                finished[count] = info.done.take();
+                count += 1;
+                info.buffer = null_mut();
+                self.free_chain(id as usize);
+            } else {
+                PROCESS_MANAGER.wake_up(info.buffer as usize);
+            }
 
             self.used_idx = (self.used_idx + 1) % NUM as u16;
         }
@@ -318,6 +365,10 @@ impl Disk {
         write(VIRTIO_MMIO_INTERRUPT_ACK, read(VIRTIO_MMIO_INTERRUPT_STATUS) & 0x3);
 
         drop(guard);
+
+        for &(done, token) in finished[..count].iter().flatten() {
+            done(token);
+        }
     }
 }
 
@@ -370,12 +421,12 @@ const VIRTIO_RING_F_EVENT_IDX: u8 = 29;
 
 #[inline]
 unsafe fn read(offset: usize) -> u32 {
-    let src = (Into::<usize>::into(VIRTIO0) + offset) as *const u32;
+    let src = (machine().virtio + offset) as *const u32;
     ptr::read_volatile(src)
 }
 
 #[inline]
 unsafe fn write(offset: usize, data: u32) {
-    let dst = (Into::<usize>::into(VIRTIO0) + offset) as *mut u32;
+    let dst = (machine().virtio + offset) as *mut u32;
     ptr::write_volatile(dst, data);
 }
@@ -1,39 +1,60 @@
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
-use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::driver::DISK;
 use crate::file_system::BLOCK_SIZE;
+use crate::memory::layout::KERNEL_HEAP_MAX_SIZE;
+use crate::process::{CPU_MANAGER, PROCESS_MANAGER};
 use crate::sleep_lock::{SleepLock, SleepLockGuard};
 use crate::spin_lock::SpinLock;
-use param_lib::BUFFER_SIZE;
 
 type BufferData = [u8; BLOCK_SIZE];
 
+// a prime, so that the blocks of a file spread over the buckets.
+const BUCKET_COUNT: usize = 61;
+// end of a bucket chain or of the LRU list
+const NONE: usize = usize::max_value();
+// the cache may take at most half of the kernel heap.
+pub const MAX_BUFFER_COUNT: usize = KERNEL_HEAP_MAX_SIZE / 2 / core::mem::size_of::<Buffer>();
+
 pub struct Buffer {
+    // dev, block_no, ref_count, in_flight and next are protected
+    // by the lock of the buffer's bucket; a buffer only changes
+    // bucket under BlockCache.evict, with a ref_count of 0.
     valid: bool,
+    // a disk request started by read_ahead() or start_write_to()
+    // is using data; it holds a reference until it is done.
+    in_flight: bool,
+    // 0 until the buffer is first used
     dev: u32,
     block_no: u32,
     data: SleepLock<BufferData>,
     ref_count: u32,
-    last_used_time: usize,
+    // in the bucket chain
+    next: usize,
+    // in the LRU list, protected by its lock
+    lru_prev: usize,
+    lru_next: usize,
 }
 
 impl Buffer {
     pub const fn new() -> Buffer {
         Buffer {
             valid: false,
+            in_flight: false,
             dev: 0,
             block_no: 0,
             data: SleepLock::new([0; BLOCK_SIZE]),
             ref_count: 0,
-            last_used_time: 0,
+            next: NONE,
+            lru_prev: NONE,
+            lru_next: NONE,
         }
     }
 }
 
 pub struct BufferGuard<'a> {
     index: usize,
-    #[allow(dead_code)]
     dev: u32,
     block_no: u32,
     data: SleepLockGuard<'a, BufferData>,
@@ -59,10 +80,21 @@ impl BufferGuard<'_> {
     }
 }
 
+// The unreferenced buffers, least recently used first.
+struct LruList {
+    head: usize,
+    tail: usize,
+    // processes sleeping in get() for a buffer to free
+    waiters: usize,
+}
+
 pub struct BlockCache {
-    buffers: UnsafeCell<[Buffer; BUFFER_SIZE]>,
-    lock: SpinLock<()>,
-    lru_release_count: AtomicUsize,
+    buffers: UnsafeCell<Vec<Buffer>>,
+    // the first buffer of each hash chain
+    buckets: [SpinLock<usize>; BUCKET_COUNT],
+    lru: SpinLock<LruList>,
+    // serializes misses, which move buffers between buckets.
+    evict: SleepLock<()>,
 }
 
 pub static BLOCK_CACHE: BlockCache = BlockCache::new();
@@ -72,72 +104,207 @@ unsafe impl Sync for BlockCache {}
 impl BlockCache {
     pub const fn new() -> BlockCache {
         BlockCache {
-            buffers: UnsafeCell::new(array![_ => Buffer::new(); BUFFER_SIZE]),
-            lock: SpinLock::new((), "block cache"),
-            lru_release_count: AtomicUsize::new(0),
+            buffers: UnsafeCell::new(Vec::new()),
+            buckets: array![_ => SpinLock::new(NONE, "bucket"); BUCKET_COUNT],
+            lru: SpinLock::new(LruList { head: NONE, tail: NONE, waiters: 0 }, "block cache"),
+            evict: SleepLock::new(()),
         }
     }
 
-    pub fn get(&self, dev: u32, block_no: u32) -> BufferGuard {
-        let lock_guard = self.lock.lock();
-
-        match self.find(dev, block_no).or(self.alloc(dev, block_no)) {
-            Some(index) => {
-                let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
-                buffers[index].ref_count += 1;
-                let data = buffers[index].data.lock();
-                drop(lock_guard);
-                return BufferGuard {
-                    index,
-                    dev,
-                    block_no,
-                    data,
-                };
-            }
-            None => {
-                panic!("no buffers");
-            }
+    // Called once, by hart 0 before any other use.
+    pub fn init(&self, count: usize) {
+        let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
+        buffers.reserve_exact(count);
+        let mut lru = self.lru.lock();
+        for index in 0..count {
+            buffers.push(Buffer::new());
+            self.lru_push(&mut lru, index);
         }
+        drop(lru);
+    }
+
+    pub fn size(&self) -> usize {
+        unsafe { self.buffers.get().as_ref().unwrap() }.len()
     }
 
-    /// should hold lock
-    fn find(&self, dev: u32, block_no: u32) -> Option<usize> {
+    fn buffer(&self, index: usize) -> &mut Buffer {
         let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
+        &mut buffers[index]
+    }
+
+    fn bucket(dev: u32, block_no: u32) -> usize {
+        (dev as usize * 31 + block_no as usize) % BUCKET_COUNT
+    }
 
-        for index in 0..buffers.len() {
-            if buffers[index].dev == dev && buffers[index].block_no == block_no {
+    /// should hold the bucket lock
+    fn find(&self, head: usize, dev: u32, block_no: u32) -> Option<usize> {
+        let mut index = head;
+        while index != NONE {
+            let buffer = self.buffer(index);
+            if buffer.dev == dev && buffer.block_no == block_no {
                 return Some(index);
             }
+            index = buffer.next;
         }
         None
     }
 
-    /// should hold lock
-    fn alloc(&self, dev: u32, block_no: u32) -> Option<usize> {
-        let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
+    /// should hold the lru lock
+    fn lru_push(&self, lru: &mut LruList, index: usize) {
+        let buffer = self.buffer(index);
+        buffer.lru_prev = lru.tail;
+        buffer.lru_next = NONE;
+        if lru.tail == NONE {
+            lru.head = index;
+        } else {
+            self.buffer(lru.tail).lru_next = index;
+        }
+        lru.tail = index;
+    }
 
-        let mut lru_index: Option<usize> = None;
-        for index in 0..buffers.len() {
-            if buffers[index].ref_count == 0 && (lru_index.is_none() || buffers[lru_index.unwrap()].last_used_time < buffers[index].last_used_time) {
-                lru_index = Some(index);
-            }
+    /// should hold the lru lock
+    fn lru_remove(&self, lru: &mut LruList, index: usize) {
+        let (prev, next) = (self.buffer(index).lru_prev, self.buffer(index).lru_next);
+        if prev == NONE {
+            lru.head = next;
+        } else {
+            self.buffer(prev).lru_next = next;
         }
-        if lru_index.is_none() {
-            return None;
+        if next == NONE {
+            lru.tail = prev;
+        } else {
+            self.buffer(next).lru_prev = prev;
         }
+    }
 
-        let index = lru_index.unwrap();
-        buffers[index].dev = dev;
-        buffers[index].block_no = block_no;
-        buffers[index].valid = false;
+    // Take a reference to a cached buffer, off the LRU list if it was on it.
+    /// should hold the bucket lock
+    fn hold(&self, index: usize) {
+        let buffer = self.buffer(index);
+        buffer.ref_count += 1;
+        if buffer.ref_count == 1 {
+            let mut lru = self.lru.lock();
+            self.lru_remove(&mut lru, index);
+            drop(lru);
+        }
+    }
 
-        lru_index
+    fn lock_buffer(&self, index: usize, dev: u32, block_no: u32) -> BufferGuard {
+        let data = self.buffer(index).data.lock();
+        let bucket = &self.buckets[Self::bucket(dev, block_no)];
+        let mut head = bucket.lock();
+        while self.buffer(index).in_flight {
+            CPU_MANAGER.my_cpu().sleep(self.buffer(index) as *const _ as usize, head);
+            head = bucket.lock();
+        }
+        drop(head);
+        BufferGuard {
+            index,
+            dev,
+            block_no,
+            data,
+        }
+    }
+
+    // Look through the cache for block_no on dev.
+    // If not found, recycle the least recently used unreferenced
+    // buffer, sleeping until there is one.
+    // Either way, return the locked buffer, once the
+    // disk is done with it.
+    pub fn get(&self, dev: u32, block_no: u32) -> BufferGuard {
+        let bucket = &self.buckets[Self::bucket(dev, block_no)];
+
+        let head = bucket.lock();
+        if let Some(index) = self.find(*head, dev, block_no) {
+            self.hold(index);
+            drop(head);
+            return self.lock_buffer(index, dev, block_no);
+        }
+        drop(head);
+
+        // Not cached. Only one miss at a time, so no one else
+        // can cache the block while this one looks for a buffer.
+        let evict = self.evict.lock();
+        let head = bucket.lock();
+        if let Some(index) = self.find(*head, dev, block_no) {
+            self.hold(index);
+            drop(head);
+            drop(evict);
+            return self.lock_buffer(index, dev, block_no);
+        }
+        drop(head);
+
+        let index = self.recycle(true).unwrap();
+        let buffer = self.buffer(index);
+        let mut head = bucket.lock();
+        buffer.dev = dev;
+        buffer.block_no = block_no;
+        buffer.valid = false;
+        buffer.ref_count = 1;
+        buffer.next = *head;
+        *head = index;
+        drop(head);
+        drop(evict);
+
+        self.lock_buffer(index, dev, block_no)
+    }
+
+    // Take the least recently used unreferenced buffer out
+    // of its bucket and the LRU list. If there is none, sleep
+    // until there is one, or return None unless wait.
+    /// should hold evict
+    fn recycle(&self, wait: bool) -> Option<usize> {
+        loop {
+            let mut lru = self.lru.lock();
+            if lru.head == NONE && !wait {
+                drop(lru);
+                return None;
+            }
+            while lru.head == NONE {
+                lru.waiters += 1;
+                CPU_MANAGER.my_cpu().sleep(&self.lru as *const _ as usize, lru);
+                lru = self.lru.lock();
+                lru.waiters -= 1;
+            }
+            let index = lru.head;
+            drop(lru);
+
+            // its bucket can't change, we hold evict.
+            let buffer = self.buffer(index);
+            if buffer.dev == 0 {
+                // never used, so in no bucket for anyone to find.
+                let mut lru = self.lru.lock();
+                self.lru_remove(&mut lru, index);
+                drop(lru);
+                return Some(index);
+            }
+
+            let mut head = self.buckets[Self::bucket(buffer.dev, buffer.block_no)].lock();
+            if buffer.ref_count != 0 {
+                // someone found it after all.
+                drop(head);
+                continue;
+            }
+            let mut lru = self.lru.lock();
+            self.lru_remove(&mut lru, index);
+            drop(lru);
+            if *head == index {
+                *head = buffer.next;
+            } else {
+                let mut prev = *head;
+                while self.buffer(prev).next != index {
+                    prev = self.buffer(prev).next;
+                }
+                self.buffer(prev).next = buffer.next;
+            }
+            drop(head);
+            return Some(index);
+        }
     }
 
     pub fn read(&self, dev: u32, block_no: u32) -> BufferGuard {
         let buffer = self.get(dev, block_no);
-        let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
-        let valid = &mut buffers[buffer.index].valid;
+        let valid = &mut self.buffer(buffer.index).valid;
         if !*valid {
             unsafe {
                 DISK.read(buffer.block_no, buffer.data.as_ptr() as *mut BufferData);
@@ -147,39 +314,142 @@ impl BlockCache {
         return buffer;
     }
 
+    // Start reading block_no into the cache and return without
+    // waiting for the disk, unless it is cached already or no
+    // buffer is free; a later read() finds it, or waits for it.
+    pub fn read_ahead(&self, dev: u32, block_no: u32) {
+        let bucket = &self.buckets[Self::bucket(dev, block_no)];
+
+        let head = bucket.lock();
+        let cached = self.find(*head, dev, block_no).is_some();
+        drop(head);
+        if cached {
+            return;
+        }
+
+        let evict = self.evict.lock();
+        let head = bucket.lock();
+        let cached = self.find(*head, dev, block_no).is_some();
+        drop(head);
+        if cached {
+            drop(evict);
+            return;
+        }
+        let index = match self.recycle(false) {
+            Some(index) => { index }
+            None => {
+                drop(evict);
+                return;
+            }
+        };
+
+        // in no bucket, so no one else can lock it.
+        let buffer = self.buffer(index);
+        let data = buffer.data.lock();
+        let data_ptr = data.as_ptr() as *mut BufferData;
+        drop(data);
+
+        let mut head = bucket.lock();
+        buffer.dev = dev;
+        buffer.block_no = block_no;
+        buffer.valid = false;
+        buffer.in_flight = true;
+        buffer.ref_count = 1;
+        buffer.next = *head;
+        *head = index;
+        drop(head);
+        drop(evict);
+
+        unsafe {
+            DISK.start(block_no, data_ptr, false, finish, index);
+        }
+    }
+
+    // A buffer for block_no taken as valid without reading
+    // it, for a caller about to overwrite all of it.
+    pub fn fresh(&self, dev: u32, block_no: u32) -> BufferGuard {
+        let buffer = self.get(dev, block_no);
+        self.buffer(buffer.index).valid = true;
+        return buffer;
+    }
+
     pub fn write(&self, buffer: &BufferGuard) {
         unsafe {
             DISK.write(buffer.block_no, buffer.data.as_ptr() as *mut BufferData);
         }
     }
 
-    pub fn release(&self, buffer: BufferGuard) {
-        drop(buffer.data);
+    // Start writing buffer to its block and return without waiting.
+    pub fn start_write(&self, buffer: &BufferGuard) {
+        self.start_write_to(buffer, buffer.block_no);
+    }
 
-        let lock_guard = self.lock.lock();
-        let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
-        buffers[buffer.index].ref_count -= 1;
+    // Start writing the data of buffer to block_no on disk, leaving
+    // any cached copy of block_no as it is. Once the caller releases
+    // the buffer, whoever gets it next waits for the write.
+    pub fn start_write_to(&self, buffer: &BufferGuard, block_no: u32) {
+        let head = self.buckets[Self::bucket(buffer.dev, buffer.block_no)].lock();
+        let cached = self.buffer(buffer.index);
+        cached.ref_count += 1;
+        cached.in_flight = true;
+        drop(head);
+
+        unsafe {
+            DISK.start(block_no, buffer.data(), true, finish, buffer.index);
+        }
+    }
+
+    // The disk is done with a request of read_ahead() or
+    // start_write_to(); called from the disk interrupt.
+    fn finish_io(&self, index: usize) {
+        // the request's reference keeps the buffer in its bucket.
+        let buffer = self.buffer(index);
+        let head = self.buckets[Self::bucket(buffer.dev, buffer.block_no)].lock();
+        buffer.valid = true;
+        buffer.in_flight = false;
+        PROCESS_MANAGER.wake_up(buffer as *const _ as usize);
+        self.unreference(index);
+        drop(head);
+    }
+
+    // Drop a reference; a buffer no one references
+    // becomes the most recently used on the LRU list.
+    fn put(&self, index: usize, dev: u32, block_no: u32) {
+        let head = self.buckets[Self::bucket(dev, block_no)].lock();
+        self.unreference(index);
+        drop(head);
+    }
 
-        if buffers[buffer.index].ref_count == 0 {
-            let used_time = self.lru_release_count.load(Ordering::Relaxed);
-            buffers[buffer.index].last_used_time = used_time;
-            self.lru_release_count.store(used_time + 1, Ordering::Relaxed);
+    /// should hold the bucket lock
+    fn unreference(&self, index: usize) {
+        let buffer = self.buffer(index);
+        buffer.ref_count -= 1;
+        if buffer.ref_count == 0 {
+            let mut lru = self.lru.lock();
+            self.lru_push(&mut lru, index);
+            if lru.waiters > 0 {
+                PROCESS_MANAGER.wake_up(&self.lru as *const _ as usize);
+            }
+            drop(lru);
         }
+    }
 
-        drop(lock_guard);
+    pub fn release(&self, buffer: BufferGuard) {
+        drop(buffer.data);
+        self.put(buffer.index, buffer.dev, buffer.block_no);
     }
 
     pub fn pin(&self, buffer: &BufferGuard) {
-        let lock_guard = self.lock.lock();
-        let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
-        buffers[buffer.index].ref_count += 1;
-        drop(lock_guard);
+        let head = self.buckets[Self::bucket(buffer.dev, buffer.block_no)].lock();
+        self.buffer(buffer.index).ref_count += 1;
+        drop(head);
     }
 
     pub fn unpin(&self, buffer: &BufferGuard) {
-        let lock_guard = self.lock.lock();
-        let buffers = unsafe { self.buffers.get().as_mut().unwrap() };
-        buffers[buffer.index].ref_count -= 1;
-        drop(lock_guard);
+        self.put(buffer.index, buffer.dev, buffer.block_no);
     }
-}
\ No newline at end of file
+}
+
+fn finish(index: usize) {
+    BLOCK_CACHE.finish_io(index);
+}
@@ -46,6 +46,11 @@ impl ElfHeader {
     }
 }
 
+// Values for ElfHeader type
+pub const ELF_TYPE_EXEC: u16 = 2;
+// position independent executable
+pub const ELF_TYPE_DYN: u16 = 3;
+
 // Program section header
 #[repr(C)]
 pub struct ProgramHeader {
@@ -76,8 +81,34 @@ impl ProgramHeader {
 
 // Values for Proghdr type
 pub const ELF_PROG_LOAD: u32 = 1;
+pub const ELF_PROG_DYNAMIC: u32 = 2;
+pub const ELF_PROG_PHDR: u32 = 6;
 
 // Flag bits for Proghdr flags
 pub const ELF_PROG_FLAG_EXEC: usize = 1;
 pub const ELF_PROG_FLAG_WRITE: usize = 2;
 pub const ELF_PROG_FLAG_READ: usize = 4;
+
+// Entry of the dynamic section
+#[repr(C)]
+pub struct DynamicEntry {
+    pub tag: u64,
+    pub value: u64,
+}
+
+// Values for DynamicEntry tag
+pub const DT_NULL: u64 = 0;
+pub const DT_RELA: u64 = 7;
+pub const DT_RELASZ: u64 = 8;
+pub const DT_RELAENT: u64 = 9;
+
+// Relocation entry with addend
+#[repr(C)]
+pub struct Rela {
+    pub offset: u64,
+    pub info: u64,
+    pub addend: u64,
+}
+
+// the only relocation a static PIE needs: *(base + offset) = base + addend
+pub const R_RISCV_RELATIVE: u64 = 3;
@@ -23,7 +23,13 @@ pub struct FileData {
     // FD_INODE and FD_DEVICE
     pub ip: Option<&'static INode>,
     // FD_INODE
-    pub off: u32,
+    pub off: u64,
+    // FD_INODE, every write goes to the end of the file
+    pub append: bool,
+    // FD_INODE, where a sequential read would go on,
+    // and how many blocks to read ahead of it
+    pub next_off: u64,
+    pub ahead: usize,
     // FD_DEVICE
     pub major: u16,
 }
@@ -43,6 +49,9 @@ impl File {
                 pipe: null_mut(),
                 ip: None,
                 off: 0,
+                append: false,
+                next_off: 0,
+                ahead: 0,
                 major: 0,
             })
         }
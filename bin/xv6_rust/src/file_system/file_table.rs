@@ -1,8 +1,10 @@
 use core::cell::UnsafeCell;
+use core::cmp::{max, min};
 use core::intrinsics::size_of;
 
+use file_control_lib::{SEEK_CUR, SEEK_END, SEEK_SET};
 use file_system_lib::{BLOCK_SIZE, FileStatus};
-use param_lib::{MAX_DEV_NUMBER, MAX_FILE_NUMBER, MAX_OP_BLOCKS};
+use param_lib::{MAX_DEV_NUMBER, MAX_FILE_NUMBER, MAX_READ_AHEAD};
 
 use crate::file_system::device::DEVICES;
 use crate::file_system::file::File;
@@ -122,19 +124,44 @@ impl FileTable {
             }
             devices[major as usize].read.unwrap().call((true, addr, size)) as u64
         } else if file.data().types == INODE {
-            let ip = file.data().ip.unwrap();
-            let guard = ip.lock();
-            let read = ip.read(true, addr, file.data().off, size as u32);
-            if read > 0 {
-                file.data().off += read;
-            }
-            drop(guard);
-            read as u64
+            self.read_inode(file, addr, size, None)
         } else {
             panic!("fileread");
         }
     }
 
+    // Read from file f at offset off, leaving its offset alone.
+    // Only files on disk have offsets.
+    pub fn pread(&self, file: &File, addr: usize, size: usize, off: u64) -> u64 {
+        if !file.data().readable || file.data().types != INODE {
+            return u64::max_value();
+        }
+        self.read_inode(file, addr, size, Some(off))
+    }
+
+    // Read at off, or at the file offset, which then advances, if off is None.
+    fn read_inode(&self, file: &File, addr: usize, size: usize, off: Option<u64>) -> u64 {
+        let ip = file.data().ip.unwrap();
+        let guard = ip.lock();
+        let start = off.unwrap_or(file.data().off);
+        let read = ip.read(true, addr, start, min(size, u32::MAX as usize - 1) as u32);
+        if read != u32::MAX && off.is_none() {
+            let data = file.data();
+            data.off += read as u64;
+            // a read that goes on where the last one stopped is
+            // sequential; read further ahead the longer that lasts.
+            if start == data.next_off && read > 0 {
+                data.ahead = min(max(data.ahead * 2, 2), MAX_READ_AHEAD);
+                ip.read_ahead(data.off, data.ahead);
+            } else {
+                data.ahead = 0;
+            }
+            data.next_off = data.off;
+        }
+        drop(guard);
+        if read == u32::MAX { u64::max_value() } else { read as u64 }
+    }
+
     // Write to file f.
     // addr is a user virtual address.
     pub fn write(&self, file: &File, addr: usize, size: usize) -> u64 {
@@ -153,42 +180,95 @@ impl FileTable {
             }
             devices[major as usize].write.unwrap().call((true, addr, size)) as u64
         } else if file.data().types == INODE {
-            // write a few blocks at a time to avoid exceeding
-            // the maximum log transaction size, including
-            // i-node, indirect block, allocation blocks,
-            // and 2 blocks of slop for non-aligned writes.
-            // this really belongs lower down, since writei()
-            // might be writing a device like the console.
-            let max = ((MAX_OP_BLOCKS - 1 - 1 - 2) / 2) * BLOCK_SIZE;
-            let log = unsafe { &mut LOG };
-            let ip = file.data().ip.unwrap();
+            self.write_inode(file, addr, size, None)
+        } else {
+            panic!("filewrite");
+        }
+    }
 
-            let mut i = 0;
-            while i < size {
-                let mut n1 = size - i;
-                if n1 > max {
-                    n1 = max;
-                }
-
-                log.begin_op();
-                let guard = ip.lock();
-                let write = ip.write(true, addr + i, file.data().off, n1 as u32);
-                if write > 0 {
-                    file.data().off += write;
-                }
-                ip.unlock(guard);
-                log.end_op();
-
-                if write as usize != n1 {
-                    // error from writei
-                    break;
-                }
-                i += write as usize;
+    // Write to file f at offset off, leaving its offset alone.
+    pub fn pwrite(&self, file: &File, addr: usize, size: usize, off: u64) -> u64 {
+        if !file.data().writable || file.data().types != INODE {
+            return u64::max_value();
+        }
+        self.write_inode(file, addr, size, Some(off))
+    }
+
+    // Write at off, or at the file offset, which then advances, if off is None.
+    // An O_APPEND file offset moves to the end of the file first.
+    fn write_inode(&self, file: &File, addr: usize, size: usize, mut off: Option<u64>) -> u64 {
+        // write a share of the log at a time to avoid exceeding
+        // the maximum log transaction size, including
+        // i-node, indirect block, allocation blocks,
+        // and 2 blocks of slop for non-aligned writes.
+        // this really belongs lower down, since writei()
+        // might be writing a device like the console.
+        let log = unsafe { &mut LOG };
+        let blocks = log.write_op_blocks();
+        let max = ((blocks - 1 - 1 - 2) / 2) * BLOCK_SIZE;
+        let ip = file.data().ip.unwrap();
+
+        let mut i = 0;
+        while i < size {
+            let mut n1 = size - i;
+            if n1 > max {
+                n1 = max;
             }
 
-            if i == size { size as u64 } else { u64::max_value() }
-        } else {
-            panic!("filewrite");
+            log.begin_op_with(blocks);
+            let guard = ip.lock();
+            let at = match off {
+                Some(off) => { off }
+                None if file.data().append => { ip.data().size }
+                None => { file.data().off }
+            };
+            let write = ip.write(true, addr + i, at, n1 as u32);
+            match off {
+                Some(ref mut off) => { *off += write as u64; }
+                None => { file.data().off = at + write as u64; }
+            }
+            ip.unlock(guard);
+            log.end_op_with(blocks);
+
+            if write as usize != n1 {
+                // error from writei
+                break;
+            }
+            i += write as usize;
         }
+
+        if i == size { size as u64 } else { u64::max_value() }
+    }
+
+    // Move the offset of file f, as lseek() does.
+    // Files can't have holes, so it can't go past the end.
+    pub fn seek(&self, file: &File, offset: isize, whence: usize) -> u64 {
+        if file.data().types != INODE {
+            return u64::max_value();
+        }
+        let ip = file.data().ip.unwrap();
+        let guard = ip.lock();
+        let base = match whence {
+            SEEK_SET => { 0 }
+            SEEK_CUR => { file.data().off }
+            SEEK_END => { ip.data().size }
+            _ => { u64::max_value() }
+        };
+        let off = if base == u64::max_value() {
+            None
+        } else if offset < 0 {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        } else {
+            base.checked_add(offset as u64)
+        };
+        let result = match off {
+            Some(off) if off <= ip.data().size => {
+                file.data().off = off;
+                off
+            }
+            _ => { u64::max_value() }
+        };
+        ip.unlock(guard);
+        result
     }
 }
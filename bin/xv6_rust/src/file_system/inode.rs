@@ -1,15 +1,19 @@
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::cmp::min;
 use core::intrinsics::size_of;
 use core::ptr;
 use core::ptr::null_mut;
+use core::slice;
 
 use cstr_core::{c_char, CStr, CString};
 
-use file_system_lib::{DIRECT_COUNT, INDIRECT_COUNT, DIRECTORY_SIZE, Dirent, FileStatus, iblock, INodeDisk, IPB, MAX_FILE_COUNT, TYPE_DIR};
-use param_lib::MAX_INODE_NUMBER;
+use file_system_lib::{ADDR_COUNT, bblock, DIRECT_COUNT, DIRECTORY_SIZE, Dirent, DOUBLE_INDIRECT, DOUBLE_INDIRECT_COUNT, FileStatus, iblock, INDIRECT, INDIRECT_COUNT, INodeDisk, IPB, LongDirent, MAX_FILE_COUNT, MAX_NAME_LENGTH, record_length, ACCESS_EXEC, ROOT_UID, TYPE_DIR};
+use param_lib::{MAX_INODE_NUMBER, MAX_OP_BLOCKS};
 
+use crate::clock::now;
+use crate::process::CPU_MANAGER;
 use crate::file_system::{Block, BLOCK_CACHE, BLOCK_SIZE, LOG, SUPER_BLOCK};
 use crate::memory::{either_copy_in, either_copy_out};
 use crate::sleep_lock::{SleepLock, SleepLockGuard};
@@ -26,8 +30,15 @@ pub struct INodeData {
     pub minor: u16,
     pub nlink: u16,
 
-    pub size: u32,
-    pub addr: [u32; DIRECT_COUNT + 1],
+    // at most MAX_FILE_COUNT * BLOCK_SIZE, so that it fits the u32 on disk
+    pub size: u64,
+    pub addr: [u32; ADDR_COUNT],
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
 }
 
 impl INodeData {
@@ -42,7 +53,13 @@ impl INodeData {
             minor: 0,
             nlink: 0,
             size: 0,
-            addr: [0; DIRECT_COUNT + 1],
+            addr: [0; ADDR_COUNT],
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
         }
     }
 }
@@ -72,22 +89,48 @@ impl INode {
             ino: self.data().inum,
             types: self.data().types,
             nlink: self.data().nlink,
-            size: self.data().size as u64,
+            mode: self.data().mode,
+            uid: self.data().uid,
+            gid: self.data().gid,
+            size: self.data().size,
+            atime: self.data().atime,
+            mtime: self.data().mtime,
+            ctime: self.data().ctime,
         }
     }
 
+    // May the current process do access, ACCESS_* bits, to the inode?
+    // The owner's bits apply to the owner, the group's to its members,
+    // the others' to everyone else.
+    // Caller must hold ip->lock.
+    pub fn permitted(&self, access: u32) -> bool {
+        let info = CPU_MANAGER.my_proc().info();
+        let data = self.data();
+        if info.euid == ROOT_UID {
+            // except that only files someone may execute can be executed.
+            return access & ACCESS_EXEC == 0 || data.types == TYPE_DIR || data.mode & 0o111 != 0;
+        }
+        let bits = if info.euid == data.uid {
+            data.mode >> 6
+        } else if info.egid == data.gid {
+            data.mode >> 3
+        } else {
+            data.mode
+        };
+        bits & access == access
+    }
+
     // Inode content
     //
     // The content (data) associated with each inode is stored
     // in blocks on the disk. The first NDIRECT block numbers
     // are listed in ip->addrs[].  The next NINDIRECT blocks are
-    // listed in block ip->addrs[NDIRECT].
+    // listed in block ip->addrs[NDIRECT], and the NINDIRECT^2
+    // after those in the blocks listed in block ip->addrs[NDIRECT+1].
 
     // Return the disk block address of the nth block in inode ip.
     // If there is no such block, bmap allocates one.
     pub fn map(&self, mut bn: u32) -> u32 {
-        let log = unsafe { &mut LOG };
-
         let data = self.data();
         if (bn as usize) < DIRECT_COUNT {
             let mut addr = data.addr[bn as usize];
@@ -101,46 +144,76 @@ impl INode {
 
         if (bn as usize) < INDIRECT_COUNT {
             // Load indirect block, allocating if necessary.
-            let mut addr = data.addr[DIRECT_COUNT];
-            if addr == 0 {
-                addr = Block::alloc(data.dev);
-                data.addr[DIRECT_COUNT] = addr;
+            if data.addr[INDIRECT] == 0 {
+                data.addr[INDIRECT] = Block::alloc(data.dev);
             }
+            return map_entry(data.dev, data.addr[INDIRECT], bn as usize);
+        }
+        bn -= INDIRECT_COUNT as u32;
 
-            let bp = BLOCK_CACHE.read(data.dev, addr);
-            let a = unsafe { (bp.data() as *mut [u32; 256] as *mut [u32]).as_ref() }.unwrap();
-            addr = a[bn as usize];
-            if addr == 0 {
-                addr = Block::alloc(data.dev);
-                data.addr[bn as usize] = addr;
-                log.write(&bp);
+        if (bn as usize) < DOUBLE_INDIRECT_COUNT {
+            // Then the indirect block listed in the doubly-indirect one.
+            if data.addr[DOUBLE_INDIRECT] == 0 {
+                data.addr[DOUBLE_INDIRECT] = Block::alloc(data.dev);
             }
-            BLOCK_CACHE.release(bp);
-
-            return addr;
+            let indirect = map_entry(data.dev, data.addr[DOUBLE_INDIRECT], bn as usize / INDIRECT_COUNT);
+            return map_entry(data.dev, indirect, bn as usize % INDIRECT_COUNT);
         }
 
         panic!("out of range");
     }
 
+    // Like map(), but 0 for a block not allocated yet.
+    fn lookup(&self, mut bn: u32) -> u32 {
+        let data = self.data();
+        if (bn as usize) < DIRECT_COUNT {
+            return data.addr[bn as usize];
+        }
+        bn -= DIRECT_COUNT as u32;
+
+        if (bn as usize) < INDIRECT_COUNT {
+            return lookup_entry(data.dev, data.addr[INDIRECT], bn as usize);
+        }
+        bn -= INDIRECT_COUNT as u32;
+
+        let indirect = lookup_entry(data.dev, data.addr[DOUBLE_INDIRECT], bn as usize / INDIRECT_COUNT);
+        lookup_entry(data.dev, indirect, bn as usize % INDIRECT_COUNT)
+    }
+
+    // Start reading up to count blocks from off into the cache,
+    // for a read() of them to come. Caller must hold ip->lock.
+    pub fn read_ahead(&self, off: u64, count: usize) {
+        let data = self.data();
+        let first = off / BLOCK_SIZE as u64;
+        let end = min(first + count as u64, (data.size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64);
+        for bn in first..end {
+            let addr = self.lookup(bn as u32);
+            if addr != 0 {
+                BLOCK_CACHE.read_ahead(data.dev, addr);
+            }
+        }
+    }
+
     // Read data from inode.
     // Caller must hold ip->lock.
     // If user_dst==1, then dst is a user virtual address;
     // otherwise, dst is a kernel address.
-    pub fn read(&self, user_dst: bool, mut dst: usize, mut off: u32, mut n: u32) -> u32 {
+    // Only reads for users count as accesses; as reads run outside
+    // transactions, atime reaches the disk with the next update().
+    pub fn read(&self, user_dst: bool, mut dst: usize, mut off: u64, mut n: u32) -> u32 {
         let data = self.data();
 
-        if off > data.size || off + n < off {
+        if off > data.size {
             return 0;
         }
-        if off + n > data.size {
-            n = data.size - off;
+        if off + n as u64 > data.size {
+            n = (data.size - off) as u32;
         }
 
         let mut tot = 0;
         while tot < n {
-            let bp = BLOCK_CACHE.read(data.dev, self.map(off / BLOCK_SIZE as u32));
-            let m = min(n - tot, BLOCK_SIZE as u32 - (off % BLOCK_SIZE as u32));
+            let bp = BLOCK_CACHE.read(data.dev, self.map((off / BLOCK_SIZE as u64) as u32));
+            let m = min(n - tot, BLOCK_SIZE as u32 - (off % BLOCK_SIZE as u64) as u32);
             if !either_copy_out(user_dst, dst, bp.data() as usize + (off as usize % BLOCK_SIZE), m as usize) {
                 BLOCK_CACHE.release(bp);
                 tot = u32::MAX;
@@ -149,9 +222,12 @@ impl INode {
             BLOCK_CACHE.release(bp);
 
             tot += m;
-            off += m;
+            off += m as u64;
             dst += m as usize;
         }
+        if user_dst && tot != u32::MAX {
+            data.atime = now();
+        }
         return tot;
     }
 
@@ -163,22 +239,22 @@ impl INode {
     // Returns the number of bytes successfully written.
     // If the return value is less than the requested n,
     // there was an error of some kind.
-    pub fn write(&self, user_src: bool, mut src: usize, mut off: u32, n: u32) -> u32 {
+    pub fn write(&self, user_src: bool, mut src: usize, mut off: u64, n: u32) -> u32 {
         let data = self.data();
 
         let log = unsafe { &mut LOG };
 
-        if off > data.size || off + n < off {
+        if off > data.size {
             return 0;
         }
-        if off + n > (MAX_FILE_COUNT * BLOCK_SIZE) as u32 {
+        if off + n as u64 > (MAX_FILE_COUNT * BLOCK_SIZE) as u64 {
             return 0;
         }
 
         let mut tot = 0;
         while tot < n {
-            let bp = BLOCK_CACHE.read(data.dev, self.map(off / BLOCK_SIZE as u32));
-            let m = min(n - tot, BLOCK_SIZE as u32 - (off % BLOCK_SIZE as u32));
+            let bp = BLOCK_CACHE.read(data.dev, self.map((off / BLOCK_SIZE as u64) as u32));
+            let m = min(n - tot, BLOCK_SIZE as u32 - (off % BLOCK_SIZE as u64) as u32);
             if !either_copy_in(user_src, bp.data() as usize + (off as usize % BLOCK_SIZE), src, m as usize) {
                 BLOCK_CACHE.release(bp);
                 break;
@@ -187,13 +263,17 @@ impl INode {
             BLOCK_CACHE.release(bp);
 
             tot += m;
-            off += m;
+            off += m as u64;
             src += m as usize;
         }
 
         if off > data.size {
             data.size = off;
         }
+        if tot > 0 {
+            data.mtime = now();
+            data.ctime = data.mtime;
+        }
 
         // write the i-node back to disk even if the size didn't change
         // because the loop above might have called bmap() and added a new
@@ -205,54 +285,100 @@ impl INode {
 
     // Look for a directory entry in a directory.
     // If found, set *poff to byte offset of entry.
-    pub fn dir_lookup(&self, name: &String, poff: *mut u32) -> Option<&INode> {
+    pub fn dir_lookup(&self, name: &String, poff: *mut u64) -> Option<&INode> {
         let data = self.data();
 
         assert_eq!(data.types, TYPE_DIR);
 
-        let mut de = Dirent {
-            inum: 0,
-            name: [0; DIRECTORY_SIZE],
-        };
-
-        let size_de = size_of::<Dirent>() as u32;
-        for off in (0..data.size).step_by(size_de as usize) {
-            if self.read(false, &mut de as *mut _ as usize, off, size_de) != size_de {
-                panic!("dirlookup read");
+        let (off, inum) = self.dir_find(|de_name| de_name == name.as_bytes())?;
+        if !poff.is_null() {
+            unsafe {
+                (*poff) = off;
             }
-            if de.inum == 0 {
-                continue;
+        }
+        return Some(ICACHE.get(data.dev, inum));
+    }
+
+    // Offset and inode number of the first entry in use whose name matches,
+    // in whichever format the file system keeps directories.
+    fn dir_find<F: FnMut(&[u8]) -> bool>(&self, mut matches: F) -> Option<(u64, u32)> {
+        let size = self.data().size;
+        let mut off = 0;
+        if SUPER_BLOCK.get().long_names() {
+            let mut name = [0u8; MAX_NAME_LENGTH];
+            while off < size {
+                let de = self.read_record(off);
+                let name = &mut name[..de.name_length as usize];
+                let size_name = name.len() as u32;
+                if self.read(false, name.as_mut_ptr() as usize, off + size_of::<LongDirent>() as u64, size_name) != size_name {
+                    panic!("dir_find: read name");
+                }
+                if de.inum != 0 && matches(name) {
+                    return Some((off, de.inum));
+                }
+                off += de.record_length as u64;
             }
-            let de_name = unsafe { CStr::from_ptr(&de.name as *const _ as *const c_char) }.to_string_lossy().into_owned();
-            if de_name == *name {
-                if !poff.is_null() {
-                    unsafe {
-                        (*poff) = off;
-                    }
+        } else {
+            let mut de = Dirent::new();
+            let size_de = size_of::<Dirent>() as u32;
+            while off < size {
+                if self.read(false, &mut de as *mut _ as usize, off, size_de) != size_de {
+                    panic!("dirlookup read");
+                }
+                let de_name = unsafe { CStr::from_ptr(&de.name as *const _ as *const c_char) };
+                if de.inum != 0 && matches(de_name.to_bytes()) {
+                    return Some((off, de.inum as u32));
                 }
-                return Some(ICACHE.get(data.dev, de.inum as u32));
+                off += size_de as u64;
             }
         }
+        None
+    }
+
+    // The header of the long-name record at byte offset off.
+    fn read_record(&self, off: u64) -> LongDirent {
+        let mut de = LongDirent::new();
+        let size_de = size_of::<LongDirent>() as u32;
+        if self.read(false, &mut de as *mut _ as usize, off, size_de) != size_de {
+            panic!("read_record");
+        }
+        let length = de.record_length as u64;
+        if length < record_length(de.name_length as usize) as u64 ||
+            off % BLOCK_SIZE as u64 + length > BLOCK_SIZE as u64 {
+            panic!("read_record: bad record");
+        }
+        de
+    }
 
-        return None;
+    fn write_record(&self, off: u64, de: &LongDirent) {
+        let size_de = size_of::<LongDirent>() as u32;
+        if self.write(false, de as *const _ as usize, off, size_de) != size_de {
+            panic!("write_record");
+        }
     }
 
     // Write a new directory entry (name, inum) into the directory dp.
-    pub fn dir_link(&self, name: &String, inum: u32) -> bool {
+    // types is the type of inode inum.
+    pub fn dir_link(&self, name: &String, inum: u32, types: u16) -> bool {
         let data = self.data();
 
+        if name.is_empty() || name.len() > max_name_length() {
+            return false;
+        }
+
         let ip = self.dir_lookup(name, null_mut());
 
         // Check that name is not present.
-        if ip.is_some() {
-            ICACHE.put(self);
+        if let Some(ip) = ip {
+            ICACHE.put(ip);
             return false;
         }
 
-        let mut de = Dirent {
-            inum: 0,
-            name: [0; DIRECTORY_SIZE],
-        };
+        if SUPER_BLOCK.get().long_names() {
+            return self.dir_link_record(name, inum, types);
+        }
+
+        let mut de = Dirent::new();
 
         // Look for an empty dirent.
         let size_de = size_of::<Dirent>() as u32;
@@ -264,16 +390,14 @@ impl INode {
             if de.inum == 0 {
                 break;
             }
-            off += size_de;
+            off += size_de as u64;
         }
 
         let c_str = CString::new(name.clone()).expect("CString::new failed");
         let c_bytes = c_str.to_bytes_with_nul();
-        assert!(c_bytes.len() <= DIRECTORY_SIZE);
 
-        unsafe {
-            ptr::copy(c_bytes as *const _ as *mut [u8; DIRECTORY_SIZE], &mut de.name, 1);
-        }
+        de.name = [0; DIRECTORY_SIZE];
+        de.name[..c_bytes.len()].copy_from_slice(c_bytes);
         de.inum = inum as u16;
 
         if self.write(false, &de as *const _ as usize, off, size_de) != size_de {
@@ -282,6 +406,109 @@ impl INode {
 
         true
     }
+
+    // Put a long-name record for name into the first record with room
+    // to spare, or into a new block at the end.
+    fn dir_link_record(&self, name: &String, inum: u32, types: u16) -> bool {
+        let needed = record_length(name.len()) as u16;
+
+        let mut off = 0;
+        let mut length = BLOCK_SIZE as u16;
+        while off < self.data().size {
+            let mut de = self.read_record(off);
+            let used = if de.inum == 0 { 0 } else { record_length(de.name_length as usize) as u16 };
+            if de.record_length - used >= needed {
+                // Split what the record doesn't use off it.
+                length = de.record_length - used;
+                if used > 0 {
+                    de.record_length = used;
+                    self.write_record(off, &de);
+                }
+                off += used as u64;
+                break;
+            }
+            off += de.record_length as u64;
+        }
+
+        let de = LongDirent {
+            inum,
+            record_length: length,
+            name_length: name.len() as u8,
+            types: types as u8,
+        };
+        let mut record = Vec::with_capacity(length as usize);
+        record.extend_from_slice(unsafe {
+            slice::from_raw_parts(&de as *const _ as *const u8, size_of::<LongDirent>())
+        });
+        record.extend_from_slice(name.as_bytes());
+        if off == self.data().size {
+            // A new block, which the record fills.
+            record.resize(length as usize, 0);
+        }
+        let size_record = record.len() as u32;
+        self.write(false, record.as_ptr() as usize, off, size_record) == size_record
+    }
+
+    // Clear the directory entry at byte offset off, as found by dir_lookup().
+    pub fn dir_unlink(&self, off: u64) {
+        if SUPER_BLOCK.get().long_names() {
+            // Give the record's space to the one before it in its block.
+            let de = self.read_record(off);
+            let mut prev = off - off % BLOCK_SIZE as u64;
+            while prev < off {
+                let mut prev_de = self.read_record(prev);
+                if prev + prev_de.record_length as u64 == off {
+                    prev_de.record_length += de.record_length;
+                    self.write_record(prev, &prev_de);
+                    return;
+                }
+                prev += prev_de.record_length as u64;
+            }
+            self.write_record(off, &LongDirent { inum: 0, ..de });
+            return;
+        }
+
+        let de = Dirent::new();
+        let size_de = size_of::<Dirent>() as u32;
+        if self.write(false, &de as *const _ as usize, off, size_de) != size_de {
+            panic!("dir_unlink: write");
+        }
+    }
+
+    // Point the directory entry at byte offset off to inum,
+    // an inode of type types, keeping its name.
+    pub fn dir_set(&self, off: u64, inum: u32, types: u16) {
+        if SUPER_BLOCK.get().long_names() {
+            let de = self.read_record(off);
+            self.write_record(off, &LongDirent { inum, types: types as u8, ..de });
+            return;
+        }
+
+        let mut de = Dirent::new();
+        let size_de = size_of::<Dirent>() as u32;
+        if self.read(false, &mut de as *mut _ as usize, off, size_de) != size_de {
+            panic!("dir_set: read");
+        }
+        de.inum = inum as u16;
+        if self.write(false, &de as *const _ as usize, off, size_de) != size_de {
+            panic!("dir_set: write");
+        }
+    }
+
+    // Is the directory empty except for "." and ".." ?
+    pub fn is_dir_empty(&self) -> bool {
+        self.dir_find(|name| name != b"." && name != b"..").is_none()
+    }
+}
+
+// The longest name a directory entry can hold.
+pub fn max_name_length() -> usize {
+    if SUPER_BLOCK.get().long_names() {
+        MAX_NAME_LENGTH
+    } else {
+        // leaving room for the terminating 0
+        DIRECTORY_SIZE - 1
+    }
 }
 
 impl INode {
@@ -300,7 +527,13 @@ impl INode {
         dip.major = data.major;
         dip.minor = data.minor;
         dip.nlink = data.nlink;
-        dip.size = data.size;
+        dip.size = data.size as u32;
+        dip.atime = data.atime;
+        dip.mtime = data.mtime;
+        dip.ctime = data.ctime;
+        dip.uid = data.uid;
+        dip.gid = data.gid;
+        dip.mode = data.mode;
         unsafe {
             ptr::copy(&data.addr, &mut dip.addr, 1);
         }
@@ -336,7 +569,13 @@ impl INode {
             data.major = dip.major;
             data.minor = dip.minor;
             data.nlink = dip.nlink;
-            data.size = dip.size;
+            data.size = dip.size as u64;
+            data.atime = dip.atime;
+            data.mtime = dip.mtime;
+            data.ctime = dip.ctime;
+            data.uid = dip.uid;
+            data.gid = dip.gid;
+            data.mode = dip.mode;
             unsafe {
                 ptr::copy(&dip.addr, &mut data.addr, 1);
             }
@@ -362,45 +601,235 @@ impl INode {
         ICACHE.put(self);
     }
 
-    // Truncate inode (discard contents).
-    // Caller must hold ip->lock.
+    // Truncate inode (discard contents), a step per transaction:
+    // freeing a big file dirties more bitmap blocks than one op
+    // may log. Caller must not hold ip->lock or be in a transaction.
     pub fn truncate(&self) {
+        let log = unsafe { &mut LOG };
+        loop {
+            log.begin_op();
+            let guard = self.lock();
+            let done = self.truncate_step();
+            self.unlock(guard);
+            log.end_op();
+            if done {
+                return;
+            }
+        }
+    }
+
+    // Free blocks from the end of the file, as many as one op
+    // may log, and cut the size to match. Returns true once the
+    // file is empty. Caller must hold ip->lock, in a transaction.
+    fn truncate_step(&self) -> bool {
         let data = self.data();
-        for i in 0..DIRECT_COUNT {
-            if data.addr[i] != 0 {
-                Block::free(data.dev, data.addr[i]);
-                data.addr[i] = 0;
+
+        // a write that failed may have left a block past the end,
+        // and the indirect blocks must be reached to be freed.
+        let mut end = (data.size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE + 1;
+        if data.addr[INDIRECT] != 0 {
+            end = end.max(DIRECT_COUNT + 1);
+        }
+        if data.addr[DOUBLE_INDIRECT] != 0 {
+            end = end.max(DIRECT_COUNT + INDIRECT_COUNT + 1);
+        }
+        let mut bn = min(end, MAX_FILE_COUNT);
+
+        let mut step = StepBlocks::new();
+        while bn > 0 && self.free_slot(bn - 1, &mut step) {
+            bn -= 1;
+        }
+
+        data.size = min(data.size, (bn * BLOCK_SIZE) as u64);
+        data.mtime = now();
+        data.ctime = data.mtime;
+        self.update();
+        bn == 0
+    }
+
+    // Free block bn, the last one left, and the indirect blocks
+    // that leaves empty. Returns false, freeing nothing, if that
+    // would log more blocks than step has room for.
+    fn free_slot(&self, bn: usize, step: &mut StepBlocks) -> bool {
+        let data = self.data();
+        let sb = SUPER_BLOCK.get();
+
+        if bn < DIRECT_COUNT {
+            let addr = data.addr[bn];
+            if addr != 0 {
+                if !step.take(&[bblock(addr, sb)]) {
+                    return false;
+                }
+                Block::free(data.dev, addr);
+                data.addr[bn] = 0;
+            }
+            return true;
+        }
+
+        // the indirect block listing bn, its index there, and for
+        // the double-indirect tree, the index of that block in it.
+        let (indirect, index, outer) = if bn < DIRECT_COUNT + INDIRECT_COUNT {
+            (data.addr[INDIRECT], bn - DIRECT_COUNT, None)
+        } else {
+            let i = bn - DIRECT_COUNT - INDIRECT_COUNT;
+            let indirect = lookup_entry(data.dev, data.addr[DOUBLE_INDIRECT], i / INDIRECT_COUNT);
+            (indirect, i % INDIRECT_COUNT, Some(i / INDIRECT_COUNT))
+        };
+        let addr = lookup_entry(data.dev, indirect, index);
+        let double = data.addr[DOUBLE_INDIRECT];
+        // entry 0 is the last one left.
+        let free_indirect = indirect != 0 && index == 0;
+        let free_double = double != 0 && outer == Some(0) && index == 0;
+
+        let mut blocks = [0; 5];
+        let mut n = 0;
+        let mut add = |block: u32| {
+            blocks[n] = block;
+            n += 1;
+        };
+        if addr != 0 {
+            add(bblock(addr, sb));
+            if !free_indirect {
+                add(indirect);
             }
         }
+        if free_indirect {
+            add(bblock(indirect, sb));
+            if outer.is_some() && !free_double {
+                add(double);
+            }
+        }
+        if free_double {
+            add(bblock(double, sb));
+        }
+        if !step.take(&blocks[..n]) {
+            return false;
+        }
 
-        if data.addr[DIRECT_COUNT] != 0 {
-            let bp = BLOCK_CACHE.read(data.dev, data.addr[DIRECT_COUNT]);
-            let a = unsafe { (bp.data() as *mut [u32; 256] as *mut [u32]).as_ref() }.unwrap();
-            for i in 0..INDIRECT_COUNT {
-                if a[i] != 0 {
-                    Block::free(data.dev, a[i] as u32);
+        if addr != 0 {
+            Block::free(data.dev, addr);
+            if !free_indirect {
+                clear_entry(data.dev, indirect, index);
+            }
+        }
+        if free_indirect {
+            Block::free(data.dev, indirect);
+            match outer {
+                None => { data.addr[INDIRECT] = 0; }
+                Some(outer) => {
+                    if !free_double {
+                        clear_entry(data.dev, double, outer);
+                    }
                 }
             }
-            BLOCK_CACHE.release(bp);
-            Block::free(data.dev, data.addr[DIRECT_COUNT]);
-            data.addr[DIRECT_COUNT] = 0;
         }
+        if free_double {
+            Block::free(data.dev, double);
+            data.addr[DOUBLE_INDIRECT] = 0;
+        }
+        true
+    }
+}
 
-        data.size = 0;
-        self.update();
+// Log blocks a truncate step may write, besides the inode's.
+const STEP_LOG_BLOCKS: usize = MAX_OP_BLOCKS - 1;
+
+// The distinct blocks a truncate step has logged so far.
+struct StepBlocks {
+    blocks: [u32; STEP_LOG_BLOCKS],
+    n: usize,
+}
+
+impl StepBlocks {
+    fn new() -> StepBlocks {
+        StepBlocks {
+            blocks: [0; STEP_LOG_BLOCKS],
+            n: 0,
+        }
+    }
+
+    // Count blocks in, unless there is no room for the new ones.
+    fn take(&mut self, blocks: &[u32]) -> bool {
+        let mut new = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            if !self.blocks[..self.n].contains(block) && !blocks[..i].contains(block) {
+                new += 1;
+            }
+        }
+        if self.n + new > STEP_LOG_BLOCKS {
+            return false;
+        }
+        for block in blocks {
+            if !self.blocks[..self.n].contains(block) {
+                self.blocks[self.n] = *block;
+                self.n += 1;
+            }
+        }
+        true
+    }
+}
+
+// The block number at index of the indirect block,
+// allocating a data block for it if there is none.
+fn map_entry(dev: u32, indirect: u32, index: usize) -> u32 {
+    let log = unsafe { &mut LOG };
+
+    let bp = BLOCK_CACHE.read(dev, indirect);
+    let a = unsafe { (bp.data() as *mut [u32; INDIRECT_COUNT]).as_mut() }.unwrap();
+    let mut addr = a[index];
+    if addr == 0 {
+        addr = Block::alloc(dev);
+        a[index] = addr;
+        log.write(&bp);
+    }
+    BLOCK_CACHE.release(bp);
+
+    addr
+}
+
+// Entry index of indirect block, 0 if there is no such block.
+fn lookup_entry(dev: u32, indirect: u32, index: usize) -> u32 {
+    if indirect == 0 {
+        return 0;
+    }
+    let bp = BLOCK_CACHE.read(dev, indirect);
+    let addr = unsafe { (*(bp.data() as *const [u32; INDIRECT_COUNT]))[index] };
+    BLOCK_CACHE.release(bp);
+    addr
+}
+
+// Zero entry index of indirect block.
+fn clear_entry(dev: u32, indirect: u32, index: usize) {
+    let log = unsafe { &mut LOG };
+
+    let bp = BLOCK_CACHE.read(dev, indirect);
+    unsafe {
+        (*(bp.data() as *mut [u32; INDIRECT_COUNT]))[index] = 0;
     }
+    log.write(&bp);
+    BLOCK_CACHE.release(bp);
 }
 
 pub static ICACHE: ICache = ICache::new();
 
+// Inodes put with no links left, waiting for reclaim().
+struct Orphans {
+    // their addresses in ICache.nodes
+    inodes: Vec<usize>,
+    // someone is in reclaim().
+    reclaiming: bool,
+}
+
 pub struct ICache {
     nodes: SpinLock<[INode; MAX_INODE_NUMBER]>,
+    orphans: SpinLock<Orphans>,
 }
 
 impl ICache {
     const fn new() -> ICache {
         ICache {
             nodes: SpinLock::new(array![_ => INode::new(); MAX_INODE_NUMBER], "icache"),
+            orphans: SpinLock::new(Orphans { inodes: Vec::new(), reclaiming: false }, "orphans"),
         }
     }
 
@@ -418,6 +847,9 @@ impl ICache {
                     ptr::write_bytes(dip as *mut INodeDisk, 0, 1);
                 }
                 dip.types = types;
+                dip.atime = now();
+                dip.mtime = dip.atime;
+                dip.ctime = dip.atime;
                 log.write(&bp);
                 BLOCK_CACHE.release(bp);
                 return self.get(dev, inum);
@@ -466,31 +898,58 @@ impl ICache {
     // If that was the last reference, the inode cache entry can
     // be recycled.
     // If that was the last reference and the inode has no links
-    // to it, free the inode (and its content) on disk.
-    // All calls to iput() must be inside a transaction in
-    // case it has to free the inode.
+    // to it, it keeps the reference until reclaim() frees the
+    // inode (and its content) on disk.
+    // All calls to iput() must be inside a transaction.
     pub fn put(&self, inode: &INode) {
-        let mut guard = self.nodes.lock();
+        let guard = self.nodes.lock();
         let data = inode.data();
 
         if data.ref_count == 1 && data.valid && data.nlink == 0 {
-            // inode has no links and no other references: truncate and free.
-
-            // ip->ref == 1 means no other process can have ip locked,
-            // so this acquiresleep() won't block (or deadlock).
-            let inode_guard = inode.lock.lock();
+            // inode has no links and no other references: freeing it
+            // may take several transactions, so it waits for the
+            // caller to be done with this one.
             drop(guard);
+            let mut orphans = self.orphans.lock();
+            orphans.inodes.push(inode as *const INode as usize);
+            drop(orphans);
+            return;
+        }
+
+        data.ref_count -= 1;
+        drop(guard);
+    }
+
+    // Free the inodes put with no links left, and their content.
+    // Called at the end of system calls, outside any transaction;
+    // whoever comes first frees them all.
+    pub fn reclaim(&self) {
+        let log = unsafe { &mut LOG };
+
+        let mut orphans = self.orphans.lock();
+        if orphans.reclaiming {
+            drop(orphans);
+            return;
+        }
+        orphans.reclaiming = true;
+        while let Some(inode) = orphans.inodes.pop() {
+            drop(orphans);
+            let inode = unsafe { (inode as *const INode).as_ref() }.unwrap();
 
             inode.truncate();
-            data.types = 0;
+            log.begin_op();
+            let guard = inode.lock();
+            inode.data().types = 0;
             inode.update();
-            data.valid = false;
+            inode.data().valid = false;
+            inode.unlock(guard);
+            // not valid, so just a reference dropped.
+            self.put(inode);
+            log.end_op();
 
-            drop(inode_guard);
-            guard = self.nodes.lock();
+            orphans = self.orphans.lock();
         }
-
-        data.ref_count -= 1;
-        drop(guard);
+        orphans.reclaiming = false;
+        drop(orphans);
     }
 }
@@ -1,3 +1,4 @@
+use core::cmp::{max, min};
 use core::intrinsics::size_of;
 use core::ptr;
 
@@ -5,18 +6,53 @@ use crate::file_system::{BLOCK_CACHE, BLOCK_SIZE, SuperBlock};
 use crate::file_system::buffer_cache::BufferGuard;
 use crate::process::{CPU_MANAGER, PROCESS_MANAGER};
 use crate::spin_lock::SpinLock;
-use param_lib::{LOG_SIZE, MAX_OP_BLOCKS};
+use crate::trap::TICKS;
+use param_lib::{FLUSH_INTERVAL, MAX_OP_BLOCKS};
 
+// Simple logging that allows concurrent FS system calls.
+//
+// A log transaction contains the updates of multiple FS system
+// calls. The logging system only commits when there are
+// no FS system calls active in the open transaction, so there
+// is never any reasoning required about whether a commit might
+// write an uncommitted system call's updates to disk.
+//
+// Commits are group commits: while one transaction is being
+// written out, the next one keeps accumulating system calls, and
+// is committed as a whole once the first is done. To make that
+// safe, a closing transaction's blocks are first copied to their
+// log buffers, with begin_op() held off; the rest of the commit
+// writes those copies, and the cached blocks are free to change.
+//
+// Commits are also lazy: the open transaction commits once it
+// fills half the log, when the flusher thread or fsync()/sync()
+// ask for it, or when an op needs the space. Installing a
+// committed transaction at the home locations waits in turn
+// until the log is about to be reused, or sync().
+//
+// The log is a physical re-do log containing disk blocks.
+// The on-disk log format:
+//   header block, containing block #s for block A, B, C, ...
+//   block A
+//   block B
+//   block C
+//   ...
+// Log appends are started all at once, and then waited for.
+
+// block numbers that fit in the header block after n.
+const LOG_HEADER_CAPACITY: usize = BLOCK_SIZE / size_of::<u32>() - 1;
+
+#[repr(C)]
 struct LogHeader {
-    n: usize,
-    block: [usize; LOG_SIZE],
+    n: u32,
+    block: [u32; LOG_HEADER_CAPACITY],
 }
 
 impl LogHeader {
     const fn new() -> LogHeader {
         LogHeader {
             n: 0,
-            block: [0; LOG_SIZE],
+            block: [0; LOG_HEADER_CAPACITY],
         }
     }
 }
@@ -25,12 +61,27 @@ pub struct Log {
     lock: SpinLock<()>,
     start: u32,
     size: u32,
-    // how many FS sys calls are executing.
+    // most blocks in one transaction.
+    capacity: usize,
+    // how many FS sys calls are executing in the open transaction.
     outstanding: usize,
-    // in commit(), please wait.
+    // log blocks those calls may still write.
+    reserved: usize,
+    // copying the closed transaction to the log buffers, please wait.
+    freezing: bool,
+    // in commit(), the open transaction can't commit yet.
     committing: bool,
+    // someone waits for the open transaction to commit.
+    force: bool,
+    // number of the open transaction, and of the
+    // last one to commit.
+    sequence: usize,
+    committed_sequence: usize,
     dev: u32,
+    // the open transaction.
     header: LogHeader,
+    // the one being committed, or not yet installed, or recovered.
+    committed: LogHeader,
 }
 
 pub static mut LOG: Log = Log::new();
@@ -41,19 +92,32 @@ impl Log {
             lock: SpinLock::new((), "log"),
             start: 0,
             size: 0,
+            capacity: 0,
             outstanding: 0,
+            reserved: 0,
+            freezing: false,
             committing: false,
+            force: false,
+            sequence: 1,
+            committed_sequence: 0,
             dev: 0,
             header: LogHeader::new(),
+            committed: LogHeader::new(),
         }
     }
 
     pub fn init(&mut self, dev: u32, sb: &SuperBlock) {
-        assert!(size_of::<LogHeader>() < BLOCK_SIZE);
+        assert!(size_of::<LogHeader>() <= BLOCK_SIZE);
 
         self.start = sb.log_start;
         self.size = sb.log_number;
         self.dev = dev;
+        // A commit pins the blocks of the committing transaction, their
+        // copies in the log and the blocks of the open transaction;
+        // the rest of the cache is left to everyone else.
+        let pinned = (BLOCK_CACHE.size() - 3 * MAX_OP_BLOCKS) / 3;
+        self.capacity = min(min(self.size as usize - 1, LOG_HEADER_CAPACITY), pinned);
+        assert!(self.capacity >= MAX_OP_BLOCKS);
 
         self.recover_from_log();
     }
@@ -61,33 +125,33 @@ impl Log {
     fn recover_from_log(&mut self) {
         self.read_head();
         self.install_transaction(true); // if committed, copy from log to disk
-        self.header.n = 0;
+        self.committed.n = 0;
         self.write_head(); // clear the log
     }
 
-    // Read the log header from disk into the in-memory log header
+    // Read the log header from disk into the committed header
     fn read_head(&mut self) {
         let buffer = BLOCK_CACHE.read(self.dev, self.start);
         let header = buffer.data() as *const LogHeader;
         unsafe {
-            self.header.n = (*header).n;
-            for i in 0..self.header.n {
-                self.header.block[i] = (*header).block[i];
+            self.committed.n = (*header).n;
+            for i in 0..self.committed.n as usize {
+                self.committed.block[i] = (*header).block[i];
             }
         }
         BLOCK_CACHE.release(buffer);
     }
 
-    // Write in-memory log header to disk.
+    // Write the committed header to disk.
     // This is the true point at which the
-    // current transaction commits.
+    // committed transaction commits.
     fn write_head(&mut self) {
         let buffer = BLOCK_CACHE.read(self.dev, self.start);
         let header = buffer.data() as *mut LogHeader;
         unsafe {
-            (*header).n = self.header.n;
-            for i in 0..self.header.n {
-                (*header).block[i] = self.header.block[i];
+            (*header).n = self.committed.n;
+            for i in 0..self.committed.n as usize {
+                (*header).block[i] = self.committed.block[i];
             }
         }
 
@@ -97,112 +161,295 @@ impl Log {
 
     // Copy committed blocks from log to their home location
     fn install_transaction(&mut self, recovering: bool) {
-        for i in 0..self.header.n {
+        for i in 0..self.committed.n as usize {
             let log_buffer = BLOCK_CACHE.read(self.dev, self.start + i as u32 + 1);
-            let dest_buffer = BLOCK_CACHE.read(self.dev, self.header.block[i] as u32);
-            unsafe {
-                ptr::copy(log_buffer.data(), dest_buffer.data(), 1);
-            }
-            BLOCK_CACHE.write(&dest_buffer);
-            if !recovering {
-                BLOCK_CACHE.unpin(&dest_buffer);
+            let dest_buffer = BLOCK_CACHE.read(self.dev, self.committed.block[i]);
+            if recovering {
+                unsafe {
+                    ptr::copy(log_buffer.data(), dest_buffer.data(), 1);
+                }
+                BLOCK_CACHE.write(&dest_buffer);
+            } else {
+                // The cached block may hold changes of the open
+                // transaction by now; write the copy in the log instead.
+                BLOCK_CACHE.start_write_to(&log_buffer, dest_buffer.block_no());
             }
             BLOCK_CACHE.release(log_buffer);
             BLOCK_CACHE.release(dest_buffer);
         }
+        if recovering {
+            return;
+        }
+
+        // Wait for the writes, which are started all at once; until
+        // they are done the cached blocks are the only good copies.
+        for i in 0..self.committed.n as usize {
+            let log_buffer = BLOCK_CACHE.read(self.dev, self.start + i as u32 + 1);
+            let dest_buffer = BLOCK_CACHE.read(self.dev, self.committed.block[i]);
+            BLOCK_CACHE.unpin(&dest_buffer);
+            BLOCK_CACHE.unpin(&log_buffer);
+            BLOCK_CACHE.release(log_buffer);
+            BLOCK_CACHE.release(dest_buffer);
+        }
     }
 
+    // Install the committed transaction, if there is
+    // one, and erase it from the log. Caller is the committer.
+    fn checkpoint(&mut self) {
+        if self.committed.n == 0 {
+            return;
+        }
+        self.install_transaction(false);
+        self.committed.n = 0;
+        self.write_head();
+    }
+
+    // Should the open transaction commit now? Needs the lock.
+    fn due(&self) -> bool {
+        self.outstanding == 0 && self.header.n > 0 &&
+            (self.force || self.header.n as usize >= self.capacity / 2)
+    }
+
+    // called at the start of each FS system call.
     pub fn begin_op(&mut self) {
+        self.begin_op_with(MAX_OP_BLOCKS);
+    }
+
+    // Like begin_op(), for a system call that may write
+    // up to blocks different blocks.
+    pub fn begin_op_with(&mut self, blocks: usize) {
+        assert!(blocks <= self.capacity);
+
         let mut guard = self.lock.lock();
         loop {
-            if self.committing {
+            if self.freezing {
                 CPU_MANAGER.my_cpu().sleep(self as *const _ as usize, guard);
                 guard = self.lock.lock();
-            } else if self.header.n + (self.outstanding + 1) * MAX_OP_BLOCKS > LOG_SIZE {
-                CPU_MANAGER.my_cpu().sleep(self as *const _ as usize, guard);
+            } else if self.header.n as usize + self.reserved + blocks > self.capacity {
+                // this op might exhaust log space; wait for commit,
+                // or commit now if no op is left to do it.
+                self.force = true;
+                if !self.committing && self.due() {
+                    self.committing = true;
+                    drop(guard);
+                    self.commit();
+                } else {
+                    CPU_MANAGER.my_cpu().sleep(self as *const _ as usize, guard);
+                }
                 guard = self.lock.lock();
             } else {
                 self.outstanding += 1;
+                self.reserved += blocks;
                 drop(guard);
                 break;
             }
         }
     }
 
+    // called at the end of each FS system call.
+    // commits if this was the last outstanding operation
+    // and the open transaction is due.
     pub fn end_op(&mut self) {
-        let mut commit = false;
+        self.end_op_with(MAX_OP_BLOCKS);
+    }
+
+    pub fn end_op_with(&mut self, blocks: usize) {
+        let commit;
 
         {
             let guard = self.lock.lock();
 
             self.outstanding -= 1;
-            assert!(!self.committing);
+            self.reserved -= blocks;
 
-            if self.outstanding == 0 {
+            if !self.committing && self.due() {
                 commit = true;
                 self.committing = true;
             } else {
                 // begin_op() may be waiting for log space,
-                // and decrementing log.outstanding has decreased
-                // the amount of reserved space.
+                // and decrementing log.reserved has decreased
+                // the amount of reserved space. If a commit is
+                // under way, it picks up this transaction after.
+                commit = false;
                 PROCESS_MANAGER.wake_up(self as *const _ as usize);
             }
 
             drop(guard);
         }
 
-
         if commit {
             // call commit w/o holding locks, since not allowed
             // to sleep with locks.
             self.commit();
+        }
+    }
+
+    // As the committer, commit the open transaction if it is due,
+    // and then any that became due while this one was written out.
+    fn commit(&mut self) {
+        loop {
+            let guard = self.lock.lock();
+            if !self.due() {
+                // an end_op() or flush() commits it once it is due.
+                self.committing = false;
+                PROCESS_MANAGER.wake_up(self as *const _ as usize);
+                drop(guard);
+                return;
+            }
+            drop(guard);
+
+            self.checkpoint(); // The log is about to be reused
+
             let guard = self.lock.lock();
-            self.committing = false;
+            if self.outstanding > 0 {
+                // an op began meanwhile; it commits at its end_op().
+                drop(guard);
+                continue;
+            }
+            self.freezing = true;
+            self.force = false;
+            self.committed.n = self.header.n;
+            for i in 0..self.header.n as usize {
+                self.committed.block[i] = self.header.block[i];
+            }
+            self.header.n = 0;
+            let sequence = self.sequence;
+            self.sequence += 1;
+            drop(guard);
+
+            self.freeze();
+            let guard = self.lock.lock();
+            self.freezing = false;
+            PROCESS_MANAGER.wake_up(self as *const _ as usize);
+            drop(guard);
+
+            self.write_log(); // Write the copies to the log
+            self.write_head(); // Write header to disk -- the real commit
+
+            let guard = self.lock.lock();
+            self.committed_sequence = sequence;
             PROCESS_MANAGER.wake_up(self as *const _ as usize);
             drop(guard);
         }
     }
 
-    fn commit(&mut self) {
-        if self.header.n > 0 {
-            self.write_log();
-            self.write_head();
-            self.install_transaction(false);
-            self.header.n = 0;
-            self.write_head();
+    // Wait until the updates of every finished FS system
+    // call are committed, and so survive a crash.
+    pub fn flush(&mut self) {
+        let mut guard = self.lock.lock();
+        let target = if self.header.n > 0 { self.sequence } else { self.sequence - 1 };
+        while self.committed_sequence < target {
+            self.force = true;
+            if !self.committing && self.due() {
+                self.committing = true;
+                drop(guard);
+                self.commit();
+            } else {
+                CPU_MANAGER.my_cpu().sleep(self as *const _ as usize, guard);
+            }
+            guard = self.lock.lock();
         }
+        drop(guard);
     }
 
-    fn write_log(&mut self) {
-        for i in 0..self.header.n {
-            let log_buffer = BLOCK_CACHE.read(self.dev, self.start + i as u32 + 1);
-            let cache_buffer = BLOCK_CACHE.read(self.dev, self.header.block[i] as u32);
+    // Like flush(), and then also install what is
+    // committed, leaving the log empty.
+    pub fn sync(&mut self) {
+        self.flush();
+
+        let mut guard = self.lock.lock();
+        while self.committing {
+            CPU_MANAGER.my_cpu().sleep(self as *const _ as usize, guard);
+            guard = self.lock.lock();
+        }
+        self.committing = true;
+        drop(guard);
+
+        self.checkpoint();
+        // hand over to whatever came due meanwhile.
+        self.commit();
+    }
+
+    // Copy the committed blocks from the cache to their
+    // log buffers, where they stay pinned until installed.
+    fn freeze(&mut self) {
+        for i in 0..self.committed.n as usize {
+            let log_buffer = BLOCK_CACHE.fresh(self.dev, self.start + i as u32 + 1);
+            let cache_buffer = BLOCK_CACHE.read(self.dev, self.committed.block[i]);
             unsafe {
                 ptr::copy(cache_buffer.data(), log_buffer.data(), 1);
             }
-            BLOCK_CACHE.write(&log_buffer);
+            BLOCK_CACHE.pin(&log_buffer);
             BLOCK_CACHE.release(cache_buffer);
             BLOCK_CACHE.release(log_buffer);
         }
     }
 
-    pub fn write(&mut self, buffer: &BufferGuard) {
-        assert!(self.header.n < LOG_SIZE && self.header.n < self.size as usize - 1);
-        assert!(self.outstanding >= 1);
+    fn write_log(&mut self) {
+        for i in 0..self.committed.n as usize {
+            let log_buffer = BLOCK_CACHE.read(self.dev, self.start + i as u32 + 1);
+            BLOCK_CACHE.start_write(&log_buffer);
+            BLOCK_CACHE.release(log_buffer);
+        }
+        // read() waits for each write to finish.
+        for i in 0..self.committed.n as usize {
+            let log_buffer = BLOCK_CACHE.read(self.dev, self.start + i as u32 + 1);
+            BLOCK_CACHE.release(log_buffer);
+        }
+    }
 
+    // Caller has modified buffer.data() and is done with the buffer.
+    // Record the block number and pin in the cache by increasing ref_count.
+    // commit()/write_log() will do the disk write.
+    //
+    // log.write() replaces BLOCK_CACHE.write(); a typical use is:
+    //   buffer = BLOCK_CACHE.read(...)
+    //   modify buffer.data()
+    //   log.write(buffer)
+    //   BLOCK_CACHE.release(buffer)
+    pub fn write(&mut self, buffer: &BufferGuard) {
         let guard = self.lock.lock();
+        assert!(self.outstanding >= 1);
 
-        for i in 0..self.header.n {
-            if self.header.block[i] as u32 == buffer.block_no() {
+        // log absorption
+        for i in 0..self.header.n as usize {
+            if self.header.block[i] == buffer.block_no() {
                 drop(guard);
                 return;
             }
         }
 
         // Add new block to log
-        self.header.block[self.header.n] = buffer.block_no() as usize;
+        assert!((self.header.n as usize) < self.capacity);
+        self.header.block[self.header.n as usize] = buffer.block_no();
         BLOCK_CACHE.pin(&buffer);
         self.header.n += 1;
         drop(guard);
     }
-}
\ No newline at end of file
+
+    // The blocks to reserve for a write to a file, a share of the
+    // log large enough that big writes take few transactions.
+    pub fn write_op_blocks(&self) -> usize {
+        max(self.capacity / 2, MAX_OP_BLOCKS)
+    }
+}
+
+// The flusher kernel thread. Every FLUSH_INTERVAL ticks it
+// commits and installs what the FS system calls did meanwhile,
+// so that their updates reach the disk without a sync().
+pub unsafe fn flusher() {
+    // Still holding p->lock from scheduler.
+    CPU_MANAGER.my_proc().lock.unlock();
+
+    loop {
+        let mut ticks = TICKS.lock();
+        let start = *ticks;
+        while *ticks - start < FLUSH_INTERVAL {
+            CPU_MANAGER.my_cpu().sleep(&TICKS as *const _ as usize, ticks);
+            ticks = TICKS.lock();
+        }
+        drop(ticks);
+
+        LOG.sync();
+    }
+}
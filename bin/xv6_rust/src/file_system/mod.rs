@@ -6,6 +6,8 @@ use file_system_lib::{bblock, BLOCK_SIZE, BPB, FSMAGIC, SuperBlock};
 pub use file_table::FILE_TABLE;
 pub use logging::LOG;
 
+use crate::memory::swap::SWAP;
+
 pub mod buffer_cache;
 pub mod logging;
 pub mod inode;
@@ -22,6 +24,7 @@ pub fn file_system_init(dev: u32) {
         assert_eq!(SUPER_BLOCK.get().magic, FSMAGIC);
         LOG.init(dev, SUPER_BLOCK.get());
     }
+    SWAP.init(SUPER_BLOCK.get());
 }
 
 pub static SUPER_BLOCK: SuperBlockWrapper = SuperBlockWrapper::new();
@@ -1,10 +1,11 @@
 use alloc::string::String;
+use alloc::vec;
 use core::ptr::null_mut;
 
 use crate::file_system::inode::{ICACHE, INode};
 use crate::process::CPU_MANAGER;
-use file_system_lib::{ROOT_INO, TYPE_DIR};
-use param_lib::ROOT_DEV;
+use file_system_lib::{ACCESS_EXEC, ROOT_INO, TYPE_DIR, TYPE_SYMLINK};
+use param_lib::{MAX_SYMLINK_DEPTH, ROOT_DEV};
 
 // Copy the next path element from path into name.
 // Return a pointer to the element following the copied one.
@@ -51,74 +52,144 @@ fn split_path(path: &String) -> Option<(String, String)> {
 // path element into name, which must have room for DIRSIZ bytes.
 // Must be called inside a transaction since it calls iput().
 pub fn find_inode(path: &String) -> Option<&'static INode> {
-    let mut ip: *const INode = if path.starts_with("/") {
-        ICACHE.get(ROOT_DEV, ROOT_INO) as *const INode
-    } else {
-        let current_dir = CPU_MANAGER.my_proc().data().current_dir.unwrap();
-        current_dir.dup() as *const INode
-    };
+    let mut depth = 0;
+    walk(start_of(path), path, false, true, &mut depth).map(|(ip, _)| ip)
+}
 
-    let mut next_level = split_path(path);
-    while next_level.is_some() {
-        let ip_ref = unsafe { ip.as_ref() }.unwrap();
+// Like find_inode(), but a symbolic link as the last element
+// is returned itself rather than followed.
+pub fn find_inode_no_follow(path: &String) -> Option<&'static INode> {
+    let mut depth = 0;
+    walk(start_of(path), path, false, false, &mut depth).map(|(ip, _)| ip)
+}
 
-        let (name, remain_path) = next_level.unwrap();
-        let guard = ip_ref.lock();
-        if ip_ref.data().types != TYPE_DIR {
-            ip_ref.unlock_put(guard);
-            return None;
-        }
-        let next_dir = ip_ref.dir_lookup(&name, null_mut());
-        if next_dir.is_none() {
-            ip_ref.unlock_put(guard);
-            return None;
+// The path that open() with OPEN_CREATE creates when path doesn't lead
+// to an inode: path itself, or the target of the symbolic links it names.
+// Must be called inside a transaction since it calls iput().
+pub fn find_create_path(path: &String) -> Option<String> {
+    let mut path = path.clone();
+    for _ in 0..=MAX_SYMLINK_DEPTH {
+        let ip = match find_inode_no_follow(&path) {
+            Some(ip) => { ip }
+            None => { return Some(path); }
+        };
+        let guard = ip.lock();
+        if ip.data().types != TYPE_SYMLINK {
+            ip.unlock_put(guard);
+            return Some(path);
         }
-        ip_ref.unlock_put(guard);
+        let target = read_link(ip);
+        ip.unlock_put(guard);
+        let target = target?;
 
-        ip = next_dir.unwrap();
-        next_level = split_path(&remain_path);
+        // a relative target starts from the directory holding the link.
+        path = if target.starts_with("/") {
+            target
+        } else {
+            let mut dir = match path.rfind('/') {
+                Some(index) => { String::from(&path[..index + 1]) }
+                None => { String::new() }
+            };
+            dir.push_str(&target);
+            dir
+        };
     }
-
-    return unsafe { ip.as_ref() };
+    None
 }
 
 pub fn find_inode_parent(path: &String) -> Option<(&'static INode, String)> {
-    let mut ip: *const INode = if path.starts_with("/") {
-        ICACHE.get(ROOT_DEV, ROOT_INO) as *const INode
+    let mut depth = 0;
+    walk(start_of(path), path, true, false, &mut depth)
+}
+
+// The directory a lookup of path starts from, referenced.
+fn start_of(path: &String) -> &'static INode {
+    if path.starts_with("/") {
+        ICACHE.get(ROOT_DEV, ROOT_INO)
     } else {
         let current_dir = CPU_MANAGER.my_proc().data().current_dir.unwrap();
-        current_dir.dup() as *const INode
-    };
+        current_dir.dup()
+    }
+}
+
+// Walk path from the directory start, taking over its reference.
+// Symbolic links are followed in the middle of path, and at its end if
+// follow is set; depth counts the links followed so far, so that cycles
+// end after MAX_SYMLINK_DEPTH of them.
+// With parent set, stop one level early and return the last element too.
+fn walk(start: &'static INode, path: &String, parent: bool, follow: bool, depth: &mut usize) -> Option<(&'static INode, String)> {
+    let mut ip = start;
 
     let mut next_level = split_path(path);
-    if next_level.is_none() {
+    if parent && next_level.is_none() {
+        ICACHE.put(ip);
         return None;
     }
 
-    while next_level.is_some() {
-        let ip_ref = unsafe { ip.as_ref() }.unwrap();
-
-        let (name, remain_path) = next_level.unwrap();
-        let guard = ip_ref.lock();
-        if ip_ref.data().types != TYPE_DIR {
-            ip_ref.unlock_put(guard);
+    while let Some((name, remain_path)) = next_level {
+        let guard = ip.lock();
+        if ip.data().types != TYPE_DIR || !ip.permitted(ACCESS_EXEC) {
+            ip.unlock_put(guard);
             return None;
         }
-        if remain_path.is_empty() {
+        if parent && remain_path.is_empty() {
             // Stop one level early.
-            ip_ref.unlock(guard);
-            return Some((unsafe { ip.as_ref() }.unwrap(), name));
+            ip.unlock(guard);
+            return Some((ip, name));
         }
-        let next_dir = ip_ref.dir_lookup(&name, null_mut());
-        if next_dir.is_none() {
-            ip_ref.unlock_put(guard);
-            return None;
+        let next_dir = ip.dir_lookup(&name, null_mut());
+        ip.unlock(guard);
+        let mut next_ip = match next_dir {
+            Some(next_ip) => { next_ip }
+            None => {
+                ICACHE.put(ip);
+                return None;
+            }
+        };
+
+        if follow || !remain_path.is_empty() {
+            let guard = next_ip.lock();
+            if next_ip.data().types == TYPE_SYMLINK {
+                *depth += 1;
+                let target = if *depth > MAX_SYMLINK_DEPTH { None } else { read_link(next_ip) };
+                next_ip.unlock_put(guard);
+                let target = match target {
+                    Some(target) => { target }
+                    None => {
+                        ICACHE.put(ip);
+                        return None;
+                    }
+                };
+
+                // a relative target starts from the directory holding the link.
+                let base = if target.starts_with("/") { start_of(&target) } else { ip.dup() };
+                next_ip = match walk(base, &target, false, true, depth) {
+                    Some((next_ip, _)) => { next_ip }
+                    None => {
+                        ICACHE.put(ip);
+                        return None;
+                    }
+                };
+            } else {
+                next_ip.unlock(guard);
+            }
         }
-        ip_ref.unlock_put(guard);
 
-        ip = next_dir.unwrap();
+        ICACHE.put(ip);
+        ip = next_ip;
         next_level = split_path(&remain_path);
     }
 
-    panic!("should not arrive here")
-}
\ No newline at end of file
+    Some((ip, String::new()))
+}
+
+// The target of a symbolic link.
+// Caller must hold ip->lock.
+pub fn read_link(ip: &INode) -> Option<String> {
+    let size = ip.data().size as u32;
+    let mut target = vec![0u8; size as usize];
+    if ip.read(false, target.as_mut_ptr() as usize, 0, size) != size {
+        return None;
+    }
+    String::from_utf8(target).ok()
+}
@@ -22,6 +22,7 @@ global_asm!(include_str!("asm/entry.S"));
 global_asm!(include_str!("asm/kernelvec.S"));
 global_asm!(include_str!("asm/swtch.S"));
 global_asm!(include_str!("asm/trampoline.S"));
+global_asm!(include_str!("asm/user_copy.S"));
 
 #[macro_use]
 mod print;
@@ -29,10 +30,13 @@ mod print;
 mod riscv;
 mod console;
 mod start;
+mod device_tree;
 mod memory;
 mod process;
 mod trap;
 mod plic;
+mod random;
+mod clock;
 mod spin_lock;
 mod sleep_lock;
 mod driver;
@@ -1,9 +1,143 @@
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::{NonNull, null_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::memory::layout::{KERNEL_HEAP_SIZE, KERNEL_HEAP_START};
+use linked_list_allocator::Heap;
+use param_lib::MAX_CPU_NUMBER;
+
+use crate::memory::{KERNEL_PAGETABLE, Page, page_round_up, PAGE_SIZE, PHYSICAL_MEMORY};
+use crate::memory::layout::{KERNEL_HEAP_MAX_SIZE, KERNEL_HEAP_SIZE, KERNEL_HEAP_START};
+use crate::memory::page_table::PageEntryFlags;
+use crate::process::{cpu_id, CPU_MANAGER};
+use crate::riscv::sfence_vma;
+use crate::spin_lock::SpinLock;
+
+// map at least this much whenever the heap runs out.
+const HEAP_GROW_SIZE: usize = 16 * PAGE_SIZE;
+
+// Small objects (path strings, argv vectors, pipes, ...) come from
+// slab caches of these sizes; larger ones from the heap.
+const SLAB_CLASS_COUNT: usize = 6;
+const SLAB_CLASSES: [usize; SLAB_CLASS_COUNT] = [16, 32, 64, 128, 256, 512];
+
+// objects each CPU keeps per cache without touching the cache's lock.
+const MAGAZINE_SIZE: usize = 16;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct Depot {
+    free: *mut FreeObject,
+    free_count: usize,
+    pages: usize,
+}
+
+unsafe impl Send for Depot {}
+
+struct SlabCache {
+    size: usize,
+    depot: SpinLock<Depot>,
+}
+
+impl SlabCache {
+    const fn new(size: usize) -> SlabCache {
+        SlabCache {
+            size,
+            depot: SpinLock::new(Depot {
+                free: null_mut(),
+                free_count: 0,
+                pages: 0,
+            }, "slab"),
+        }
+    }
+
+    // Move half a magazine of objects from the depot into the magazine,
+    // carving a fresh page into objects if the depot is empty.
+    fn refill(&self, magazine: &mut Magazine) {
+        let mut depot = self.depot.lock();
+
+        if depot.free.is_null() {
+            let page = match PHYSICAL_MEMORY.alloc() {
+                Some(frame) => { frame.addr() }
+                None => { return; }
+            };
+            let mut addr = page;
+            while addr + self.size <= page + PAGE_SIZE {
+                let object = addr as *mut FreeObject;
+                unsafe { (*object).next = depot.free; }
+                depot.free = object;
+                depot.free_count += 1;
+                addr += self.size;
+            }
+            depot.pages += 1;
+        }
+
+        while magazine.count < MAGAZINE_SIZE / 2 && !depot.free.is_null() {
+            let object = depot.free;
+            depot.free = unsafe { (*object).next };
+            depot.free_count -= 1;
+            magazine.objects[magazine.count] = object as *mut u8;
+            magazine.count += 1;
+        }
+        drop(depot);
+    }
+
+    // Give half of a full magazine back to the depot.
+    fn flush(&self, magazine: &mut Magazine) {
+        let mut depot = self.depot.lock();
+        while magazine.count > MAGAZINE_SIZE / 2 {
+            magazine.count -= 1;
+            let object = magazine.objects[magazine.count] as *mut FreeObject;
+            unsafe { (*object).next = depot.free; }
+            depot.free = object;
+            depot.free_count += 1;
+        }
+        drop(depot);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Magazine {
+            objects: [null_mut(); MAGAZINE_SIZE],
+            count: 0,
+        }
+    }
+}
+
+// Only touched by its own CPU with interrupts off.
+struct CpuMagazines {
+    magazines: UnsafeCell<[Magazine; SLAB_CLASS_COUNT]>,
+}
+
+impl CpuMagazines {
+    const fn new() -> CpuMagazines {
+        CpuMagazines {
+            magazines: UnsafeCell::new([Magazine::new(); SLAB_CLASS_COUNT]),
+        }
+    }
+}
+
+pub struct KernelHeap {
+    heap: SpinLock<Heap>,
+    // end of the mapped part of the heap region.
+    end: AtomicUsize,
+    caches: [SlabCache; SLAB_CLASS_COUNT],
+    cpus: [CpuMagazines; MAX_CPU_NUMBER],
+}
+
+unsafe impl Sync for KernelHeap {}
 
 #[global_allocator]
-static KERNEL_HEAP: LockedHeap = LockedHeap::empty();
+static KERNEL_HEAP: KernelHeap = KernelHeap::new();
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -12,6 +146,156 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 pub fn kernel_heap_init() {
     unsafe {
-        KERNEL_HEAP.lock().init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
+        KERNEL_HEAP.heap.lock().init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
+    }
+    KERNEL_HEAP.end.store(KERNEL_HEAP_START + KERNEL_HEAP_SIZE, Ordering::SeqCst);
+}
+
+// The heap may have been grown by another hart, whose new
+// mappings this hart's TLB does not know about yet.
+// Called by kerneltrap() on a page fault.
+pub fn handle_page_fault(va: usize) -> bool {
+    if va < KERNEL_HEAP_START || va >= KERNEL_HEAP.end.load(Ordering::SeqCst) {
+        return false;
+    }
+    unsafe {
+        sfence_vma();
+    }
+    true
+}
+
+// Print the mapped heap size and the pages held by each slab cache.
+// Runs when user types ^P on console.
+pub fn print_statistics() {
+    println!("kernel heap: {} KiB mapped", (KERNEL_HEAP.end.load(Ordering::SeqCst) - KERNEL_HEAP_START) / 1024);
+    for cache in KERNEL_HEAP.caches.iter() {
+        let depot = cache.depot.lock();
+        println!("slab {:3}: {:4} pages {:6} free", cache.size, depot.pages, depot.free_count);
+        drop(depot);
+    }
+}
+
+fn slab_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SLAB_CLASSES.iter().position(|class| size <= *class)
+}
+
+impl KernelHeap {
+    const fn new() -> KernelHeap {
+        KernelHeap {
+            heap: SpinLock::new(Heap::empty(), "kernel heap"),
+            end: AtomicUsize::new(0),
+            caches: [
+                SlabCache::new(SLAB_CLASSES[0]),
+                SlabCache::new(SLAB_CLASSES[1]),
+                SlabCache::new(SLAB_CLASSES[2]),
+                SlabCache::new(SLAB_CLASSES[3]),
+                SlabCache::new(SLAB_CLASSES[4]),
+                SlabCache::new(SLAB_CLASSES[5]),
+            ],
+            cpus: array![_ => CpuMagazines::new(); MAX_CPU_NUMBER],
+        }
+    }
+
+    fn slab_alloc(&self, class: usize) -> *mut u8 {
+        let cpu = CPU_MANAGER.my_cpu();
+        cpu.push_off();
+        let magazine = unsafe { &mut (*self.cpus[cpu_id()].magazines.get())[class] };
+        if magazine.count == 0 {
+            self.caches[class].refill(magazine);
+        }
+        let object = if magazine.count == 0 {
+            null_mut()
+        } else {
+            magazine.count -= 1;
+            magazine.objects[magazine.count]
+        };
+        cpu.pop_off();
+        object
+    }
+
+    fn slab_free(&self, object: *mut u8, class: usize) {
+        let cpu = CPU_MANAGER.my_cpu();
+        cpu.push_off();
+        let magazine = unsafe { &mut (*self.cpus[cpu_id()].magazines.get())[class] };
+        if magazine.count == MAGAZINE_SIZE {
+            self.caches[class].flush(magazine);
+        }
+        magazine.objects[magazine.count] = object;
+        magazine.count += 1;
+        cpu.pop_off();
+    }
+
+    fn heap_alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        loop {
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if !self.grow(&mut heap, layout) {
+                return null_mut();
+            }
+        }
+    }
+
+    // Map more frames at the end of the heap region and hand them to the heap.
+    // Returns false if nothing could be mapped.
+    fn grow(&self, heap: &mut Heap, layout: Layout) -> bool {
+        let end = self.end.load(Ordering::SeqCst);
+        let mut size = page_round_up(layout.size() + layout.align()).max(HEAP_GROW_SIZE);
+        if end + size > KERNEL_HEAP_START + KERNEL_HEAP_MAX_SIZE {
+            size = KERNEL_HEAP_START + KERNEL_HEAP_MAX_SIZE - end;
+        }
+
+        let rw = PageEntryFlags::READABLE | PageEntryFlags::WRITEABLE;
+        let mut page_table = KERNEL_PAGETABLE.lock();
+        let mut mapped = 0;
+        while mapped < size {
+            let frame = match PHYSICAL_MEMORY.alloc() {
+                Some(frame) => { frame }
+                None => { break; }
+            };
+            if let Err(frame) = page_table.map(Page::from_virtual_address(end + mapped), frame, rw) {
+                PHYSICAL_MEMORY.dealloc(frame);
+                break;
+            }
+            mapped += PAGE_SIZE;
+        }
+        unsafe {
+            sfence_vma();
+        }
+        drop(page_table);
+
+        if mapped == 0 {
+            return false;
+        }
+        unsafe {
+            heap.extend(mapped);
+        }
+        self.end.store(end + mapped, Ordering::SeqCst);
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = slab_class(&layout) {
+            let object = self.slab_alloc(class);
+            if !object.is_null() {
+                return object;
+            }
+        }
+        self.heap_alloc(layout)
+    }
+
+    // Slab objects live in the direct-mapped physical memory,
+    // so the address tells which allocator a block came from.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let addr = ptr as usize;
+        if addr >= KERNEL_HEAP_START && addr < KERNEL_HEAP_START + KERNEL_HEAP_MAX_SIZE {
+            self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+        } else {
+            self.slab_free(ptr, slab_class(&layout).unwrap());
+        }
     }
 }
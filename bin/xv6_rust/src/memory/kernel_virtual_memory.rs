@@ -1,7 +1,10 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use lazy_static::lazy_static;
 
-use crate::memory::{ActivePageTable, make_satp, Page, page_round_down, PAGE_SIZE, PHYSICAL_MEMORY};
-use crate::memory::layout::{CLINT, KERNEL_BASE, KERNEL_HEAP_SIZE, KERNEL_HEAP_START, PHY_STOP, PLIC, TRAMPOLINE, UART0, VIRTIO0};
+use crate::device_tree::machine;
+use crate::memory::{ActivePageTable, GIGA_PAGE_SIZE, make_satp, MEGA_PAGE_SIZE, Page, page_round_down, PAGE_SIZE, PHYSICAL_MEMORY};
+use crate::memory::layout::{KERNEL_BASE, KERNEL_HEAP_SIZE, KERNEL_HEAP_START, TRAMPOLINE, USER_STACK_BASE, USER_STACK_TOP};
 use crate::memory::page_table::PageEntryFlags;
 use crate::riscv::{sfence_vma, write_satp};
 use crate::spin_lock::SpinLock;
@@ -24,13 +27,22 @@ lazy_static! {
 
         let etext = etext as usize;
         let trampoline = trampoline as usize;
-
-        page_table.map_pages(UART0, UART0, PAGE_SIZE, rw);
-        page_table.map_pages(VIRTIO0, VIRTIO0, PAGE_SIZE, rw);
-        page_table.map_pages(CLINT, CLINT, 0x10000, rw);
-        page_table.map_pages(PLIC, PLIC, 0x400000, rw);
+        let machine = machine();
+
+        // map_pages() uses 2 MiB and 1 GiB leaves where the ranges allow it,
+        // so most of RAM and the PLIC take a handful of entries.
+        page_table.map_pages(machine.uart, machine.uart, PAGE_SIZE, rw);
+        page_table.map_pages(machine.virtio, machine.virtio, PAGE_SIZE, rw);
+        page_table.map_pages(machine.clint, machine.clint, 0x10000, rw);
+        page_table.map_pages(machine.plic, machine.plic, machine.plic_size, rw);
         page_table.map_pages(KERNEL_BASE, KERNEL_BASE, etext - KERNEL_BASE, rx);
-        page_table.map_pages(etext, etext , PHY_STOP - etext, rw);
+        for &(start, end) in machine.memory() {
+            // the range holding the kernel starts at KERNEL_BASE.
+            let start = start.max(etext);
+            if start < end {
+                page_table.map_pages(start, start, end - start, rw);
+            }
+        }
         page_table.map_pages(TRAMPOLINE, trampoline , PAGE_SIZE, rx);
 
         page_table.alloc_pages(KERNEL_HEAP_START, KERNEL_HEAP_SIZE, rw);
@@ -40,12 +52,13 @@ lazy_static! {
 }
 
 impl ActivePageTable {
+    // Only used at boot, to map the initial kernel heap.
     fn alloc_pages(&mut self, virtual_memory: usize, size: usize, perm: PageEntryFlags) {
         let mut v_addr = page_round_down(virtual_memory);
         let v_last = page_round_down(virtual_memory + size - 1) + PAGE_SIZE;
 
         while v_addr < v_last {
-            let frame = PHYSICAL_MEMORY.alloc().unwrap();
+            let frame = PHYSICAL_MEMORY.alloc().expect("alloc_pages: no memory for the boot-time kernel heap");
             let result = self.map(
                 Page::from_virtual_address(v_addr),
                 frame,
@@ -62,18 +75,73 @@ pub fn kernel_page_table_init() {
     let page_table = &*KERNEL_PAGETABLE.lock();
 
     let etext = etext as usize;
-    assert!(page_table.translate(UART0).is_some());
-    assert!(page_table.translate(VIRTIO0).is_some());
-    assert!(page_table.translate(CLINT).is_some());
-    assert!(page_table.translate(PLIC).is_some());
+    let machine = machine();
+    assert!(page_table.translate(machine.uart).is_some());
+    assert!(page_table.translate(machine.virtio).is_some());
+    assert!(page_table.translate(machine.clint).is_some());
+    assert!(page_table.translate(machine.plic).is_some());
     assert!(page_table.translate(KERNEL_BASE).is_some());
     assert!(page_table.translate(etext).is_some());
     assert!(page_table.translate(TRAMPOLINE).is_some());
 }
 
+// satp of KERNEL_PAGETABLE, so the scheduler can switch back without the lock.
+static KERNEL_SATP: AtomicUsize = AtomicUsize::new(0);
+
 pub fn hart_init() {
+    let satp = make_satp(&*KERNEL_PAGETABLE.lock());
+    KERNEL_SATP.store(satp, Ordering::Relaxed);
     unsafe {
-        write_satp(make_satp(&*KERNEL_PAGETABLE.lock()));
+        write_satp(satp);
         sfence_vma();
     }
-}
\ No newline at end of file
+}
+
+// Called by the scheduler after a process gives up the CPU.
+pub fn switch_to_kernel_page_table() {
+    unsafe {
+        write_satp(KERNEL_SATP.load(Ordering::Relaxed));
+        sfence_vma();
+    }
+}
+
+// The user addresses a process kernel page table can map:
+// everything in the first GiB below the devices the kernel
+// uses from supervisor mode, and the stack region.
+fn user_windows() -> [(usize, usize); 2] {
+    let machine = machine();
+    let low_end = [machine.uart, machine.virtio, machine.plic].iter()
+        .map(|&it| it / MEGA_PAGE_SIZE * MEGA_PAGE_SIZE)
+        .fold(GIGA_PAGE_SIZE, usize::min);
+    [(0, low_end), (USER_STACK_BASE, USER_STACK_TOP)]
+}
+
+// Each process runs in the kernel on its own copy of the kernel page
+// table, which also maps the process's user memory in user_windows(),
+// so copy_in() and copy_out() can use user addresses directly.
+// It can leave out the CLINT, which is only used from machine mode.
+pub fn alloc_process_page_table() -> Option<ActivePageTable> {
+    let guard = KERNEL_PAGETABLE.lock();
+    let page_table = ActivePageTable::new_process_kernel(&*guard, &user_windows());
+    drop(guard);
+    page_table
+}
+
+pub fn free_process_page_table(page_table: ActivePageTable) {
+    page_table.free_process_kernel(&user_windows());
+}
+
+// The end of the user window holding va, if any.
+pub fn user_window_end(va: usize) -> Option<usize> {
+    user_windows().iter().find(|&&(start, end)| va >= start && va < end).map(|&(_, end)| end)
+}
+
+// Make page_table reach all of user's memory in user_windows(), e.g. after exec()
+// replaced the user page table. Returns true if the TLB must be flushed.
+pub fn share_user_windows(page_table: &mut ActivePageTable, user: &ActivePageTable) -> bool {
+    let mut changed = false;
+    for &(start, end) in user_windows().iter() {
+        changed |= page_table.share_user_range(user, start, end);
+    }
+    changed
+}
@@ -1,6 +1,9 @@
 use crate::memory::PAGE_SIZE;
 
 pub const KERNEL_BASE: usize = 0x80000000;
+
+// qemu -machine virt's defaults, used where the device tree
+// doesn't say otherwise; see device_tree.rs.
 pub const PHY_STOP: usize = KERNEL_BASE + 128 * 1024 * 1024;
 pub const MAX_VA: usize = 1 << ((9 + 9 + 9 + 12) - 1);
 
@@ -11,11 +14,30 @@ pub const VIRTIO0: usize = 0x10001000;
 pub const VIRTIO0_IRQ: usize = 1;
 
 pub const CLINT: usize = 0x2000000;
+pub const RTC: usize = 0x101000;
+// time CSR ticks per second
+pub const TIMEBASE_FREQUENCY: usize = 10000000;
 pub const PLIC: usize = 0x0c000000;
+pub const PLIC_SIZE: usize = 0x400000;
 pub const TRAMPOLINE: usize = MAX_VA - PAGE_SIZE;
 pub const TRAPFRAME: usize = TRAMPOLINE - PAGE_SIZE;
 
+// user stacks grow down from below USER_STACK_TOP, at most to
+// USER_STACK_BASE; this leaves the last 2 MiB of user space
+// to the trampoline and trap frame.
+pub const USER_STACK_TOP: usize = MAX_VA - 2 * 1024 * 1024;
+pub const USER_STACK_BASE: usize = MAX_VA - 1024 * 1024 * 1024;
+// the lowest stack page stays this far above the heap
+pub const STACK_GUARD_GAP: usize = 1024 * 1024;
+
+// shared memory segments are attached above here;
+// the user image and heap stay below.
+pub const SHARED_MEMORY_BASE: usize = MAX_VA / 2;
+
 pub const KERNEL_HEAP_START: usize = 0x40000000;
 pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024;
+// the heap grows on demand up to this size
+pub const KERNEL_HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
 
+// must be a power of two
 pub const KERNEL_STACK_PAGE_COUNT: usize = 4;
@@ -11,6 +11,7 @@ pub use virtual_memory::ActivePageTable;
 pub use virtual_memory::Page;
 
 use crate::process::CPU_MANAGER;
+use crate::riscv::{read_sstatus, sfence_vma, SSTATUS_SUM, write_sstatus};
 
 pub mod layout;
 pub mod physical_memory;
@@ -19,14 +20,29 @@ pub mod page_table;
 pub mod kernel_virtual_memory;
 pub mod kernel_heap;
 pub mod user_virtual_memory;
+pub mod swap;
+pub mod shared_memory;
 
 pub const PAGE_SIZE: usize = 4096;
+// leaf entries of the Level2 and Level3 tables
+pub const MEGA_PAGE_SIZE: usize = 512 * PAGE_SIZE;
+pub const GIGA_PAGE_SIZE: usize = 512 * MEGA_PAGE_SIZE;
 
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
 const SATP_SV39: usize = 8 << 60;
 
+// copy_in_string() reads this many bytes at a time.
+const STRING_CHUNK: usize = 128;
+
+extern {
+    fn user_copy(dst: usize, src: usize, len: usize) -> isize;
+    fn user_copy_string(dst: usize, src: usize, max: usize) -> isize;
+    fn user_copy_fault();
+    fn user_copy_end();
+}
+
 #[inline]
 pub fn make_satp(page_table: &ActivePageTable) -> usize {
     SATP_SV39 | (page_table.addr() >> 12)
@@ -76,13 +92,58 @@ pub fn either_copy_in(user_src: bool, dst: usize, src: usize, len: usize) -> boo
     };
 }
 
+// Where kerneltrap() resumes after a page fault at pc,
+// if pc is inside user_copy.S.
+pub fn user_copy_fixup(pc: usize) -> Option<usize> {
+    if pc >= user_copy as usize && pc < user_copy_end as usize {
+        Some(user_copy_fault as usize)
+    } else {
+        None
+    }
+}
+
+// Run f with [va, va + len) of pt reachable at its user addresses.
+// Only the running process's page table can be reached this way,
+// through its kernel page table, and only within its user windows.
+// Returns false if the range can't be reached or f failed, e.g. on a
+// swapped-out page or a bad pointer; callers then walk pt instead.
+fn direct_access<F: FnOnce() -> bool>(pt: &ActivePageTable, va: usize, len: usize, f: F) -> bool {
+    let end = match (va.checked_add(len), kernel_virtual_memory::user_window_end(va)) {
+        (Some(end), Some(window_end)) if len > 0 && end <= window_end => { end }
+        _ => { return false; }
+    };
+
+    let process = CPU_MANAGER.my_cpu().my_proc();
+    if process.is_null() {
+        return false;
+    }
+    let data = unsafe { &*process }.data();
+    match (data.page_table.as_ref(), data.kernel_page_table.as_mut()) {
+        (Some(user), Some(kernel)) if user.addr() == pt.addr() => {
+            if kernel.share_user_range(user, va, end) {
+                unsafe { sfence_vma(); }
+            }
+        }
+        _ => { return false; }
+    }
+
+    unsafe { write_sstatus(read_sstatus() | SSTATUS_SUM); }
+    let result = f();
+    unsafe { write_sstatus(read_sstatus() & !SSTATUS_SUM); }
+    result
+}
+
 // Copy from kernel to user.
 // Copy len bytes from src to virtual address dstva in a given page table.
 // Return 0 on success, -1 on error.
 pub unsafe fn copy_out(pt: &ActivePageTable, mut dst_va: usize, mut src: usize, mut len: usize) -> bool {
+    if direct_access(pt, dst_va, len, || user_copy(dst_va, src, len) == 0) {
+        return true;
+    }
+
     while len > 0 {
         let va0 = page_round_down(dst_va);
-        let pa0 = pt.translate(va0);
+        let pa0 = pt.translate(va0).or_else(|| swap::swap_in_current(pt, va0));
         if pa0.is_none() {
             return false;
         }
@@ -106,9 +167,13 @@ pub unsafe fn copy_out(pt: &ActivePageTable, mut dst_va: usize, mut src: usize,
 // Copy len bytes to dst from virtual address srcva in a given page table.
 // Return 0 on success, -1 on error.
 pub fn copy_in(pt: &ActivePageTable, mut dst: usize, mut src_va: usize, mut len: usize) -> bool {
+    if direct_access(pt, src_va, len, || unsafe { user_copy(dst, src_va, len) } == 0) {
+        return true;
+    }
+
     while len > 0 {
         let va0 = page_round_down(src_va);
-        let pa0 = pt.translate(va0);
+        let pa0 = pt.translate(va0).or_else(|| swap::swap_in_current(pt, va0));
         if pa0.is_none() {
             return false;
         }
@@ -130,12 +195,49 @@ pub fn copy_in(pt: &ActivePageTable, mut dst: usize, mut src_va: usize, mut len:
     true
 }
 
+// Read a null-terminated string through direct_access(), a chunk at a time.
+fn copy_in_string_direct(pt: &ActivePageTable, va: usize) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    loop {
+        let start = va.checked_add(bytes.len())?;
+        let chunk = STRING_CHUNK.min(kernel_virtual_memory::user_window_end(start)? - start);
+        let old_len = bytes.len();
+        bytes.resize(old_len + chunk, 0);
+
+        let dst = bytes[old_len..].as_mut_ptr() as usize;
+        let mut copied = 0;
+        if !direct_access(pt, start, chunk, || {
+            copied = unsafe { user_copy_string(dst, start, chunk) };
+            copied >= 0
+        }) {
+            return None;
+        }
+        if copied > 0 {
+            bytes.truncate(old_len + copied as usize);
+            return Some(bytes);
+        }
+    }
+}
+
 // Copy a null-terminated string from user to kernel.
 // Copy bytes to dst from virtual address srcva in a given page table,
 // until a '\0', or max.
 // Return 0 on success, -1 on error.
-pub fn copy_in_string(pt: &ActivePageTable, mut va: usize) -> Option<String> {
-    let mut pa = match pt.translate(va) {
+pub fn copy_in_string(pt: &ActivePageTable, va: usize) -> Option<String> {
+    let mut bytes = match copy_in_string_direct(pt, va) {
+        Some(bytes) => { bytes }
+        None => { copy_in_string_walk(pt, va)? }
+    };
+
+    unsafe {
+        let result = CStr::from_ptr(bytes.as_mut_ptr() as *mut c_char).to_string_lossy().into_owned();
+
+        Some(result)
+    }
+}
+
+fn copy_in_string_walk(pt: &ActivePageTable, mut va: usize) -> Option<Vec<u8>> {
+    let mut pa = match pt.translate(va).or_else(|| swap::swap_in_current(pt, va)) {
         Some(x) => x,
         None => return None,
     };
@@ -149,16 +251,12 @@ pub fn copy_in_string(pt: &ActivePageTable, mut va: usize) -> Option<String> {
         va += 1;
         pa += 1;
         if va % PAGE_SIZE == 0 {
-            pa = match pt.translate(va) {
+            pa = match pt.translate(va).or_else(|| swap::swap_in_current(pt, va)) {
                 Some(x) => x,
                 None => return None
             }
         }
     }
 
-    unsafe {
-        let result = CStr::from_ptr(bytes.as_mut_ptr() as *mut c_char).to_string_lossy().into_owned();
-
-        Some(result)
-    }
-}
\ No newline at end of file
+    Some(bytes)
+}
@@ -3,7 +3,7 @@ use core::ops::{Index, IndexMut};
 
 use crate::memory::{Frame, PHYSICAL_MEMORY};
 
-const ENTRY_COUNT: usize = 512;
+pub const ENTRY_COUNT: usize = 512;
 
 bitflags! {
     pub struct PageEntryFlags: u64 {
@@ -15,12 +15,21 @@ bitflags! {
         const GLOBAL     = 1 << 5;
         const ACCESSED   = 1 << 6;
         const DIRTY      = 1 << 7;
+        // software bit (RSW): the page lives in a swap slot, the PPN field holds the slot
+        const SWAPPED    = 1 << 8;
+        // software bit (RSW): the frame belongs to a shared memory segment
+        const SHARED     = 1 << 9;
     }
 }
 
+#[derive(Clone, Copy, Eq, PartialEq)]
 pub struct PageEntry(u64);
 
 impl PageEntry {
+    pub const fn unused() -> PageEntry {
+        PageEntry(0)
+    }
+
     pub fn flags(&self) -> PageEntryFlags {
         PageEntryFlags::from_bits_truncate(self.0)
     }
@@ -41,6 +50,27 @@ impl PageEntry {
         self.0 = ((self.pointed_frame().unwrap().addr() >> 12) << 10) as u64 | flags.bits();
     }
 
+    pub fn swap_slot(&self) -> Option<usize> {
+        let flags = self.flags();
+        if flags.contains(PageEntryFlags::SWAPPED) && !flags.contains(PageEntryFlags::VALID) {
+            Some((self.0 >> 10) as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_swapped(&mut self, slot: usize, flags: PageEntryFlags) {
+        let flags = (flags | PageEntryFlags::SWAPPED) - PageEntryFlags::VALID;
+        self.0 = (slot << 10) as u64 | flags.bits();
+    }
+
+    // A valid entry with any of R/W/X set maps memory directly,
+    // at any level; otherwise a valid entry points to the next table.
+    pub fn is_leaf(&self) -> bool {
+        let flags = self.flags();
+        flags.contains(PageEntryFlags::VALID) && flags.intersects(PageEntryFlags::READABLE | PageEntryFlags::WRITEABLE | PageEntryFlags::EXECUTABLE)
+    }
+
     pub fn is_unused(&self) -> bool {
         self.0 == 0
     }
@@ -117,7 +147,7 @@ impl<L: HierarchicalLevel> PageTable<L> {
 
     fn next_table_address(&self, index: usize) -> Option<usize> {
         let flags = self[index].flags();
-        if flags.contains(PageEntryFlags::VALID) {
+        if flags.contains(PageEntryFlags::VALID) && !self[index].is_leaf() {
             self[index].pointed_frame().map(|it| it.addr())
         } else {
             None
@@ -125,6 +155,10 @@ impl<L: HierarchicalLevel> PageTable<L> {
     }
 
     pub fn next_table_or_create(&mut self, index: usize) -> Option<&mut PageTable<L::NextLevel>> {
+        // already covered by a large page.
+        if self[index].is_leaf() {
+            return None;
+        }
         if self.next_table(index).is_none() {
             let frame = PHYSICAL_MEMORY.alloc();
             if frame.is_some() {
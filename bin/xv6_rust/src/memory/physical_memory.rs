@@ -1,8 +1,13 @@
+use core::ptr;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
 
 use lazy_static::lazy_static;
+use param_lib::MAX_CPU_NUMBER;
 
-use crate::spin_lock::SpinLock;
+use crate::device_tree::machine;
+use crate::process::cpu_id;
+use crate::spin_lock::{SpinLock, SpinLockGuard};
 
 use super::*;
 use super::layout::*;
@@ -11,18 +16,209 @@ extern {
     fn end();
 }
 
+// Largest block handed out by the buddy allocator is 2^MAX_ORDER pages (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+// single pages a hart keeps for itself before giving a batch back to the buddy allocator.
+const CPU_LIST_HIGH: usize = 64;
+const CPU_LIST_BATCH: usize = 32;
+
 #[repr(C)]
-struct FreePage {
-    next: *mut FreePage,
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
 }
 
-#[repr(C)]
 struct FreeMemory {
-    head: *mut FreePage,
+    heads: [*mut FreeBlock; MAX_ORDER + 1],
+    // order + 1 of the free block starting at each frame,
+    // 0 if the frame does not start a free block.
+    // One byte per frame up to the end of RAM, carved out
+    // of the memory just after the kernel.
+    tags: *mut u8,
+    frame_count: usize,
+    free_blocks: [usize; MAX_ORDER + 1],
+    // blocks of each order allocated by alloc_order() and not freed yet.
+    allocations: [usize; MAX_ORDER + 1],
 }
 
 unsafe impl Send for FreeMemory {}
 
+// A plain static, so that PHYSICAL_MEMORY can refer to it for good.
+static FREE_MEMORY: SpinLock<FreeMemory> = SpinLock::new(FreeMemory::new(), "physical memory");
+
+impl FreeMemory {
+    const fn new() -> FreeMemory {
+        FreeMemory {
+            heads: [null_mut(); MAX_ORDER + 1],
+            tags: null_mut(),
+            frame_count: 0,
+            free_blocks: [0; MAX_ORDER + 1],
+            allocations: [0; MAX_ORDER + 1],
+        }
+    }
+
+    fn tag(&mut self, addr: usize) -> &mut u8 {
+        let index = frame_index(addr);
+        assert!(index < self.frame_count);
+        unsafe { &mut *self.tags.add(index) }
+    }
+
+    fn is_free(&mut self, addr: usize, order: usize) -> bool {
+        *self.tag(addr) == order as u8 + 1
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        unsafe {
+            (*block).prev = null_mut();
+            (*block).next = self.heads[order];
+            if !self.heads[order].is_null() {
+                (*self.heads[order]).prev = block;
+            }
+        }
+        self.heads[order] = block;
+        *self.tag(addr) = order as u8 + 1;
+        self.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        unsafe {
+            let prev = (*block).prev;
+            let next = (*block).next;
+            if prev.is_null() {
+                self.heads[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+        *self.tag(addr) = 0;
+        self.free_blocks[order] -= 1;
+    }
+
+    // Take a free block of 2^order pages,
+    // splitting a larger one if necessary.
+    fn take(&mut self, order: usize) -> Option<usize> {
+        let mut current = order;
+        while current <= MAX_ORDER && self.heads[current].is_null() {
+            current += 1;
+        }
+        if current > MAX_ORDER {
+            return None;
+        }
+
+        let addr = self.heads[current] as usize;
+        self.remove(addr, current);
+
+        // split, giving the upper halves back to the smaller orders.
+        while current > order {
+            current -= 1;
+            self.push(addr + (PAGE_SIZE << current), current);
+        }
+        Some(addr)
+    }
+
+    // Give back a block of 2^order pages, merging it with its buddy
+    // for as long as the buddy lies in [start, end) and is free as well.
+    fn give(&mut self, addr: usize, order: usize, start: usize, end: usize) {
+        assert!(*self.tag(addr) == 0);
+
+        let mut addr = addr;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = buddy_of(addr, order);
+            if buddy < start || buddy + (PAGE_SIZE << order) > end || !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            if buddy < addr {
+                addr = buddy;
+            }
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+}
+
+// A stack of single free pages owned by one hart.
+struct PageList {
+    head: *mut FreeBlock,
+    count: usize,
+}
+
+unsafe impl Send for PageList {}
+
+impl PageList {
+    const fn new() -> PageList {
+        PageList {
+            head: null_mut(),
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, addr: usize) {
+        let block = addr as *mut FreeBlock;
+        unsafe { (*block).next = self.head; }
+        self.head = block;
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.head.is_null() {
+            return None;
+        }
+        let block = self.head;
+        self.head = unsafe { (*block).next };
+        self.count -= 1;
+        Some(block as usize)
+    }
+}
+
+struct CpuPages {
+    list: SpinLock<PageList>,
+    // times a hart found this list locked by another hart.
+    contention: AtomicUsize,
+    // pages alloc() handed out on this hart less those free() took
+    // back on it; a page freed on another hart makes this negative.
+    allocated: AtomicIsize,
+}
+
+impl CpuPages {
+    const fn new() -> CpuPages {
+        CpuPages {
+            list: SpinLock::new(PageList::new(), "cpu pages"),
+            contention: AtomicUsize::new(0),
+            allocated: AtomicIsize::new(0),
+        }
+    }
+
+    fn lock(&self) -> SpinLockGuard<'_, PageList> {
+        match self.list.try_lock() {
+            Some(guard) => { guard }
+            None => {
+                self.contention.fetch_add(1, Ordering::Relaxed);
+                self.list.lock()
+            }
+        }
+    }
+}
+
+static CPU_PAGES: [CpuPages; MAX_CPU_NUMBER] = array![_ => CpuPages::new(); MAX_CPU_NUMBER];
+
+fn frame_index(addr: usize) -> usize {
+    (addr - KERNEL_BASE) / PAGE_SIZE
+}
+
+// KERNEL_BASE is aligned far beyond the largest block,
+// so buddies can be found by flipping one bit of the frame index.
+fn buddy_of(addr: usize, order: usize) -> usize {
+    KERNEL_BASE + (frame_index(addr) ^ (1 << order)) * PAGE_SIZE
+}
+
 pub struct Frame {
     number: usize,
 }
@@ -40,23 +236,39 @@ impl Frame {
 pub struct PhysicalMemory {
     start: usize,
     end: usize,
-    memory: SpinLock<FreeMemory>,
+    memory: &'static SpinLock<FreeMemory>,
+    cpus: &'static [CpuPages; MAX_CPU_NUMBER],
 }
 
 lazy_static! {
     pub static ref PHYSICAL_MEMORY: PhysicalMemory = {
         let phy_end = end as usize;
-        let start = page_round_up(phy_end);
-        let end = PHY_STOP;
+        let mut start = page_round_up(phy_end);
+        let end = machine().memory_end();
+
+        let frame_count = (end - KERNEL_BASE) / PAGE_SIZE;
+        let mut free = FREE_MEMORY.lock();
+        free.tags = start as *mut u8;
+        free.frame_count = frame_count;
+        unsafe { ptr::write_bytes(free.tags, 0, frame_count); }
+        drop(free);
+        start = page_round_up(start + frame_count);
         println!("physical memory: {:#x} - {:#x}", start, end);
 
         let memory = PhysicalMemory {
             start: start,
             end: end,
-            memory: SpinLock::new(FreeMemory { head: null_mut() },"physical memory"),
+            memory: &FREE_MEMORY,
+            cpus: &CPU_PAGES,
         };
 
-        memory.free_range(start, end);
+        // RAM ranges other than the kernel's may leave holes,
+        // whose frames are never tagged free.
+        for &(range_start, range_end) in machine().memory() {
+            if range_end > start {
+                memory.free_range(range_start.max(start), range_end);
+            }
+        }
 
         memory
     };
@@ -64,51 +276,174 @@ lazy_static! {
 
 impl PhysicalMemory {
     pub fn init(&self) {
-        assert!(PHYSICAL_MEMORY.start == page_round_up(end as usize) && PHYSICAL_MEMORY.end == PHY_STOP)
+        assert!(PHYSICAL_MEMORY.start > end as usize && PHYSICAL_MEMORY.end == machine().memory_end())
     }
 
+    // Give memory that was never allocated to the buddy allocator.
     pub fn free_range(&self, start: usize, end: usize) {
         let mut addr = page_round_up(start);
+        let mut free = self.memory.lock();
         while addr + PAGE_SIZE <= end {
-            self.free(addr);
+            free.give(addr, 0, self.start, self.end);
             addr += PAGE_SIZE;
         }
+        drop(free);
     }
 
+    // Single pages go through the list of the current hart,
+    // which only talks to the buddy allocator in batches.
     pub fn free(&self, addr: usize) {
         assert!(addr % PAGE_SIZE == 0 && addr >= self.start && addr < self.end);
 
         // unsafe { memset(addr, 1, PAGE_SIZE); }
 
-        unsafe {
-            let page = addr as *mut FreePage;
-            let mut lock = self.memory.lock();
-            let mut free = &mut *lock;
-
-            let next_page = free.head;
-            (*page).next = next_page;
-            free.head = page
+        let cpu = &self.cpus[cpu_id()];
+        cpu.allocated.fetch_sub(1, Ordering::Relaxed);
+        let mut list = cpu.lock();
+        list.push(addr);
+        if list.count > CPU_LIST_HIGH {
+            let mut free = self.memory.lock();
+            while list.count > CPU_LIST_HIGH - CPU_LIST_BATCH {
+                let addr = list.pop().unwrap();
+                free.give(addr, 0, self.start, self.end);
+            }
+            drop(free);
         }
+        drop(list);
+    }
+
+    // Free a block of 2^order pages.
+    pub fn free_order(&self, addr: usize, order: usize) {
+        let size = PAGE_SIZE << order;
+        assert!(order <= MAX_ORDER && addr % size == 0 && addr >= self.start && addr + size <= self.end);
+
+        // unsafe { memset(addr, 1, size); }
+
+        let mut free = self.memory.lock();
+        free.allocations[order] -= 1;
+        free.give(addr, order, self.start, self.end);
+        drop(free);
     }
 
     pub fn alloc(&self) -> Option<Frame> {
-        let mut lock = self.memory.lock();
-        let mut free = &mut *lock;
+        let cpu = &self.cpus[cpu_id()];
+        let mut list = cpu.lock();
+        if list.count == 0 {
+            let mut free = self.memory.lock();
+            while list.count < CPU_LIST_BATCH {
+                match free.take(0) {
+                    Some(addr) => { list.push(addr) }
+                    None => { break; }
+                }
+            }
+            drop(free);
+        }
+        let addr = list.pop();
+        drop(list);
 
-        let addr = free.head;
-        if addr.is_null() {
-            return None;
+        // unsafe { memset(addr, 5, PAGE_SIZE); }
+
+        let addr = addr.or_else(|| self.steal());
+        if addr.is_some() {
+            cpu.allocated.fetch_add(1, Ordering::Relaxed);
         }
+        addr.map(Frame::from_physical_address)
+    }
 
-        unsafe {
-            free.head = (*addr).next;
-            // memset(addr as usize, 5, PAGE_SIZE);
+    // Give the pages cached by every hart back to the buddy allocator.
+    fn drain(&self) {
+        for cpu in self.cpus.iter().take(machine().hart_count) {
+            let mut list = cpu.lock();
+            let mut free = self.memory.lock();
+            while let Some(addr) = list.pop() {
+                free.give(addr, 0, self.start, self.end);
+            }
+            drop(free);
+            drop(list);
         }
+    }
+
+    // Pop a page from the first other hart whose list has one,
+    // and move half of what is left there to this hart's list.
+    // Only used when the buddy allocator has nothing left.
+    fn steal(&self) -> Option<usize> {
+        let id = cpu_id();
+        let hart_count = machine().hart_count;
+        for i in 1..hart_count {
+            let mut victim = self.cpus[(id + i) % hart_count].lock();
+            let page = victim.pop();
+            if page.is_none() {
+                drop(victim);
+                continue;
+            }
+            let mut stolen = PageList::new();
+            while stolen.count < victim.count {
+                stolen.push(victim.pop().unwrap());
+            }
+            drop(victim);
 
-        return Some(Frame::from_physical_address(addr as usize));
+            // never hold two hart lists at once.
+            let mut list = self.cpus[id].lock();
+            while let Some(addr) = stolen.pop() {
+                list.push(addr);
+            }
+            drop(list);
+            return page;
+        }
+        None
+    }
+
+    // Allocate 2^order physically contiguous pages,
+    // aligned to their size.
+    pub fn alloc_order(&self, order: usize) -> Option<Frame> {
+        assert!(order <= MAX_ORDER);
+
+        let mut free = self.memory.lock();
+        let mut addr = free.take(order);
+        if addr.is_none() {
+            // the pages the harts keep may be the buddies
+            // missing for a block that large.
+            drop(free);
+            self.drain();
+            free = self.memory.lock();
+            addr = free.take(order);
+        }
+        if addr.is_some() {
+            free.allocations[order] += 1;
+        }
+        drop(free);
+
+        // unsafe { memset(addr, 5, PAGE_SIZE << order); }
+
+        addr.map(Frame::from_physical_address)
     }
 
     pub fn dealloc(&self, frame: Frame) {
         self.free(frame.addr());
     }
+
+    // Print free and allocated blocks per order, single pages from the
+    // hart lists included, then the pages cached by each hart and how
+    // often their lists were contended.
+    // Runs when user types ^P on console, together with the process listing.
+    pub fn print_statistics(&self) {
+        let pages: isize = self.cpus.iter().map(|it| it.allocated.load(Ordering::Relaxed)).sum();
+        let lock = self.memory.lock();
+        let mut free_pages = 0;
+        for order in 0..=MAX_ORDER {
+            let allocated = lock.allocations[order] as isize + if order == 0 { pages } else { 0 };
+            println!("order {:2}: {:6} free {:8} allocated", order, lock.free_blocks[order], allocated);
+            free_pages += lock.free_blocks[order] << order;
+        }
+        drop(lock);
+        for (id, cpu) in self.cpus.iter().enumerate() {
+            let list = cpu.lock();
+            if list.count > 0 || cpu.contention.load(Ordering::Relaxed) > 0 {
+                println!("cpu {}: {:4} cached {:8} contended", id, list.count, cpu.contention.load(Ordering::Relaxed));
+            }
+            free_pages += list.count;
+            drop(list);
+        }
+        println!("{} free pages", free_pages);
+    }
 }
@@ -0,0 +1,284 @@
+use alloc::vec::Vec;
+use core::ptr;
+
+use file_control_lib::{IPC_CREAT, IPC_EXCL, IPC_PRIVATE, SHM_RDONLY, SharedMemoryStatus};
+use param_lib::{MAX_SHM_ATTACH, MAX_SHM_NUMBER, MAX_SHM_SIZE};
+
+use crate::memory::{ActivePageTable, Frame, Page, page_round_up, PAGE_SIZE, PHYSICAL_MEMORY, swap};
+use crate::memory::layout::SHARED_MEMORY_BASE;
+use crate::memory::page_table::PageEntryFlags;
+use crate::process::process::ProcessData;
+use crate::spin_lock::SpinLock;
+
+// A set of physical frames owned by the kernel,
+// mapped into any number of process page tables.
+struct Segment {
+    used: bool,
+    key: usize,
+    size: usize,
+    frames: Vec<usize>,
+    attach_number: usize,
+    // IPC_RMID was called; the frames are freed once the last attach is gone.
+    removed: bool,
+}
+
+impl Segment {
+    const fn new() -> Segment {
+        Segment {
+            used: false,
+            key: IPC_PRIVATE,
+            size: 0,
+            frames: Vec::new(),
+            attach_number: 0,
+            removed: false,
+        }
+    }
+
+    // Once removed and detached everywhere, the segment gives up its frames.
+    fn reclaim(&mut self) -> Option<Vec<usize>> {
+        if self.removed && self.attach_number == 0 {
+            self.used = false;
+            Some(core::mem::replace(&mut self.frames, Vec::new()))
+        } else {
+            None
+        }
+    }
+}
+
+// Where a segment is mapped in a process.
+#[derive(Clone, Copy)]
+pub struct Attachment {
+    id: usize,
+    address: usize,
+    size: usize,
+}
+
+pub struct SharedMemory {
+    segments: SpinLock<[Segment; MAX_SHM_NUMBER]>,
+}
+
+pub static SHARED_MEMORY: SharedMemory = SharedMemory::new();
+
+fn free_frames(frames: Vec<usize>) {
+    for pa in frames {
+        PHYSICAL_MEMORY.free(pa);
+    }
+}
+
+// Map the frames at address, or map nothing.
+fn map_frames(page_table: &mut ActivePageTable, address: usize, frames: &Vec<usize>, perm: PageEntryFlags) -> bool {
+    for (i, pa) in frames.iter().enumerate() {
+        let page = Page::from_virtual_address(address + i * PAGE_SIZE);
+        if page_table.map(page, Frame::from_physical_address(*pa), perm | PageEntryFlags::SHARED).is_err() {
+            page_table.unmap_pages(address, i * PAGE_SIZE);
+            return false;
+        }
+    }
+    true
+}
+
+impl SharedMemory {
+    const fn new() -> SharedMemory {
+        SharedMemory {
+            segments: SpinLock::new(array![_ => Segment::new(); MAX_SHM_NUMBER], "shared memory"),
+        }
+    }
+
+    // Look up the segment of key, creating it if flags ask for that.
+    // Returns the segment id.
+    pub fn get(&self, key: usize, size: usize, flags: usize) -> Option<usize> {
+        if key != IPC_PRIVATE {
+            let guard = self.segments.lock();
+            let found = guard.iter().position(|it| it.used && it.key == key);
+            let existing = found.map(|id| guard[id].size);
+            drop(guard);
+
+            if let Some(existing) = existing {
+                if flags & IPC_CREAT != 0 && flags & IPC_EXCL != 0 || size > existing {
+                    return None;
+                }
+                return found;
+            }
+            if flags & IPC_CREAT == 0 {
+                return None;
+            }
+        }
+
+        if size == 0 || size > MAX_SHM_SIZE {
+            return None;
+        }
+
+        // allocating may swap pages out and sleep,
+        // so it is done without holding the lock.
+        let mut frames = Vec::new();
+        for _ in 0..(page_round_up(size) / PAGE_SIZE) {
+            match swap::alloc_frame() {
+                Some(frame) => {
+                    unsafe { ptr::write_bytes(frame.addr() as *mut u8, 0, PAGE_SIZE); }
+                    frames.push(frame.addr());
+                }
+                None => {
+                    free_frames(frames);
+                    return None;
+                }
+            }
+        }
+
+        let mut guard = self.segments.lock();
+        // someone may have created the key meanwhile.
+        if key != IPC_PRIVATE && guard.iter().any(|it| it.used && it.key == key) {
+            drop(guard);
+            free_frames(frames);
+            return self.get(key, size, flags);
+        }
+        let id = match guard.iter().position(|it| !it.used) {
+            Some(id) => { id }
+            None => {
+                drop(guard);
+                free_frames(frames);
+                return None;
+            }
+        };
+        let segment = &mut guard[id];
+        segment.used = true;
+        segment.key = key;
+        segment.size = size;
+        segment.frames = frames;
+        segment.attach_number = 0;
+        segment.removed = false;
+        drop(guard);
+
+        Some(id)
+    }
+
+    // Count a new attach, returning the frames to map.
+    fn hold(&self, id: usize) -> Option<Vec<usize>> {
+        let mut guard = self.segments.lock();
+        let frames = match guard.get_mut(id) {
+            Some(segment) if segment.used && !segment.removed => {
+                segment.attach_number += 1;
+                Some(segment.frames.clone())
+            }
+            _ => { None }
+        };
+        drop(guard);
+        frames
+    }
+
+    // Drop an attach, destroying the segment if it was the last one of a removed segment.
+    fn release(&self, id: usize) {
+        let mut guard = self.segments.lock();
+        let segment = &mut guard[id];
+        segment.attach_number -= 1;
+        let frames = segment.reclaim();
+        drop(guard);
+
+        if let Some(frames) = frames {
+            free_frames(frames);
+        }
+    }
+
+    // Map segment id into the process.
+    // Each attach slot of a process owns a fixed window above SHARED_MEMORY_BASE.
+    // Returns the user address of the segment.
+    pub fn attach(&self, data: &mut ProcessData, id: usize, flags: usize) -> Option<usize> {
+        let slot = data.shared_memory.iter().position(|it| it.is_none())?;
+        let frames = self.hold(id)?;
+
+        let address = SHARED_MEMORY_BASE + slot * MAX_SHM_SIZE;
+        let mut perm = PageEntryFlags::READABLE | PageEntryFlags::USER;
+        if flags & SHM_RDONLY == 0 {
+            perm |= PageEntryFlags::WRITEABLE;
+        }
+        if !map_frames(data.page_table.as_mut().unwrap(), address, &frames, perm) {
+            self.release(id);
+            return None;
+        }
+
+        data.shared_memory[slot] = Some(Attachment { id, address, size: frames.len() * PAGE_SIZE });
+        Some(address)
+    }
+
+    pub fn detach(&self, data: &mut ProcessData, address: usize) -> bool {
+        let slot = data.shared_memory.iter().position(|it| it.map_or(false, |it| it.address == address));
+        let slot = match slot {
+            Some(slot) => { slot }
+            None => { return false; }
+        };
+        let attachment = data.shared_memory[slot].take().unwrap();
+        data.page_table.as_mut().unwrap().unmap_pages(attachment.address, attachment.size);
+        self.release(attachment.id);
+        true
+    }
+
+    // Called when the process exits or replaces its image in exec().
+    pub fn detach_all(&self, data: &mut ProcessData) {
+        for slot in 0..MAX_SHM_ATTACH {
+            if let Some(attachment) = data.shared_memory[slot].take() {
+                if let Some(page_table) = data.page_table.as_mut() {
+                    page_table.unmap_pages(attachment.address, attachment.size);
+                }
+                self.release(attachment.id);
+            }
+        }
+    }
+
+    // Give a forked child the attaches of its parent, at the same addresses
+    // and with the same permissions.
+    pub fn copy_attachments(&self, parent: &ProcessData, child: &mut ProcessData) -> bool {
+        for slot in 0..MAX_SHM_ATTACH {
+            let attachment = match parent.shared_memory[slot] {
+                Some(attachment) => { attachment }
+                None => { continue; }
+            };
+            let frames = match self.hold(attachment.id) {
+                Some(frames) => { frames }
+                None => { return false; }
+            };
+            let flags = parent.page_table.as_ref().unwrap()
+                .read_flags(&Page::from_virtual_address(attachment.address)).unwrap();
+            let perm = flags & (PageEntryFlags::READABLE | PageEntryFlags::WRITEABLE | PageEntryFlags::USER);
+            if !map_frames(child.page_table.as_mut().unwrap(), attachment.address, &frames, perm) {
+                self.release(attachment.id);
+                return false;
+            }
+            child.shared_memory[slot] = Some(attachment);
+        }
+        true
+    }
+
+    // Mark the segment for destruction after its last detach.
+    // New lookups of its key no longer find it.
+    pub fn remove(&self, id: usize) -> bool {
+        let mut guard = self.segments.lock();
+        let segment = match guard.get_mut(id) {
+            Some(segment) if segment.used && !segment.removed => { segment }
+            _ => { return false; }
+        };
+        segment.removed = true;
+        segment.key = IPC_PRIVATE;
+        let frames = segment.reclaim();
+        drop(guard);
+
+        if let Some(frames) = frames {
+            free_frames(frames);
+        }
+        true
+    }
+
+    pub fn stat(&self, id: usize) -> Option<SharedMemoryStatus> {
+        let guard = self.segments.lock();
+        let status = match guard.get(id) {
+            Some(segment) if segment.used => {
+                Some(SharedMemoryStatus {
+                    key: segment.key,
+                    size: segment.size,
+                    attach_number: segment.attach_number,
+                })
+            }
+            _ => { None }
+        };
+        drop(guard);
+        status
+    }
+}
@@ -0,0 +1,303 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use file_system_lib::{BLOCK_SIZE, SuperBlock};
+use param_lib::MAX_PROCESS_NUMBER;
+
+use crate::driver::DISK;
+use crate::memory::{ActivePageTable, Frame, Page, page_round_down, PAGE_SIZE, PHYSICAL_MEMORY, PhysicalAddress};
+use crate::memory::page_table::PageEntryFlags;
+use crate::memory::user_virtual_memory;
+use crate::process::{CPU_MANAGER, PROCESS_MANAGER};
+use crate::process::process::Process;
+use crate::process::process::ProcessState::{RUNNABLE, SLEEPING};
+use crate::riscv::sfence_vma;
+use crate::spin_lock::SpinLock;
+use crate::trap::TICKS;
+
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+// clock ticks an allocation waits for an OOM victim to exit
+const OOM_WAIT_TICKS: usize = 10;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum SlotState {
+    FREE,
+    // the page is still being written out;
+    // dropped means its owner unmapped it meanwhile.
+    WRITING { dropped: bool },
+    USED,
+}
+
+struct SwapArea {
+    start: u32,
+    slots: Vec<SlotState>,
+    // clock hand: the process index and user address to inspect next.
+    hand_process: usize,
+    hand_va: usize,
+}
+
+pub struct Swap {
+    area: SpinLock<SwapArea>,
+}
+
+pub static SWAP: Swap = Swap::new();
+
+impl Swap {
+    const fn new() -> Swap {
+        Swap {
+            area: SpinLock::new(SwapArea {
+                start: 0,
+                slots: Vec::new(),
+                hand_process: 0,
+                hand_va: 0,
+            }, "swap"),
+        }
+    }
+
+    // The swap area lives on the root device, right behind the file system.
+    pub fn init(&self, sb: &SuperBlock) {
+        let mut guard = self.area.lock();
+        guard.start = sb.swap_start;
+        guard.slots = vec![SlotState::FREE; sb.swap_number as usize / BLOCKS_PER_PAGE];
+        println!("swap: {} slots at block {}", guard.slots.len(), guard.start);
+        drop(guard);
+    }
+
+    fn reserve_slot(&self) -> Option<usize> {
+        let mut guard = self.area.lock();
+        let slot = guard.slots.iter().position(|it| *it == SlotState::FREE);
+        if let Some(slot) = slot {
+            guard.slots[slot] = SlotState::WRITING { dropped: false };
+        }
+        drop(guard);
+        slot
+    }
+
+    // Release a slot whose page table entry is gone.
+    // Never sleeps, so it is safe to call with process locks held.
+    pub fn free_slot(&self, slot: usize) {
+        let mut guard = self.area.lock();
+        guard.slots[slot] = match guard.slots[slot] {
+            SlotState::WRITING { .. } => SlotState::WRITING { dropped: true },
+            SlotState::USED => SlotState::FREE,
+            SlotState::FREE => panic!("free_slot: slot {} is free", slot),
+        };
+        drop(guard);
+    }
+
+    fn finish_write(&self, slot: usize) {
+        let mut guard = self.area.lock();
+        guard.slots[slot] = match guard.slots[slot] {
+            SlotState::WRITING { dropped: true } => SlotState::FREE,
+            SlotState::WRITING { dropped: false } => SlotState::USED,
+            _ => panic!("finish_write"),
+        };
+        drop(guard);
+        PROCESS_MANAGER.wake_up(self.channel(slot));
+    }
+
+    fn channel(&self, slot: usize) -> usize {
+        &self.area as *const _ as usize + slot
+    }
+
+    // Sleep until the slot has reached the disk.
+    fn wait_written(&self, slot: usize) {
+        let mut guard = self.area.lock();
+        while let SlotState::WRITING { .. } = guard.slots[slot] {
+            CPU_MANAGER.my_cpu().sleep(self.channel(slot), guard);
+            guard = self.area.lock();
+        }
+        drop(guard);
+    }
+
+    fn first_block(&self, slot: usize) -> u32 {
+        let guard = self.area.lock();
+        let block_no = guard.start + (slot * BLOCKS_PER_PAGE) as u32;
+        drop(guard);
+        block_no
+    }
+
+    fn write_page(&self, slot: usize, pa: PhysicalAddress) {
+        let block_no = self.first_block(slot);
+        for i in 0..BLOCKS_PER_PAGE {
+            unsafe {
+                DISK.write(block_no + i as u32, (pa + i * BLOCK_SIZE) as *mut [u8; BLOCK_SIZE]);
+            }
+        }
+    }
+
+    // Copy the content of a slot into the page at pa.
+    pub fn read_slot(&self, slot: usize, pa: PhysicalAddress) {
+        self.wait_written(slot);
+        let block_no = self.first_block(slot);
+        for i in 0..BLOCKS_PER_PAGE {
+            unsafe {
+                DISK.read(block_no + i as u32, (pa + i * BLOCK_SIZE) as *mut [u8; BLOCK_SIZE]);
+            }
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        let guard = self.area.lock();
+        let enabled = !guard.slots.is_empty();
+        drop(guard);
+        enabled
+    }
+
+    // Write one user page out to the swap area and free its frame.
+    // Victims are chosen with the clock algorithm: a page whose
+    // accessed bit is set gets a second chance and the bit is cleared.
+    // Only processes that are not running on another hart are considered,
+    // since their TLB entries are flushed when they next return to user space.
+    // Returns false if nothing could be evicted.
+    pub fn evict(&self) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+
+        let guard = self.area.lock();
+        let (mut index, mut start_va) = (guard.hand_process, guard.hand_va);
+        drop(guard);
+
+        let current = CPU_MANAGER.my_proc() as *const Process;
+
+        // two sweeps are enough to clear every accessed bit once.
+        for _ in 0..(2 * MAX_PROCESS_NUMBER + 1) {
+            let process = PROCESS_MANAGER.process(index);
+            let guard = process.lock.lock();
+            let state = process.info().state;
+            let data = process.data();
+
+            if (state == RUNNABLE || state == SLEEPING || process as *const _ == current) && data.page_table.is_some() {
+                let ranges = data.user_ranges();
+                let page_table = data.page_table.as_mut().unwrap();
+                for &(start, end) in ranges.iter() {
+                    let mut va = start.max(start_va);
+                    while va < end {
+                        let page = Page::from_virtual_address(va);
+                        if let Some(entry) = page_table.entry_mut(&page) {
+                            let flags = entry.flags();
+                            if flags.contains(PageEntryFlags::VALID | PageEntryFlags::USER) {
+                                if flags.contains(PageEntryFlags::ACCESSED) {
+                                    entry.set_flags(flags - PageEntryFlags::ACCESSED);
+                                } else {
+                                    let slot = match self.reserve_slot() {
+                                        Some(slot) => { slot }
+                                        None => {
+                                            drop(guard);
+                                            return false;
+                                        }
+                                    };
+                                    let frame = entry.pointed_frame().unwrap();
+                                    entry.set_swapped(slot, flags - PageEntryFlags::DIRTY);
+                                    unsafe {
+                                        sfence_vma();
+                                    }
+                                    drop(guard);
+
+                                    self.write_page(slot, frame.addr());
+                                    self.finish_write(slot);
+                                    PHYSICAL_MEMORY.dealloc(frame);
+
+                                    let mut hand = self.area.lock();
+                                    hand.hand_process = index;
+                                    hand.hand_va = va + PAGE_SIZE;
+                                    drop(hand);
+                                    return true;
+                                }
+                            }
+                        }
+                        va += PAGE_SIZE;
+                    }
+                }
+            }
+            drop(guard);
+
+            index = (index + 1) % MAX_PROCESS_NUMBER;
+            start_va = 0;
+        }
+
+        false
+    }
+
+    // Bring the page containing va back from its swap slot.
+    // Returns false if the page is not swapped out or memory is exhausted.
+    pub fn swap_in(&self, page_table: &mut ActivePageTable, va: usize) -> bool {
+        let page = Page::from_virtual_address(page_round_down(va));
+        let (slot, flags) = match page_table.entry(&page) {
+            Some(entry) if entry.swap_slot().is_some() => { (entry.swap_slot().unwrap(), entry.flags()) }
+            _ => { return false; }
+        };
+
+        let frame = match alloc_frame() {
+            Some(frame) => { frame }
+            None => { return false; }
+        };
+        self.read_slot(slot, frame.addr());
+
+        page_table.entry_mut(&page).unwrap().set(frame, (flags - PageEntryFlags::SWAPPED) | PageEntryFlags::VALID);
+        self.free_slot(slot);
+        true
+    }
+}
+
+// Allocate a frame for user memory, swapping out
+// another user page if physical memory runs dry.
+// Once swap is full too, a process is killed and the allocation
+// is retried each tick while the victim exits, for a while only:
+// the victim may be waiting for something the caller holds.
+// Swapping sleeps, so it is skipped while spinlocks are held.
+pub fn alloc_frame() -> Option<Frame> {
+    let mut waited = 0;
+    loop {
+        if let Some(frame) = PHYSICAL_MEMORY.alloc() {
+            return Some(frame);
+        }
+        if CPU_MANAGER.my_cpu().holding_locks() {
+            return None;
+        }
+        if SWAP.evict() {
+            continue;
+        }
+        if waited == OOM_WAIT_TICKS || !PROCESS_MANAGER.kill_for_memory() {
+            return None;
+        }
+        let ticks = TICKS.lock();
+        CPU_MANAGER.my_cpu().sleep(&TICKS as *const _ as usize, ticks);
+        waited += 1;
+    }
+}
+
+// Called by usertrap() on a page fault.
+// The fault either grows the stack or brings a swapped-out page back.
+pub fn handle_page_fault(va: usize) -> bool {
+    let data = CPU_MANAGER.my_proc().data();
+    if user_virtual_memory::grow_stack(data, va) {
+        return true;
+    }
+    if !data.is_user_address(va) {
+        return false;
+    }
+    SWAP.swap_in(data.page_table.as_mut().unwrap(), va)
+}
+
+// Used by copy_in()/copy_out() when a user address of the current
+// process does not translate because it is swapped out,
+// or lies below the stack, which then grows.
+// Both may sleep, so this fails while any spinlock is held;
+// copies to or from user memory are done after dropping them.
+pub fn swap_in_current(page_table: &ActivePageTable, va: usize) -> Option<PhysicalAddress> {
+    if CPU_MANAGER.my_cpu().holding_locks() {
+        return None;
+    }
+    let data = CPU_MANAGER.my_proc().data();
+    if data.page_table.as_ref()?.addr() != page_table.addr() {
+        return None;
+    }
+    if !user_virtual_memory::grow_stack(data, va) {
+        if !data.is_user_address(va) || !SWAP.swap_in(data.page_table.as_mut().unwrap(), va) {
+            return None;
+        }
+    }
+    data.page_table.as_ref().unwrap().translate(va)
+}
@@ -1,8 +1,11 @@
 use core::ptr;
 
-use crate::memory::{ActivePageTable, Page, page_round_up, PAGE_SIZE, PHYSICAL_MEMORY};
-use crate::memory::layout::{TRAMPOLINE, TRAPFRAME};
+use crate::memory::{ActivePageTable, Page, page_round_down, page_round_up, PAGE_SIZE, PHYSICAL_MEMORY};
+use crate::memory::layout::{STACK_GUARD_GAP, TRAMPOLINE, TRAPFRAME, USER_STACK_BASE};
 use crate::memory::page_table::PageEntryFlags;
+use crate::memory::swap;
+use crate::memory::swap::SWAP;
+use crate::process::process::ProcessData;
 use crate::process::trap_frame::TrapFrame;
 
 extern {
@@ -20,14 +23,23 @@ static INIT_CODE: [u8; 52] = [
 ];
 
 
-pub unsafe fn init(page_table: &mut ActivePageTable) {
-    let frame = PHYSICAL_MEMORY.alloc().unwrap();
+// Returns false if memory ran out.
+pub unsafe fn init(page_table: &mut ActivePageTable) -> bool {
+    let frame = match PHYSICAL_MEMORY.alloc() {
+        Some(frame) => { frame }
+        None => { return false; }
+    };
     ptr::write_bytes(frame.addr() as *mut u8, 0, PAGE_SIZE);
     ptr::copy(&INIT_CODE as *const [u8; 52], frame.addr() as *mut [u8; 52], 1);
 
     let wrxu: PageEntryFlags = PageEntryFlags::WRITEABLE | PageEntryFlags::READABLE | PageEntryFlags::EXECUTABLE | PageEntryFlags::USER;
-    let result = page_table.map(Page::from_virtual_address(0), frame, wrxu);
-    assert!(result.is_ok());
+    match page_table.map(Page::from_virtual_address(0), frame, wrxu) {
+        Ok(_) => { true }
+        Err(frame) => {
+            PHYSICAL_MEMORY.dealloc(frame);
+            false
+        }
+    }
 }
 
 pub fn alloc_page_table(trapframe: *mut TrapFrame) -> Option<ActivePageTable> {
@@ -62,10 +74,14 @@ pub fn alloc_page_table(trapframe: *mut TrapFrame) -> Option<ActivePageTable> {
     Some(page_table)
 }
 
+// Pages below size may be left unmapped by exec's layout randomization.
 pub fn free_page_table(mut page_table: ActivePageTable, size: usize) {
     assert_eq!(size % PAGE_SIZE, 0);
     for v_addr in (0..size).step_by(PAGE_SIZE) {
-        page_table.unmap(Page::from_virtual_address(v_addr));
+        let page = Page::from_virtual_address(v_addr);
+        if page_table.read_flags(&page).is_some() {
+            page_table.unmap(page);
+        }
     }
     page_table.free();
 }
@@ -79,7 +95,7 @@ pub fn alloc_user_virtual_memory(page_table: &mut ActivePageTable, mut old_size:
 
     old_size = page_round_up(old_size);
     for addr in (old_size..new_size).step_by(PAGE_SIZE) {
-        let frame = PHYSICAL_MEMORY.alloc();
+        let frame = swap::alloc_frame();
         if frame.is_none() {
             dealloc_user_virtual_memory(page_table, addr, old_size);
             return None;
@@ -110,45 +126,51 @@ pub fn dealloc_user_virtual_memory(page_table: &mut ActivePageTable, old_size: u
     }
 
     for addr in (page_round_up(new_size)..page_round_up(old_size)).step_by(PAGE_SIZE) {
-        page_table.unmap(Page::from_virtual_address(addr));
+        let page = Page::from_virtual_address(addr);
+        if page_table.read_flags(&page).is_some() {
+            page_table.unmap(page);
+        }
     }
 
     return new_size;
 }
 
 // Given a parent process's page table, copy
-// its memory into a child's page table.
+// its memory in [start, end) into a child's page table.
 // Copies both the page table and the
 // physical memory.
 // returns 0 on success, -1 on failure.
 // frees any allocated pages on failure.
-pub fn copy_page_table(old: &ActivePageTable, new: &mut ActivePageTable, size: usize) -> bool {
-    for va in (0..size).step_by(PAGE_SIZE) {
-        let pa = old.translate(va);
-        assert!(pa.is_some());
-        let pa = pa.unwrap();
-        let flags = old.read_flags(&Page::from_virtual_address(va)).unwrap();
-
-        let frame = match PHYSICAL_MEMORY.alloc() {
+pub fn copy_page_table(old: &ActivePageTable, new: &mut ActivePageTable, start: usize, end: usize) -> bool {
+    for va in (start..end).step_by(PAGE_SIZE) {
+        let page = Page::from_virtual_address(va);
+        let flags = match old.read_flags(&page) {
+            Some(flags) => { flags }
+            None => { continue; } // a hole left by layout randomization
+        };
+
+        let frame = match swap::alloc_frame() {
             None => {
-                for j in (0..va).step_by(PAGE_SIZE) {
-                    new.unmap(Page::from_virtual_address(j));
-                }
+                dealloc_user_virtual_memory(new, va, start);
                 return false;
             }
             Some(it) => { it }
         };
 
-        unsafe {
-            ptr::copy(pa as *const u8, frame.addr() as *mut u8, PAGE_SIZE);
+        // a swapped-out page is read straight from its slot into the child.
+        match old.translate(va) {
+            Some(pa) => unsafe {
+                ptr::copy(pa as *const u8, frame.addr() as *mut u8, PAGE_SIZE);
+            }
+            None => {
+                SWAP.read_slot(old.entry(&page).unwrap().swap_slot().expect("copy_page_table"), frame.addr());
+            }
         }
 
-        match new.map(Page::from_virtual_address(va), frame, flags) {
+        match new.map(page, frame, flags - PageEntryFlags::SWAPPED) {
             Err(frame) => {
                 PHYSICAL_MEMORY.dealloc(frame);
-                for j in (0..va).step_by(PAGE_SIZE) {
-                    new.unmap(Page::from_virtual_address(j));
-                }
+                dealloc_user_virtual_memory(new, va, start);
                 return false;
             }
             Ok(_) => {}
@@ -158,11 +180,30 @@ pub fn copy_page_table(old: &ActivePageTable, new: &mut ActivePageTable, size: u
     return true;
 }
 
-// mark a PTE invalid for user access.
-// used by exec for the user stack guard page.
-pub fn make_guard_page(page_table: &mut ActivePageTable, va: usize) {
-    let page = Page::from_virtual_address(va);
-    let mut flags = page_table.read_flags(&page).unwrap();
-    flags &= !PageEntryFlags::USER;
-    page_table.write_flags(&page, flags);
-}
\ No newline at end of file
+// Count the user pages in ranges that are in memory, not swapped out.
+pub fn resident_pages(page_table: &ActivePageTable, ranges: &[(usize, usize)]) -> usize {
+    ranges.iter()
+        .map(|&(start, end)| (start..end).step_by(PAGE_SIZE).filter(|va| page_table.translate(*va).is_some()).count())
+        .sum()
+}
+
+// Grow the stack of the process down to the page holding va,
+// if that stays within its stack limit, the stack window
+// and clear of the heap. The window's top moves with exec's
+// random offset but its base doesn't, so the limit alone
+// could reach below USER_STACK_BASE.
+pub fn grow_stack(data: &mut ProcessData, va: usize) -> bool {
+    let bottom = page_round_down(va);
+    if va >= data.stack_bottom || va < data.stack_top.saturating_sub(data.stack_limit.current)
+        || bottom < USER_STACK_BASE || bottom < data.size + STACK_GUARD_GAP {
+        return false;
+    }
+
+    match alloc_user_virtual_memory(data.page_table.as_mut().unwrap(), bottom, data.stack_bottom) {
+        Some(_) => {
+            data.stack_bottom = bottom;
+            true
+        }
+        None => { false }
+    }
+}
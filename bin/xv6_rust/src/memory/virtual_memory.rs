@@ -1,8 +1,9 @@
 use core::intrinsics::size_of;
 
-use crate::memory::{Frame, page_round_down, PAGE_SIZE, PHYSICAL_MEMORY, PhysicalAddress, VirtualAddress};
+use crate::memory::{Frame, GIGA_PAGE_SIZE, MEGA_PAGE_SIZE, page_round_down, PAGE_SIZE, PHYSICAL_MEMORY, PhysicalAddress, VirtualAddress};
 use crate::memory::layout::MAX_VA;
-use crate::memory::page_table::{Level1, Level2, Level3, PageEntry, PageEntryFlags, PageTable};
+use crate::memory::page_table::{ENTRY_COUNT, Level1, Level2, Level3, PageEntry, PageEntryFlags, PageTable};
+use crate::memory::swap::SWAP;
 
 pub struct Page {
     number: usize,
@@ -62,15 +63,40 @@ impl ActivePageTable {
         self.p3 as usize
     }
 
-    pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
-        let offset = virtual_address % PAGE_SIZE;
-        self.translate_page(&Page::from_virtual_address(virtual_address)).map(|it| it + offset)
+    // Find the entry that maps va: a leaf at any level, or else the
+    // entry of the last-level table, which may be invalid.
+    // Also returns the size of the memory the entry covers.
+    fn walk(&self, va: VirtualAddress) -> Option<(&PageEntry, usize)> {
+        let page = Page::from_virtual_address(va);
+        let p3 = self.p3();
+        if p3[page.l3_index()].is_leaf() {
+            return Some((&p3[page.l3_index()], GIGA_PAGE_SIZE));
+        }
+        let p2 = p3.next_table(page.l3_index())?;
+        if p2[page.l2_index()].is_leaf() {
+            return Some((&p2[page.l2_index()], MEGA_PAGE_SIZE));
+        }
+        let p1 = p2.next_table(page.l2_index())?;
+        Some((&p1[page.l1_index()], PAGE_SIZE))
+    }
+
+    fn walk_mut(&mut self, va: VirtualAddress) -> Option<(&mut PageEntry, usize)> {
+        let page = Page::from_virtual_address(va);
+        let p3 = self.p3_mut();
+        if p3[page.l3_index()].is_leaf() {
+            return Some((&mut p3[page.l3_index()], GIGA_PAGE_SIZE));
+        }
+        let p2 = p3.next_table_mut(page.l3_index())?;
+        if p2[page.l2_index()].is_leaf() {
+            return Some((&mut p2[page.l2_index()], MEGA_PAGE_SIZE));
+        }
+        let p1 = p2.next_table_mut(page.l2_index())?;
+        Some((&mut p1[page.l1_index()], PAGE_SIZE))
     }
 
-    fn translate_page(&self, page: &Page) -> Option<PhysicalAddress> {
-        let p2 = self.p3().next_table(page.l3_index());
-        let p1 = p2.and_then(|it| it.next_table(page.l2_index()));
-        return p1.and_then(|it| it[page.l1_index()].pointed_frame().map(|it| it.addr()));
+    pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+        let (entry, size) = self.walk(virtual_address)?;
+        entry.pointed_frame().map(|it| it.addr() + virtual_address % size)
     }
 
     pub fn map(&mut self, page: Page, frame: Frame, flags: PageEntryFlags) -> Result<(), Frame> {
@@ -87,35 +113,74 @@ impl ActivePageTable {
         }
     }
 
+    // Map a 2 MiB leaf into a Level2 table or a 1 GiB leaf into the Level3 table.
+    fn map_large(&mut self, page: Page, frame: Frame, size: usize, flags: PageEntryFlags) -> bool {
+        assert!(page.addr() % size == 0 && frame.addr() % size == 0);
+
+        let entry = if size == GIGA_PAGE_SIZE {
+            &mut self.p3_mut()[page.l3_index()]
+        } else {
+            match self.p3_mut().next_table_or_create(page.l3_index()) {
+                None => { return false; }
+                Some(p2) => { &mut p2[page.l2_index()] }
+            }
+        };
+        assert!(entry.is_unused());
+        entry.set(frame, flags | PageEntryFlags::VALID);
+        true
+    }
+
+    // Unmap the page and free the memory behind it.
+    // A large page must have come from PHYSICAL_MEMORY.alloc_order().
     pub fn unmap(&mut self, page: Page) {
-        assert!(self.translate(page.addr()).is_some());
+        let (entry, size) = self.walk_mut(page.addr()).expect("unmap");
+
+        // a swapped-out page only owns its swap slot
+        if let Some(slot) = entry.swap_slot() {
+            entry.set_unused();
+            SWAP.free_slot(slot);
+            return;
+        }
 
-        let p1 = self.p3_mut().next_table_mut(page.l3_index())
-            .and_then(|p2| p2.next_table_mut(page.l2_index()))
-            .expect("unmap");
+        // the segment owns the frame, not the page table
+        if entry.flags().contains(PageEntryFlags::SHARED) {
+            entry.set_unused();
+            return;
+        }
 
-        let frame = p1[page.l1_index()].pointed_frame().unwrap();
-        p1[page.l1_index()].set_unused();
-        PHYSICAL_MEMORY.dealloc(frame);
+        assert!(page.addr() % size == 0);
+        let frame = entry.pointed_frame().expect("unmap");
+        entry.set_unused();
+        if size == PAGE_SIZE {
+            PHYSICAL_MEMORY.dealloc(frame);
+        } else {
+            PHYSICAL_MEMORY.free_order(frame.addr(), (size / PAGE_SIZE).trailing_zeros() as usize);
+        }
     }
 
+    // Map [virtual_memory, virtual_memory + size) to physical_memory.
+    // Uses 1 GiB and 2 MiB leaves wherever both addresses are aligned
+    // and the rest of the range covers the whole large page.
     pub fn map_pages(&mut self, virtual_memory: usize, physical_memory: usize, size: usize, perm: PageEntryFlags) -> bool {
         let mut v_addr = page_round_down(virtual_memory);
         let mut p_addr = physical_memory;
         let v_last = page_round_down(virtual_memory + size - 1) + PAGE_SIZE;
 
         while v_addr < v_last {
-            let result = self.map(
-                Page::from_virtual_address(v_addr),
-                Frame::from_physical_address(p_addr),
-                perm,
-            );
-            if result.is_err() {
+            let step = large_page_size(v_addr, p_addr, v_last - v_addr);
+            let page = Page::from_virtual_address(v_addr);
+            let frame = Frame::from_physical_address(p_addr);
+            let result = if step == PAGE_SIZE {
+                self.map(page, frame, perm).is_ok()
+            } else {
+                self.map_large(page, frame, step, perm)
+            };
+            if !result {
                 return false;
             }
 
-            v_addr += PAGE_SIZE;
-            p_addr += PAGE_SIZE;
+            v_addr += step;
+            p_addr += step;
         }
 
         true
@@ -125,40 +190,160 @@ impl ActivePageTable {
         assert_eq!(virtual_memory % PAGE_SIZE, 0);
         assert_eq!(size % PAGE_SIZE, 0);
 
-        for v_addr in (virtual_memory..(virtual_memory + size)).step_by(PAGE_SIZE) {
-            self.unmap_no_free(Page::from_virtual_address(v_addr));
+        let mut v_addr = virtual_memory;
+        while v_addr < virtual_memory + size {
+            v_addr += self.unmap_no_free(Page::from_virtual_address(v_addr));
         }
     }
 
-    fn unmap_no_free(&mut self, page: Page) {
+    // Returns the size of the memory that was unmapped.
+    fn unmap_no_free(&mut self, page: Page) -> usize {
         assert!(self.translate(page.addr()).is_some());
 
-        let p1 = self.p3_mut().next_table_mut(page.l3_index())
-            .and_then(|p2| p2.next_table_mut(page.l2_index()))
-            .expect("unmap");
-
-        p1[page.l1_index()].set_unused();
+        let (entry, size) = self.walk_mut(page.addr()).expect("unmap");
+        assert!(page.addr() % size == 0);
+        entry.set_unused();
+        size
     }
 
     pub fn read_flags(&self, page: &Page) -> Option<PageEntryFlags> {
-        let p2 = self.p3().next_table(page.l3_index());
-        let p1 = p2.and_then(|it| it.next_table(page.l2_index()));
-        return p1.and_then(|it| if it[page.l1_index()].is_unused() {
+        self.walk(page.addr()).and_then(|(entry, _)| if entry.is_unused() {
             None
         } else {
-            Some(it[page.l1_index()].flags())
-        });
+            Some(entry.flags())
+        })
     }
 
-    pub fn write_flags(&mut self, page: &Page, flags: PageEntryFlags) {
-        assert!(self.translate_page(page).is_some());
+    pub fn entry(&self, page: &Page) -> Option<&PageEntry> {
+        self.walk(page.addr()).map(|(entry, _)| entry)
+    }
 
-        let p1 = self.p3_mut().next_table_mut(page.l3_index())
-            .and_then(|p2| p2.next_table_mut(page.l2_index()))
-            .expect("unmap");
+    pub fn entry_mut(&mut self, page: &Page) -> Option<&mut PageEntry> {
+        self.walk_mut(page.addr()).map(|(entry, _)| entry)
+    }
 
-        p1[page.l1_index()].set_flags(flags);
+    // Print every valid entry, one line each, indented by level:
+    // the virtual address it starts at, the frame it points to,
+    // and for leaves the flags. Swapped-out pages show their slot.
+    pub fn print(&self) {
+        println!("page table {:#x}", self.addr());
+        let p3 = self.p3();
+        for i3 in 0..ENTRY_COUNT {
+            let va3 = i3 * GIGA_PAGE_SIZE;
+            print_entry(1, va3, &p3[i3]);
+            let p2 = match p3.next_table(i3) {
+                Some(p2) => { p2 }
+                None => { continue; }
+            };
+            for i2 in 0..ENTRY_COUNT {
+                let va2 = va3 + i2 * MEGA_PAGE_SIZE;
+                print_entry(2, va2, &p2[i2]);
+                let p1 = match p2.next_table(i2) {
+                    Some(p1) => { p1 }
+                    None => { continue; }
+                };
+                for i1 in 0..ENTRY_COUNT {
+                    print_entry(3, va2 + i1 * PAGE_SIZE, &p1[i1]);
+                }
+            }
+        }
+    }
+
+    // A kernel page table for one process.
+    // It shares the root entries of kernel, except that the entry of each
+    // window gets a private Level2 table: kernel's mappings outside the
+    // window, and room inside it for share_user_range().
+    // Windows are 2 MiB aligned and each lies in a 1 GiB region of its own.
+    pub fn new_process_kernel(kernel: &ActivePageTable, windows: &[(VirtualAddress, VirtualAddress)]) -> Option<ActivePageTable> {
+        let mut page_table = ActivePageTable::new()?;
+        for index in 0..ENTRY_COUNT {
+            if windows.iter().all(|&(start, _)| Page::from_virtual_address(start).l3_index() != index) {
+                page_table.p3_mut()[index] = kernel.p3()[index];
+            }
+        }
+
+        for &(start, end) in windows.iter() {
+            let index = Page::from_virtual_address(start).l3_index();
+            assert!(!kernel.p3()[index].is_leaf());
+            let p2 = match page_table.p3_mut().next_table_or_create(index) {
+                Some(p2) => { p2 }
+                None => {
+                    page_table.free_process_kernel(windows);
+                    return None;
+                }
+            };
+            if let Some(kernel_p2) = kernel.p3().next_table(index) {
+                for i2 in 0..ENTRY_COUNT {
+                    p2[i2] = kernel_p2[i2];
+                }
+            }
+            for i2 in Page::from_virtual_address(start).l2_index()..=Page::from_virtual_address(end - 1).l2_index() {
+                p2[i2].set_unused();
+            }
+        }
+        Some(page_table)
+    }
+
+    // Free a table made by new_process_kernel() with the same windows.
+    // The tables below its private Level2 tables belong to the kernel or the user.
+    pub fn free_process_kernel(mut self, windows: &[(VirtualAddress, VirtualAddress)]) {
+        for &(start, _) in windows.iter() {
+            if let Some(p2) = self.p3_mut().next_table_mut(Page::from_virtual_address(start).l3_index()) {
+                PHYSICAL_MEMORY.free(p2 as *mut PageTable<Level2> as usize);
+            }
+        }
+        self.free();
+    }
+
+    // Point the Level2 entries covering [start, end), which must lie in a
+    // window of new_process_kernel(), at the Level1 tables of user,
+    // so its pages there become reachable through this table.
+    // The Level1 tables are shared, so later changes to them show up
+    // here too; only a newly created one needs another call.
+    // Returns true if an entry changed and the TLB must be flushed.
+    pub fn share_user_range(&mut self, user: &ActivePageTable, start: VirtualAddress, end: VirtualAddress) -> bool {
+        assert!(start < end);
+
+        let mut changed = false;
+        for mega in (start / MEGA_PAGE_SIZE)..=((end - 1) / MEGA_PAGE_SIZE) {
+            let page = Page::from_virtual_address(mega * MEGA_PAGE_SIZE);
+            let entry = match user.p3().next_table(page.l3_index()) {
+                Some(user_p2) if !user_p2[page.l2_index()].is_leaf() => { user_p2[page.l2_index()] }
+                _ => { PageEntry::unused() }
+            };
+            let p2 = self.p3_mut().next_table_mut(page.l3_index()).expect("share_user_range");
+            if p2[page.l2_index()] != entry {
+                p2[page.l2_index()] = entry;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn print_entry(depth: usize, va: VirtualAddress, entry: &PageEntry) {
+    if entry.is_unused() {
+        return;
+    }
+    for _ in 0..depth {
+        print!(" ..");
+    }
+    if let Some(slot) = entry.swap_slot() {
+        println!("{:#011x}: swapped slot {}", va, slot);
+    } else if entry.is_leaf() {
+        println!("{:#011x}: pa {:#x} {:?}", va, entry.pointed_frame().unwrap().addr(), entry.flags());
+    } else {
+        println!("{:#011x}: pa {:#x}", va, entry.pointed_frame().unwrap().addr());
+    }
+}
+
+fn large_page_size(virtual_address: usize, physical_address: usize, remain: usize) -> usize {
+    for size in [GIGA_PAGE_SIZE, MEGA_PAGE_SIZE].iter() {
+        if virtual_address % size == 0 && physical_address % size == 0 && remain >= *size {
+            return *size;
+        }
     }
+    PAGE_SIZE
 }
 
 pub fn virtual_memory_init() {
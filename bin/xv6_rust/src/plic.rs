@@ -1,21 +1,29 @@
-use crate::memory::layout::{PLIC, UART0_IRQ, VIRTIO0_IRQ};
+use crate::device_tree::machine;
 use crate::process::cpu_id;
 
 pub fn plic_init() {
+    let machine = machine();
     unsafe {
-        *((PLIC + UART0_IRQ * 4) as *mut u32) = 1;
-        *((PLIC + VIRTIO0_IRQ * 4) as *mut u32) = 1;
+        // set desired IRQ priorities non-zero (otherwise disabled).
+        *((machine.plic + machine.uart_irq * 4) as *mut u32) = 1;
+        *((machine.plic + machine.virtio_irq * 4) as *mut u32) = 1;
     }
 }
 
 pub fn plic_hart_init() {
+    let machine = machine();
     let hart = cpu_id();
 
-    let plic_senable = PLIC + 0x2080 + hart * 0x100;
-    let plic_spriority = PLIC + 0x201000 + hart * 0x2000;
+    let plic_senable = machine.plic + 0x2080 + hart * 0x100;
+    let plic_spriority = machine.plic + 0x201000 + hart * 0x2000;
 
     unsafe {
-        *(plic_senable as *mut u32) = 1 << UART0_IRQ | 1 << VIRTIO0_IRQ;
+        // set enable bits for this hart's S-mode
+        // for the uart and virtio disk.
+        for irq in [machine.uart_irq, machine.virtio_irq].iter() {
+            let word = (plic_senable + irq / 32 * 4) as *mut u32;
+            *word |= 1 << (irq % 32);
+        }
         *(plic_spriority as *mut u32) = 0;
     }
 }
@@ -23,7 +31,7 @@ pub fn plic_hart_init() {
 pub fn plic_claim() -> u32 {
     unsafe {
         let hart = cpu_id();
-        let irq = *((PLIC + 0x201004 + hart * 0x2000) as *mut u32);
+        let irq = *((machine().plic + 0x201004 + hart * 0x2000) as *mut u32);
         return irq;
     }
 }
@@ -31,6 +39,6 @@ pub fn plic_claim() -> u32 {
 pub fn plic_complete(irq: u32) {
     unsafe {
         let hart = cpu_id();
-        *((PLIC + 0x201004 + hart * 0x2000) as *mut u32) = irq;
+        *((machine().plic + 0x201004 + hart * 0x2000) as *mut u32) = irq;
     }
 }
\ No newline at end of file
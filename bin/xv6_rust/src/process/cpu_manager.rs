@@ -66,6 +66,11 @@ impl Cpu {
         }
     }
 
+    /// Is this CPU holding any spinlock? If so it must not sleep.
+    pub fn holding_locks(&self) -> bool {
+        self.off_depth > 0
+    }
+
     pub unsafe fn scheduled(&mut self) {
         extern {
             fn swtch(old: *mut Context, new: *mut Context);
@@ -3,11 +3,13 @@ use core::cell::UnsafeCell;
 use core::fmt;
 use core::ptr::{null, null_mut};
 
-use param_lib::MAX_OPEN_FILE_NUMBER;
+use param_lib::{MAX_OPEN_FILE_NUMBER, MAX_SHM_ATTACH, ResourceLimit, STACK_LIMIT};
 
 use crate::file_system::file::File;
 use crate::file_system::inode::INode;
 use crate::memory::ActivePageTable;
+use crate::memory::layout::{USER_STACK_BASE, USER_STACK_TOP};
+use crate::memory::shared_memory::Attachment;
 use crate::process::context::Context;
 use crate::process::trap_frame::TrapFrame;
 use crate::spin_lock::SpinLock;
@@ -17,11 +19,21 @@ pub struct ProcessData {
     pub kernel_stack: usize,
     pub size: usize,
     pub page_table: Option<ActivePageTable>,
+    // the kernel page table plus the user memory, used while in the kernel
+    pub kernel_page_table: Option<ActivePageTable>,
     pub trap_frame: *mut TrapFrame,
     pub context: Context,
     pub current_dir: Option<&'static INode>,
     pub name: String,
     pub open_file: [*const File; MAX_OPEN_FILE_NUMBER],
+    // randomize the layout on exec; inherited by children.
+    pub randomize: bool,
+    pub shared_memory: [Option<Attachment>; MAX_SHM_ATTACH],
+    // the stack maps [stack_bottom, stack_top) and grows down on
+    // page faults, as far as stack_limit allows.
+    pub stack_top: usize,
+    pub stack_bottom: usize,
+    pub stack_limit: ResourceLimit,
 }
 
 unsafe impl Send for ProcessData {}
@@ -32,17 +44,35 @@ impl ProcessData {
             kernel_stack: 0,
             size: 0,
             page_table: None,
+            kernel_page_table: None,
             trap_frame: null_mut(),
             context: Context::new(),
             current_dir: None,
             name: String::new(),
             open_file: [null(); MAX_OPEN_FILE_NUMBER],
+            randomize: true,
+            shared_memory: [None; MAX_SHM_ATTACH],
+            stack_top: USER_STACK_TOP,
+            stack_bottom: USER_STACK_TOP,
+            stack_limit: ResourceLimit {
+                current: STACK_LIMIT,
+                maximum: USER_STACK_TOP - USER_STACK_BASE,
+            },
         }
     }
+
+    // The user memory of the process: the image and heap, then the stack.
+    pub fn user_ranges(&self) -> [(usize, usize); 2] {
+        [(0, self.size), (self.stack_bottom, self.stack_top)]
+    }
+
+    pub fn is_user_address(&self, va: usize) -> bool {
+        self.user_ranges().iter().any(|&(start, end)| va >= start && va < end)
+    }
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
-pub enum ProcessState { UNUSED, SLEEPING, RUNNABLE, RUNNING, ZOMBIE }
+pub enum ProcessState { UNUSED, USED, SLEEPING, RUNNABLE, RUNNING, ZOMBIE }
 
 impl fmt::Display for ProcessState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -58,6 +88,12 @@ pub struct ProcessInfo {
     pub killed: bool,
     pub exit_state: i32,
     pub parent: Option<&'static Process>,
+    // the real user and group, and the effective ones
+    // permissions are checked against; inherited by children.
+    pub uid: u32,
+    pub euid: u32,
+    pub gid: u32,
+    pub egid: u32,
 }
 
 impl ProcessInfo {
@@ -69,6 +105,10 @@ impl ProcessInfo {
             killed: false,
             exit_state: 0,
             parent: None,
+            uid: 0,
+            euid: 0,
+            gid: 0,
+            egid: 0,
         }
     }
 }
@@ -6,16 +6,19 @@ use core::ptr;
 use param_lib::{MAX_OPEN_FILE_NUMBER, MAX_PROCESS_NUMBER, ROOT_DEV};
 
 use crate::file_system::{file_system_init, FILE_TABLE};
+use crate::file_system::logging::flusher;
+use crate::file_system::inode::ICACHE;
 use crate::file_system::path::find_inode;
-use crate::memory::{KERNEL_PAGETABLE, Page, PAGE_SIZE, PHYSICAL_MEMORY, user_virtual_memory};
-use crate::memory::layout::{KERNEL_STACK_PAGE_COUNT, TRAMPOLINE, TRAPFRAME};
+use crate::memory::{Frame, kernel_virtual_memory, KERNEL_PAGETABLE, make_satp, Page, PAGE_SIZE, PHYSICAL_MEMORY, user_virtual_memory};
+use crate::memory::layout::{KERNEL_STACK_PAGE_COUNT, SHARED_MEMORY_BASE, TRAMPOLINE, TRAPFRAME, USER_STACK_TOP};
+use crate::memory::shared_memory::SHARED_MEMORY;
 use crate::memory::page_table::PageEntryFlags;
 use crate::process::context::Context;
 use crate::process::CPU_MANAGER;
 use crate::process::process::Process;
-use crate::process::process::ProcessState::{RUNNABLE, RUNNING, SLEEPING, UNUSED, ZOMBIE};
+use crate::process::process::ProcessState::{RUNNABLE, RUNNING, SLEEPING, UNUSED, USED, ZOMBIE};
 use crate::process::trap_frame::TrapFrame;
-use crate::riscv::{intr_on, sfence_vma};
+use crate::riscv::{intr_on, sfence_vma, write_satp};
 use crate::spin_lock::{SpinLock, SpinLockGuard};
 use crate::trap::user_trap_return;
 
@@ -44,6 +47,10 @@ impl ProcessManager {
         unsafe { (*self.init_process.get()).as_ref() }.unwrap()
     }
 
+    pub fn process(&self, index: usize) -> &Process {
+        &self.processes[index]
+    }
+
     pub fn init(&self) {
         let mut pt_lock = KERNEL_PAGETABLE.lock();
         let page_table = &mut *pt_lock;
@@ -54,11 +61,34 @@ impl ProcessManager {
         for i in 0..MAX_PROCESS_NUMBER {
             let stack_top = top_guard - i * (KERNEL_STACK_PAGE_COUNT + 1) * PAGE_SIZE;
 
-            for j in 0..KERNEL_STACK_PAGE_COUNT {
-                let pa = PHYSICAL_MEMORY.alloc().unwrap();
-                let va = stack_top - (j + 1) * PAGE_SIZE;
-                let map_result = page_table.map(Page::from_virtual_address(va), pa, rw);
-                assert!(map_result.is_ok());
+            // one physically contiguous block per stack, straight from the
+            // buddy allocator: single pages now come from the hart lists,
+            // and a stack is the one multi-page block the kernel needs.
+            // a slot left without a stack is never handed out by alloc_process().
+            let order = KERNEL_STACK_PAGE_COUNT.trailing_zeros() as usize;
+            let stack = match PHYSICAL_MEMORY.alloc_order(order) {
+                Some(stack) => { stack }
+                None => {
+                    println!("process: out of memory after {} kernel stacks", i);
+                    break;
+                }
+            };
+            let mut mapped = 0;
+            while mapped < KERNEL_STACK_PAGE_COUNT {
+                let pa = Frame::from_physical_address(stack.addr() + (KERNEL_STACK_PAGE_COUNT - 1 - mapped) * PAGE_SIZE);
+                let va = stack_top - (mapped + 1) * PAGE_SIZE;
+                if page_table.map(Page::from_virtual_address(va), pa, rw).is_err() {
+                    break;
+                }
+                mapped += 1;
+            }
+            if mapped < KERNEL_STACK_PAGE_COUNT {
+                if mapped > 0 {
+                    page_table.unmap_pages(stack_top - mapped * PAGE_SIZE, mapped * PAGE_SIZE);
+                }
+                PHYSICAL_MEMORY.free_order(stack.addr(), order);
+                println!("process: out of memory after {} kernel stacks", i);
+                break;
             }
 
             let process = self.processes[i].data();
@@ -89,21 +119,22 @@ impl ProcessManager {
             // Avoid deadlock by ensuring that devices can interrupt.
             intr_on();
 
-            let mut process_count = 0;
+            let mut found = false;
             for process in self.processes.iter() {
                 let guard = process.lock.lock();
                 let info = process.info();
-                if info.state != UNUSED {
-                    process_count += 1;
-                }
                 if info.state == RUNNABLE {
+                    found = true;
                     // Switch to chosen process.  It is the process's job
                     // to release its lock and then reacquire it
                     // before jumping back to us.
                     info.state = RUNNING;
                     cpu.process = process as *const Process;
 
+                    write_satp(make_satp(process.data().kernel_page_table.as_ref().unwrap()));
+                    sfence_vma();
                     swtch(&mut cpu.context, &mut process.data().context);
+                    kernel_virtual_memory::switch_to_kernel_page_table();
 
                     // Process is done running for now.
                     // It should have changed its p->state before coming back.
@@ -111,7 +142,7 @@ impl ProcessManager {
                 }
                 drop(guard);
             }
-            if process_count <= 2 { // only init and sh exist
+            if !found { // nothing to run; wait for an interrupt
                 intr_on();
                 llvm_asm!("wfi"::::"volatile");
             }
@@ -155,15 +186,36 @@ impl ProcessManager {
         }
     }
 
+    // Print the user page table of process pid.
+    // Returns false if there is no such process.
+    pub fn print_page_table(&self, pid: usize) -> bool {
+        for process in self.processes.iter() {
+            let guard = process.lock.lock();
+            let info = process.info();
+            if info.state != UNUSED && info.pid == pid {
+                match process.data().page_table.as_ref() {
+                    Some(page_table) => { page_table.print(); }
+                    None => { println!("pid {} has no page table", pid); }
+                }
+                drop(guard);
+                return true;
+            }
+            drop(guard);
+        }
+        false
+    }
+
     pub unsafe fn user_init(&self) {
-        let (process, guard) = self.alloc_process().unwrap();
+        let (process, guard) = self.alloc_process().expect("user_init: out of memory");
 
         (*self.init_process.get()) = process as *const Process;
 
         let mut data = process.data();
         let info = &mut process.info();
 
-        user_virtual_memory::init(data.page_table.as_mut().unwrap());
+        if !user_virtual_memory::init(data.page_table.as_mut().unwrap()) {
+            panic!("user_init: out of memory");
+        }
         data.size = PAGE_SIZE;
 
         (*data.trap_frame).epc = 0;
@@ -177,6 +229,20 @@ impl ProcessManager {
         drop(guard);
     }
 
+    // Start a kernel thread running entry, which never returns.
+    // Like fork_return(), entry starts out holding its p->lock.
+    pub fn kernel_thread(&self, name: &str, entry: unsafe fn()) {
+        let (process, guard) = self.alloc_process().expect("kernel_thread: out of memory");
+
+        let data = process.data();
+        data.context.ra = entry as u64;
+        data.name = String::from(name);
+
+        process.info().state = RUNNABLE;
+
+        drop(guard);
+    }
+
     // Look in the process table for an UNUSED proc.
     // If found, initialize state required to run in the kernel,
     // and return with p->lock held.
@@ -185,10 +251,11 @@ impl ProcessManager {
         for process in self.processes.iter() {
             let guard = process.lock.lock();
             let info = process.info();
-            if info.state == UNUSED {
+            if info.state == UNUSED && process.data().kernel_stack != 0 {
                 let mut data = process.data();
 
                 info.pid = self.alloc_pid();
+                info.state = USED;
 
                 // Allocate a trapframe page.
                 data.trap_frame = match PHYSICAL_MEMORY.alloc() {
@@ -209,6 +276,14 @@ impl ProcessManager {
                     return None;
                 }
 
+                // The kernel page table to run on, with room for the user memory.
+                data.kernel_page_table = kernel_virtual_memory::alloc_process_page_table();
+                if data.kernel_page_table.is_none() {
+                    self.free_process(process);
+                    drop(guard);
+                    return None;
+                }
+
                 // Set up new context to start executing at forkret,
                 // which returns to user space.
                 data.context.clear();
@@ -244,14 +319,21 @@ impl ProcessManager {
         }
         data.trap_frame = null_mut();
 
+        SHARED_MEMORY.detach_all(data);
         if data.page_table.is_some() {
             let mut page_table = data.page_table.take().unwrap();
             page_table.unmap_pages(TRAMPOLINE, PAGE_SIZE);
             page_table.unmap_pages(TRAPFRAME, PAGE_SIZE);
+            user_virtual_memory::dealloc_user_virtual_memory(&mut page_table, data.stack_top, data.stack_bottom);
             user_virtual_memory::free_page_table(page_table, data.size);
         }
         data.page_table = None;
+        if let Some(page_table) = data.kernel_page_table.take() {
+            kernel_virtual_memory::free_process_page_table(page_table);
+        }
         data.size = 0;
+        data.stack_top = USER_STACK_TOP;
+        data.stack_bottom = USER_STACK_TOP;
         data.name.clear();
 
         info.pid = 0;
@@ -260,6 +342,10 @@ impl ProcessManager {
         info.parent = None;
         info.killed = false;
         info.exit_state = 0;
+        info.uid = 0;
+        info.euid = 0;
+        info.gid = 0;
+        info.egid = 0;
     }
 
     pub fn grow_process(&self, n: isize) -> bool {
@@ -273,6 +359,9 @@ impl ProcessManager {
             return false;
         }
         let new_size = new_size as usize;
+        if new_size > SHARED_MEMORY_BASE {
+            return false;
+        }
 
         return if n > 0 {
             match user_virtual_memory::alloc_user_virtual_memory(page_table, old_size, new_size) {
@@ -296,22 +385,31 @@ impl ProcessManager {
             Some(p) => { p }
             None => { return None; }
         };
+        // The child is USED, so nobody else can take it.
+        // Copying may sleep to swap pages in or out, which is
+        // not allowed while holding a spinlock.
+        drop(guard);
 
         // Copy user memory from parent to child.
-        let copy_result = user_virtual_memory::copy_page_table(
-            process.data().page_table.as_ref().unwrap(),
-            new_process.data().page_table.as_mut().unwrap(),
-            process.data().size,
-        );
-        if !copy_result {
+        // The child's layout is set first, so that free_process()
+        // finds whatever was copied if a later step fails.
+        let data = process.data();
+        let new_data = new_process.data();
+        new_data.size = data.size;
+        new_data.stack_top = data.stack_top;
+        new_data.stack_bottom = data.stack_bottom;
+        new_data.stack_limit = data.stack_limit;
+        let old_page_table = data.page_table.as_ref().unwrap();
+        let new_page_table = new_data.page_table.as_mut().unwrap();
+        let copy_result = user_virtual_memory::copy_page_table(old_page_table, new_page_table, 0, data.size)
+            && user_virtual_memory::copy_page_table(old_page_table, new_page_table, data.stack_bottom, data.stack_top);
+        if !copy_result || !SHARED_MEMORY.copy_attachments(data, new_data) {
+            let guard = new_process.lock.lock();
             self.free_process(new_process);
             drop(guard);
             return None;
         }
 
-        new_process.data().size = process.data().size;
-        new_process.info().parent = Some(process);
-
         unsafe {
             // copy saved user registers.
             ptr::copy(process.data().trap_frame, new_process.data().trap_frame, 1);
@@ -329,9 +427,16 @@ impl ProcessManager {
         }
         new_process.data().current_dir = Some(process.data().current_dir.unwrap().dup());
         new_process.data().name = process.data().name.clone();
+        new_process.data().randomize = process.data().randomize;
 
+        let guard = new_process.lock.lock();
         let pid = new_process.info().pid;
 
+        new_process.info().parent = Some(process);
+        new_process.info().uid = process.info().uid;
+        new_process.info().euid = process.info().euid;
+        new_process.info().gid = process.info().gid;
+        new_process.info().egid = process.info().egid;
         new_process.info().state = RUNNABLE;
         drop(guard);
 
@@ -399,6 +504,19 @@ impl ProcessManager {
                 process.data().open_file[fd] = null();
             }
         }
+        ICACHE.reclaim();
+
+        // Give back the user memory now instead of in the parent's wait(),
+        // so that a process killed for memory frees it as soon as it runs.
+        let proc_guard = process.lock.lock();
+        let data = process.data();
+        SHARED_MEMORY.detach_all(data);
+        let page_table = data.page_table.as_mut().unwrap();
+        user_virtual_memory::dealloc_user_virtual_memory(page_table, data.stack_top, data.stack_bottom);
+        user_virtual_memory::dealloc_user_virtual_memory(page_table, data.size, 0);
+        data.stack_bottom = data.stack_top;
+        data.size = 0;
+        drop(proc_guard);
 
         // we might re-parent a child to init. we can't be precise about
         // waking up init, since we can't acquire its lock once we've
@@ -443,6 +561,58 @@ impl ProcessManager {
         panic!("zombie exit");
     }
 
+    // Called when a user allocation finds both memory and swap exhausted.
+    // Kills the process other than init with the most resident user pages.
+    // It doesn't wait for the victim to give its memory back: the caller
+    // may hold an inode or be inside a transaction the victim waits for.
+    // Returns whether there is a victim whose exit the caller can retry after.
+    pub fn kill_for_memory(&self) -> bool {
+        let current = CPU_MANAGER.my_proc();
+        if current.info().killed {
+            return false;
+        }
+
+        let mut victim: Option<(usize, usize, usize)> = None; // index, pid, resident pages
+        for (index, process) in self.processes.iter().enumerate() {
+            if process as *const _ == self.init_process() as *const _ {
+                continue;
+            }
+            let guard = process.lock.lock();
+            let info = process.info();
+            let data = process.data();
+            if info.state == RUNNABLE || info.state == RUNNING || info.state == SLEEPING {
+                // a process that is already dying is picked again
+                // rather than killing another one.
+                let pages = if info.killed {
+                    usize::max_value()
+                } else {
+                    user_virtual_memory::resident_pages(data.page_table.as_ref().unwrap(), &data.user_ranges())
+                };
+                if victim.map_or(true, |(_, _, most)| pages > most) {
+                    victim = Some((index, info.pid, pages));
+                }
+            }
+            drop(guard);
+        }
+
+        let (index, pid, pages) = match victim {
+            Some(victim) if victim.2 > 0 => { victim }
+            _ => { return false; }
+        };
+        let process = &self.processes[index];
+        let guard = process.lock.lock();
+        let info = process.info();
+        if info.pid == pid && !info.killed {
+            println!("oom: killing pid {} ({}) with {} resident pages", pid, process.data().name, pages);
+            info.killed = true;
+            if info.state == SLEEPING {
+                info.state = RUNNABLE;
+            }
+        }
+        drop(guard);
+        true
+    }
+
     // Pass p's abandoned children to init.
     // Caller must hold p->lock.
     fn reparent(&self, process: &Process) {
@@ -480,6 +650,7 @@ unsafe fn fork_return() {
         // be run from main().
         IS_FIRST_PROCESS = false;
         file_system_init(ROOT_DEV);
+        PROCESS_MANAGER.kernel_thread("flusher", flusher);
     }
 
     user_trap_return();
@@ -0,0 +1,42 @@
+use crate::riscv::read_time;
+use crate::spin_lock::SpinLock;
+
+// Kernel entropy pool.
+// Seeded from the time CSR at boot and stirred with the arrival
+// time of every device interrupt; outputs are whitened with splitmix64.
+static POOL: SpinLock<u64> = SpinLock::new(0, "random");
+
+fn timer() -> u64 {
+    unsafe { read_time() as u64 }
+}
+
+pub fn random_init() {
+    add_entropy(timer());
+}
+
+pub fn add_entropy(value: u64) {
+    let mut pool = POOL.lock();
+    *pool = pool.rotate_left(13) ^ value;
+    drop(pool);
+}
+
+// called by dev_intr() for every device interrupt.
+pub fn add_interrupt_entropy(irq: u32) {
+    add_entropy(timer() ^ ((irq as u64) << 48));
+}
+
+pub fn random_u64() -> u64 {
+    let mut pool = POOL.lock();
+    *pool = pool.wrapping_add(0x9e3779b97f4a7c15) ^ timer();
+    let mut z = *pool;
+    drop(pool);
+
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// A random number in [0, bound).
+pub fn random_below(bound: usize) -> usize {
+    (random_u64() % bound as u64) as usize
+}
@@ -38,6 +38,7 @@ pub unsafe fn write_mepc(x: usize) {
 
 // Supervisor Status Register, sstatus
 
+pub const SSTATUS_SUM: usize = 1 << 18;  /* Supervisor User Memory access */
 pub const SSTATUS_SPP: usize = 1 << 8;   /* Previous mode, 1=Supervisor, 0=User */
 pub const SSTATUS_SPIE: usize = 1 << 5;  /* Supervisor Previous Interrupt Enable */
 pub const SSTATUS_UPIE: usize = 1 << 4;  /* User Previous Interrupt Enable */
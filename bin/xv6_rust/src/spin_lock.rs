@@ -33,6 +33,24 @@ impl<T: ?Sized> SpinLock<T> {
         }
     }
 
+    // Acquire the lock only if no one else holds it.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        CPU_MANAGER.my_cpu().push_off();
+        if self.holding() {
+            panic!("spinlock {} acquire", self.name);
+        }
+        if self.lock.compare_exchange(false, true, Ordering::Acquire, Ordering::Acquire).is_err() {
+            CPU_MANAGER.my_cpu().pop_off();
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        self.cpuid.set(cpu_id() as isize);
+        Some(SpinLockGuard {
+            lock: &self,
+            data: unsafe { &mut *self.data.get() },
+        })
+    }
+
     pub fn holding(&self) -> bool {
         self.lock.load(Ordering::Relaxed) && (self.cpuid.get() == cpu_id() as isize)
     }
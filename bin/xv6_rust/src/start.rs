@@ -2,14 +2,41 @@ use core::sync::atomic::{AtomicBool, Ordering};
 
 use param_lib::MAX_CPU_NUMBER;
 
+use crate::clock::clock_init;
+use crate::device_tree::{device_tree_init, machine, wait_for_device_tree};
 use crate::driver::DISK;
-use crate::memory::layout::CLINT;
+use crate::file_system::BLOCK_CACHE;
 use crate::memory::PHYSICAL_MEMORY;
 use crate::process::PROCESS_MANAGER;
 
+// entry.S parks harts at or beyond this, and gives
+// each of the rest a BOOT_STACK_SIZE slice of STACK0.
 #[no_mangle]
-pub unsafe fn start() -> ! {
+static MAX_HARTS: usize = MAX_CPU_NUMBER;
+
+const STACK_SIZE: usize = 8192;
+
+#[no_mangle]
+static BOOT_STACK_SIZE: usize = STACK_SIZE;
+
+#[repr(C, align(16))]
+struct BootStacks([u8; STACK_SIZE * MAX_CPU_NUMBER]);
+
+#[no_mangle]
+static mut STACK0: BootStacks = BootStacks([0; STACK_SIZE * MAX_CPU_NUMBER]);
+
+#[no_mangle]
+pub unsafe fn start(_hartid: usize, dtb: usize) -> ! {
     use crate::riscv::*;
+    // hart 0 learns the memory size, hart count and device
+    // addresses from the device tree, which the others wait for.
+    if read_mhartid() == 0 {
+        device_tree_init(dtb);
+        clock_init();
+    } else {
+        wait_for_device_tree();
+    }
+
     // set M Previous Privilege mode to Supervisor, for mret.
     let mut x = read_mstatus();
     x &= !MSTATUS_MPP_MASK;
@@ -28,6 +55,10 @@ pub unsafe fn start() -> ! {
     write_mideleg(0xffff);
     write_sie(read_sie() | SIE_SEIE | SIE_STIE | SIE_SSIE);
 
+    // let supervisor mode read the time CSR; process kernel
+    // page tables don't map the CLINT.
+    write_mcounteren(read_mcounteren() | 2);
+
     // ask for clock interrupts.
     timer_init();
 
@@ -59,8 +90,9 @@ pub unsafe fn timer_init() {
 
     // ask the CLINT for a timer interrupt.
     let interval = 1000000; // cycles; about 1/10th second in qemu.
-    let clint_mtimecmp: usize = CLINT + 0x4000 + 8 * id;
-    let clint_mtime = CLINT + 0xBFF8;
+    let clint = machine().clint;
+    let clint_mtimecmp: usize = clint + 0x4000 + 8 * id;
+    let clint_mtime = clint + 0xBFF8;
     *(clint_mtimecmp as *mut usize) = *(clint_mtime as *const usize) + interval;
 
     // prepare information in scratch[] for timervec.
@@ -89,15 +121,20 @@ pub unsafe fn main() -> ! {
     if cpuid == 0 {
         crate::console::console_init();
         println!("xv6 kernel is booting");
+        machine().print();
         PHYSICAL_MEMORY.init();
         crate::memory::virtual_memory::virtual_memory_init();
         crate::memory::kernel_virtual_memory::kernel_page_table_init();
         crate::memory::kernel_virtual_memory::hart_init(); // turn on paging
+        #[cfg(feature = "vmprint-kernel")]
+        crate::memory::KERNEL_PAGETABLE.lock().print();
         crate::memory::kernel_heap::kernel_heap_init();
+        BLOCK_CACHE.init(machine().buffer_count);
         PROCESS_MANAGER.init();
         crate::trap::trap_hart_init();
         crate::plic::plic_init();
         crate::plic::plic_hart_init();
+        crate::random::random_init();
         DISK.init();
         PROCESS_MANAGER.user_init();
         crate::syscall::system_call_init();
@@ -4,17 +4,40 @@ use core::intrinsics::size_of;
 
 use cstr_core::CString;
 
-use param_lib::MAX_ARG;
+use file_control_lib::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM, AT_RANDOM};
+use file_system_lib::{ACCESS_EXEC, MODE_SETGID, MODE_SETUID, TYPE_FILE};
+use param_lib::{MAX_ARG, MAX_ARG_SIZE};
 
-use crate::file_system::elf::{ELF_MAGIC, ELF_PROG_LOAD, ElfHeader, ProgramHeader};
+use crate::file_system::elf::{DT_NULL, DT_RELA, DT_RELAENT, DT_RELASZ, DynamicEntry, ELF_MAGIC, ELF_PROG_DYNAMIC, ELF_PROG_LOAD, ELF_PROG_PHDR, ELF_TYPE_DYN, ElfHeader, ProgramHeader, R_RISCV_RELATIVE, Rela};
 use crate::file_system::inode::INode;
 use crate::file_system::LOG;
 use crate::file_system::path::find_inode;
-use crate::memory::{ActivePageTable, copy_in, copy_in_string, copy_out, page_round_up, PAGE_SIZE};
-use crate::memory::user_virtual_memory;
+use crate::memory::{ActivePageTable, copy_in, copy_in_string, copy_out, page_round_down, page_round_up, PAGE_SIZE};
+use crate::memory::layout::USER_STACK_TOP;
+use crate::memory::shared_memory::SHARED_MEMORY;
+use crate::memory::{kernel_virtual_memory, user_virtual_memory};
 use crate::process::CPU_MANAGER;
+use crate::random::{random_below, random_u64};
+use crate::riscv::sfence_vma;
 use crate::syscall::{read_arg_string, read_arg_usize};
 
+// Layout randomization moves the load bias of a PIE, the stack
+// and the heap by up to this many pages each.
+const RANDOM_PAGES: usize = 256;
+
+// entries in the auxiliary vector, AT_NULL included
+const AUXV_NUMBER: usize = 7;
+// size of the AT_RANDOM seed
+const RANDOM_BYTES: usize = 16;
+
+fn random_offset() -> usize {
+    if CPU_MANAGER.my_proc().data().randomize {
+        random_below(RANDOM_PAGES) * PAGE_SIZE
+    } else {
+        0
+    }
+}
+
 pub fn sys_exec() -> u64 {
     let path = read_arg_string(0);
     if path.is_none() {
@@ -25,18 +48,45 @@ pub fn sys_exec() -> u64 {
         return u64::max_value();
     }
 
-    return exec(path.unwrap(), argv.unwrap());
+    return exec(path.unwrap(), argv.unwrap(), Vec::new());
+}
+
+pub fn sys_execve() -> u64 {
+    let path = read_arg_string(0);
+    if path.is_none() {
+        return u64::max_value();
+    }
+    let argv = read_arg_string_array(1);
+    if argv.is_none() {
+        return u64::max_value();
+    }
+    let envp = read_arg_string_array(2);
+    if envp.is_none() {
+        return u64::max_value();
+    }
+
+    return exec(path.unwrap(), argv.unwrap(), envp.unwrap());
 }
 
+// Read a null terminated array of user string pointers.
+// A null array is empty.
 fn read_arg_string_array(pos: usize) -> Option<Vec<String>> {
     let page_table = CPU_MANAGER.my_proc().data().page_table.as_ref().unwrap();
     let mut array_addr = read_arg_usize(pos) as *const usize;
 
     let mut vec = Vec::new();
+    if array_addr.is_null() {
+        return Some(vec);
+    }
     let mut string_addr: usize = 0;
 
-    copy_in(page_table, &mut string_addr as *mut usize as usize, array_addr as usize, size_of::<usize>());
+    if !copy_in(page_table, &mut string_addr as *mut usize as usize, array_addr as usize, size_of::<usize>()) {
+        return None;
+    }
     while string_addr != 0 {
+        if vec.len() >= MAX_ARG {
+            return None;
+        }
         let string = copy_in_string(page_table, string_addr);
         if string.is_none() {
             return None;
@@ -44,20 +94,22 @@ fn read_arg_string_array(pos: usize) -> Option<Vec<String>> {
         vec.push(string.unwrap());
 
         array_addr = unsafe { array_addr.offset(1) };
-        copy_in(page_table, &mut string_addr as *mut usize as usize, array_addr as usize, size_of::<usize>());
+        if !copy_in(page_table, &mut string_addr as *mut usize as usize, array_addr as usize, size_of::<usize>()) {
+            return None;
+        }
     }
 
     Some(vec)
 }
 
-fn exec(path: String, argv: Vec<String>) -> u64 {
+fn exec(path: String, argv: Vec<String>, envp: Vec<String>) -> u64 {
     let load_result = load_program(&path);
     if load_result.is_none() {
         return u64::max_value();
     }
 
-    let (page_table, size, elf_header) = load_result.unwrap();
-    let result = prepare_process(path, argv, page_table, size, elf_header);
+    let (page_table, (size, phdr), elf_header, credentials) = load_result.unwrap();
+    let result = prepare_process(path, argv, envp, page_table, size, phdr, elf_header, credentials);
 
     return if result.is_none() {
         u64::max_value()
@@ -66,7 +118,11 @@ fn exec(path: String, argv: Vec<String>) -> u64 {
     };
 }
 
-fn load_program(path: &String) -> Option<(ActivePageTable, usize, ElfHeader)> {
+// Returns the size of the image and where its program headers are,
+// and the effective user and group to run the program as,
+// those of the file if it is setuid or setgid.
+// A PIE is loaded at a random base, which the entry point includes.
+fn load_program(path: &String) -> Option<(ActivePageTable, (usize, usize), ElfHeader, (u32, u32))> {
     let log = unsafe { &mut LOG };
 
     log.begin_op();
@@ -79,6 +135,16 @@ fn load_program(path: &String) -> Option<(ActivePageTable, usize, ElfHeader)> {
     let ip = ip.unwrap();
     let guard = ip.lock();
 
+    if ip.data().types != TYPE_FILE || !ip.permitted(ACCESS_EXEC) {
+        ip.unlock_put(guard);
+        log.end_op();
+        return None;
+    }
+    let info = CPU_MANAGER.my_proc().info();
+    let mode = ip.data().mode;
+    let euid = if mode & MODE_SETUID != 0 { ip.data().uid } else { info.euid };
+    let egid = if mode & MODE_SETGID != 0 { ip.data().gid } else { info.egid };
+
     // Check ELF header
     let mut elf_header = ElfHeader::new();
     if !check_elf_header(&mut elf_header, ip) {
@@ -95,9 +161,12 @@ fn load_program(path: &String) -> Option<(ActivePageTable, usize, ElfHeader)> {
     }
     let mut page_table = page_table.unwrap();
 
-    let load_result = load_program_into_memory(&mut page_table, &elf_header, ip);
-    let size = match load_result {
-        Ok(sz) => { sz }
+    // a position independent executable can be loaded anywhere.
+    let base = if elf_header.types == ELF_TYPE_DYN { random_offset() } else { 0 };
+
+    let load_result = load_program_into_memory(&mut page_table, &elf_header, ip, base);
+    let image = match load_result {
+        Ok(image) => { image }
         Err(sz) => {
             user_virtual_memory::free_page_table(page_table, sz);
             ip.unlock_put(guard);
@@ -109,96 +178,139 @@ fn load_program(path: &String) -> Option<(ActivePageTable, usize, ElfHeader)> {
     ip.unlock_put(guard);
     log.end_op();
 
-    return Some((page_table, size, elf_header));
+    elf_header.entry += base as u64;
+    return Some((page_table, image, elf_header, (euid, egid)));
 }
 
-fn prepare_process(path: String, argv: Vec<String>, mut page_table: ActivePageTable, mut size: usize, elf_header: ElfHeader) -> Option<usize> {
+fn prepare_process(path: String, argv: Vec<String>, envp: Vec<String>, mut page_table: ActivePageTable, mut size: usize, phdr: usize, elf_header: ElfHeader, credentials: (u32, u32)) -> Option<usize> {
     let process = CPU_MANAGER.my_proc();
     let old_size = process.data().size;
-
-    // Allocate two pages at the next page boundary.
-    // Use the second as the user stack.
     size = page_round_up(size);
-    size = match user_virtual_memory::alloc_user_virtual_memory(&mut page_table, size, size + 2 * PAGE_SIZE) {
-        None => {
-            user_virtual_memory::free_page_table(page_table, size);
-            return None;
-        }
-        Some(new_size) => {
-            new_size
-        }
-    };
-    let stack_top = size;
-    let stack_base = stack_top - PAGE_SIZE;
-
-    user_virtual_memory::make_guard_page(&mut page_table, stack_top - 2 * PAGE_SIZE);
 
-    // Push argument strings, prepare rest of stack in ustack.
-    if argv.len() >= MAX_ARG {
+    // The initial stack, from sp upwards, as the RISC-V psABI lays it out:
+    // argc, the argv pointers and a null, the envp pointers and a null,
+    // the auxiliary vector ending in AT_NULL, then the AT_RANDOM bytes
+    // and the strings themselves.
+    let strings: Vec<CString> = argv.iter().chain(envp.iter())
+        .map(|it| CString::new(it.clone()).expect("CString::new failed"))
+        .collect();
+    let string_size: usize = strings.iter().map(|it| it.to_bytes_with_nul().len()).sum();
+    let word_number = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * AUXV_NUMBER;
+    let image_size = word_number * size_of::<usize>() + RANDOM_BYTES + string_size;
+    if image_size > MAX_ARG_SIZE || image_size > process.data().stack_limit.current {
         user_virtual_memory::free_page_table(page_table, size);
         return None;
     }
-    let mut sp = stack_top;
-    let mut user_stack = Vec::new();
-    for argc in 0..argv.len() {
-        let c_str = CString::new(argv[argc].clone()).expect("CString::new failed");
-        let c_bytes = c_str.to_bytes_with_nul();
 
-        sp -= c_bytes.len();
-        sp -= sp % 16; // riscv sp must be 16-byte aligned
+    // The stack sits below a random gap under USER_STACK_TOP,
+    // starts as the pages holding the image, and grows down on page faults.
+    let stack_top = USER_STACK_TOP - random_offset();
+    let mut top = stack_top;
+    if process.data().randomize {
+        top -= random_below(16) * 16;
+    }
+    let sp = (top - image_size) & !15; // riscv sp must be 16-byte aligned
+    let stack_base = page_round_down(sp);
+    if user_virtual_memory::alloc_user_virtual_memory(&mut page_table, stack_base, stack_top).is_none() {
+        user_virtual_memory::free_page_table(page_table, size);
+        return None;
+    }
 
-        if sp < stack_base {
-            user_virtual_memory::free_page_table(page_table, size);
-            return None;
-        }
-        let copy_result = unsafe { copy_out(&page_table, sp, c_bytes.as_ptr() as usize, c_bytes.len()) };
-        if !copy_result {
-            user_virtual_memory::free_page_table(page_table, size);
-            return None;
+    let random_address = sp + word_number * size_of::<usize>();
+    let mut string_address = random_address + RANDOM_BYTES;
+    let mut words: Vec<usize> = Vec::with_capacity(word_number);
+    words.push(argv.len());
+    for (i, string) in strings.iter().enumerate() {
+        if i == argv.len() {
+            words.push(0);
         }
-
-        user_stack.push(sp);
+        words.push(string_address);
+        string_address += string.to_bytes_with_nul().len();
+    }
+    if envp.is_empty() {
+        words.push(0);
+    }
+    words.push(0);
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, size_of::<ProgramHeader>()),
+        (AT_PHNUM, elf_header.phnum as usize),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf_header.entry as usize),
+        (AT_RANDOM, random_address),
+        (AT_NULL, 0),
+    ];
+    for (key, value) in auxv.iter() {
+        words.push(*key);
+        words.push(*value);
     }
-    user_stack.push(0);
 
-    // push the array of argv[] pointers.
-    sp -= (argv.len() + 1) * size_of::<u64>();
-    sp -= sp % 16;
-    if sp < stack_base {
-        user_virtual_memory::free_page_table(page_table, size);
-        return None;
+    let mut image: Vec<u8> = Vec::with_capacity(top - sp);
+    for word in words.iter() {
+        image.extend_from_slice(&word.to_le_bytes());
     }
-    let copy_result = unsafe { copy_out(&page_table, sp, user_stack.as_ptr() as usize, (argv.len() + 1) * size_of::<u64>()) };
+    for _ in 0..(RANDOM_BYTES / size_of::<u64>()) {
+        image.extend_from_slice(&random_u64().to_le_bytes());
+    }
+    for string in strings.iter() {
+        image.extend_from_slice(string.to_bytes_with_nul());
+    }
+    image.resize(top - sp, 0);
+
+    let copy_result = unsafe { copy_out(&page_table, sp, image.as_ptr() as usize, image.len()) };
     if !copy_result {
-        user_virtual_memory::free_page_table(page_table, size);
+        free_image(page_table, size, stack_base, stack_top);
         return None;
     }
 
-    // arguments to user main(argc, argv)
+    // arguments to user main(argc, argv, envp)
     // argc is returned via the system call return
     // value, which goes in a0.
     let data = process.data();
     let trap_frame = unsafe { data.trap_frame.as_mut() }.unwrap();
 
-    trap_frame.a1 = sp as u64;
+    trap_frame.a1 = (sp + size_of::<usize>()) as u64;
+    trap_frame.a2 = (sp + (argv.len() + 2) * size_of::<usize>()) as u64;
 
     // Save program name for debugging.
     let last = path.rfind("/").map_or(0, |it| it + 1);
     let (_, filename) = path.split_at(last);
     data.name = String::from(filename);
 
+    // the heap grows from size, so another unmapped gap moves its base.
+    size += random_offset();
+
     // Commit to the user image.
+    SHARED_MEMORY.detach_all(data);
     let old_page_table = data.page_table.take().unwrap();
+    let (old_stack_bottom, old_stack_top) = (data.stack_bottom, data.stack_top);
     data.page_table = Some(page_table);
     data.size = size;
+    data.stack_top = stack_top;
+    data.stack_bottom = stack_base;
     trap_frame.epc = elf_header.entry;  // initial program counter = main
     trap_frame.sp = sp as u64; // initial stack pointer
+    let guard = process.lock.lock();
+    process.info().euid = credentials.0;
+    process.info().egid = credentials.1;
+    drop(guard);
+
+    // the kernel page table still points at the old image's tables.
+    if kernel_virtual_memory::share_user_windows(data.kernel_page_table.as_mut().unwrap(), data.page_table.as_ref().unwrap()) {
+        unsafe { sfence_vma(); }
+    }
 
-    user_virtual_memory::free_page_table(old_page_table, old_size);
+    free_image(old_page_table, old_size, old_stack_bottom, old_stack_top);
 
     return Some(argv.len()); // this ends up in a0, the first argument to main(argc, argv)
 }
 
+// Free a user page table along with its image and stack.
+fn free_image(mut page_table: ActivePageTable, size: usize, stack_bottom: usize, stack_top: usize) {
+    user_virtual_memory::dealloc_user_virtual_memory(&mut page_table, stack_top, stack_bottom);
+    user_virtual_memory::free_page_table(page_table, size);
+}
+
 fn check_elf_header(elf_header: &mut ElfHeader, ip: &INode) -> bool {
     let size_of_elf_header = size_of::<ElfHeader>() as u32;
     if ip.read(false, elf_header as *const _ as usize, 0, size_of_elf_header) != size_of_elf_header {
@@ -210,19 +322,31 @@ fn check_elf_header(elf_header: &mut ElfHeader, ip: &INode) -> bool {
     return true;
 }
 
-fn load_program_into_memory(page_table: &mut ActivePageTable, elf_header: &ElfHeader, ip: &INode) -> Result<usize, usize> {
+// Load every segment at base + vaddr; nothing below base is mapped.
+// Returns the size of the image and the address of the program headers,
+// 0 if no segment loads them.
+fn load_program_into_memory(page_table: &mut ActivePageTable, elf_header: &ElfHeader, ip: &INode, base: usize) -> Result<(usize, usize), usize> {
     // Load program into memory.
-    let mut off = elf_header.phoff as u32;
+    let mut off = elf_header.phoff;
     let size_of_program_header = size_of::<ProgramHeader>() as u32;
 
-    let mut size = 0;
+    let mut size = base;
+    let mut dynamic = None;
+    let mut phdr = 0;
     for _ in 0..(elf_header.phnum as usize) {
-        let ph = ProgramHeader::new();
+        let mut ph = ProgramHeader::new();
         if ip.read(false, &ph as *const _ as usize, off, size_of_program_header) != size_of_program_header {
             return Err(size);
         }
+        ph.vaddr += base as u64;
+        if ph.types == ELF_PROG_DYNAMIC {
+            dynamic = Some(ph.vaddr as usize);
+        }
+        if ph.types == ELF_PROG_PHDR {
+            phdr = ph.vaddr as usize;
+        }
         if ph.types != ELF_PROG_LOAD {
-            off += size_of_program_header;
+            off += size_of_program_header as u64;
             continue;
         }
         if ph.memsz < ph.filesz {
@@ -244,9 +368,58 @@ fn load_program_into_memory(page_table: &mut ActivePageTable, elf_header: &ElfHe
         if !load_segement(page_table, ph.vaddr as usize, ip, ph.off as usize, ph.filesz as usize) {
             return Err(size);
         }
-        off += size_of_program_header;
+        if phdr == 0 && ph.off <= elf_header.phoff && elf_header.phoff < ph.off + ph.filesz {
+            phdr = (ph.vaddr + elf_header.phoff - ph.off) as usize;
+        }
+        off += size_of_program_header as u64;
+    }
+
+    if elf_header.types == ELF_TYPE_DYN && dynamic.is_some() && !relocate(page_table, dynamic.unwrap(), base) {
+        return Err(size);
+    }
+    Ok((size, phdr))
+}
+
+// Apply the relocations of a PIE loaded at base.
+// Only R_RISCV_RELATIVE is supported, which is all a static PIE has.
+fn relocate(page_table: &ActivePageTable, dynamic: usize, base: usize) -> bool {
+    let mut rela = 0;
+    let mut rela_size = 0;
+    let mut rela_entry = size_of::<Rela>();
+
+    let mut addr = dynamic;
+    loop {
+        let mut entry = DynamicEntry { tag: 0, value: 0 };
+        if !copy_in(page_table, &mut entry as *mut _ as usize, addr, size_of::<DynamicEntry>()) {
+            return false;
+        }
+        match entry.tag {
+            DT_NULL => { break; }
+            DT_RELA => { rela = entry.value as usize; }
+            DT_RELASZ => { rela_size = entry.value as usize; }
+            DT_RELAENT => { rela_entry = entry.value as usize; }
+            _ => {}
+        }
+        addr += size_of::<DynamicEntry>();
+    }
+    if rela_entry < size_of::<Rela>() {
+        return false;
+    }
+
+    for off in (0..rela_size).step_by(rela_entry) {
+        let mut entry = Rela { offset: 0, info: 0, addend: 0 };
+        if !copy_in(page_table, &mut entry as *mut _ as usize, base + rela + off, size_of::<Rela>()) {
+            return false;
+        }
+        if entry.info & 0xffffffff != R_RISCV_RELATIVE {
+            return false;
+        }
+        let value = (base as u64).wrapping_add(entry.addend);
+        if !unsafe { copy_out(page_table, base + entry.offset as usize, &value as *const u64 as usize, size_of::<u64>()) } {
+            return false;
+        }
     }
-    Ok(size)
+    true
 }
 
 // Load a program segment into pagetable at virtual address va.
@@ -265,7 +438,7 @@ fn load_segement(page_table: &mut ActivePageTable, va: usize, ip: &INode, offset
             PAGE_SIZE
         } as u32;
 
-        if ip.read(false, pa, (offset + i) as u32, n) != n {
+        if ip.read(false, pa, (offset + i) as u64, n) != n {
             return false;
         }
     }
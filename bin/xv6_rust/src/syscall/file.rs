@@ -1,17 +1,26 @@
 use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::intrinsics::size_of;
 use core::ptr::{null, null_mut};
 
-use file_control_lib::{OPEN_CREATE, OPEN_READ_ONLY, OPEN_READ_WRITE, OPEN_TRUNC, OPEN_WRITE_ONLY};
-use file_system_lib::{TYPE_DEVICE, TYPE_DIR, TYPE_FILE};
+use file_control_lib::{OPEN_APPEND, OPEN_CREATE, OPEN_NOFOLLOW, OPEN_READ_ONLY, OPEN_READ_WRITE, OPEN_TRUNC, OPEN_WRITE_ONLY};
+use file_system_lib::{
+    ACCESS_EXEC, ACCESS_READ, ACCESS_WRITE, BLOCK_SIZE, DEFAULT_DEVICE_MODE, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE,
+    DEFAULT_SYMLINK_MODE, MODE_MASK, MODE_SETGID, MODE_SETUID, ROOT_INO, ROOT_UID, TYPE_DEVICE, TYPE_DIR, TYPE_FILE,
+    TYPE_SYMLINK,
+};
 use param_lib::{MAX_DEV_NUMBER, MAX_OPEN_FILE_NUMBER};
 
+use crate::clock::now;
 use crate::file_system::{FILE_TABLE, LOG};
 use crate::file_system::file::File;
-use crate::file_system::file::FileType::{DEVICE, INODE};
-use crate::file_system::inode::{ICACHE, INode};
-use crate::file_system::path::{find_inode, find_inode_parent};
+use crate::file_system::file::FileType::{DEVICE, INODE, PIPE};
+use crate::file_system::inode::{ICACHE, INode, max_name_length};
+use crate::file_system::path::{find_create_path, find_inode, find_inode_no_follow, find_inode_parent};
+use crate::memory::either_copy_in;
 use crate::process::CPU_MANAGER;
-use crate::sleep_lock::SleepLockGuard;
+use crate::sleep_lock::{SleepLock, SleepLockGuard};
 use crate::syscall::{read_arg_string, read_arg_usize};
 
 // Fetch the nth word-sized system call argument as a file descriptor
@@ -88,6 +97,163 @@ pub fn sys_write() -> u64 {
     return FILE_TABLE.write(file, addr, size);
 }
 
+pub fn sys_lseek() -> u64 {
+    let file = match read_arg_fd(0) {
+        Some((_, file)) => { file }
+        None => {
+            return u64::max_value();
+        }
+    };
+    let offset = read_arg_usize(1) as isize;
+    let whence = read_arg_usize(2);
+
+    return FILE_TABLE.seek(file, offset, whence);
+}
+
+pub fn sys_pread() -> u64 {
+    let file = match read_arg_fd(0) {
+        Some((_, file)) => { file }
+        None => {
+            return u64::max_value();
+        }
+    };
+    let addr = read_arg_usize(1);
+    let size = read_arg_usize(2);
+    let off = read_arg_usize(3) as u64;
+
+    return FILE_TABLE.pread(file, addr, size, off);
+}
+
+pub fn sys_pwrite() -> u64 {
+    let file = match read_arg_fd(0) {
+        Some((_, file)) => { file }
+        None => {
+            return u64::max_value();
+        }
+    };
+    let addr = read_arg_usize(1);
+    let size = read_arg_usize(2);
+    let off = read_arg_usize(3) as u64;
+
+    return FILE_TABLE.pwrite(file, addr, size, off);
+}
+
+// Set the access and modification times of path to times[0] and times[1],
+// or both to now if times is null.
+pub fn sys_utimes() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let path = read_arg_string(0);
+    if path.is_none() {
+        return u64::max_value();
+    }
+    let path = path.unwrap();
+    let addr = read_arg_usize(1);
+
+    let mut times = [now(); 2];
+    if addr != 0 && !either_copy_in(true, times.as_mut_ptr() as usize, addr, size_of::<[u64; 2]>()) {
+        return u64::max_value();
+    }
+
+    log.begin_op();
+    let ip = match find_inode(&path) {
+        Some(ip) => { ip }
+        None => {
+            log.end_op();
+            return u64::max_value();
+        }
+    };
+    let guard = ip.lock();
+    // Only the owner may set times; setting them to now also
+    // takes no more than permission to write.
+    let euid = CPU_MANAGER.my_proc().info().euid;
+    let owner = euid == ROOT_UID || euid == ip.data().uid;
+    if !owner && (addr != 0 || !ip.permitted(ACCESS_WRITE)) {
+        ip.unlock_put(guard);
+        log.end_op();
+        return u64::max_value();
+    }
+    ip.data().atime = times[0];
+    ip.data().mtime = times[1];
+    ip.data().ctime = now();
+    ip.update();
+    ip.unlock_put(guard);
+    log.end_op();
+
+    return 0;
+}
+
+pub fn sys_chmod() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let path = read_arg_string(0);
+    if path.is_none() {
+        return u64::max_value();
+    }
+    let path = path.unwrap();
+    let mode = read_arg_usize(1) as u32;
+
+    log.begin_op();
+    let ip = match find_inode(&path) {
+        Some(ip) => { ip }
+        None => {
+            log.end_op();
+            return u64::max_value();
+        }
+    };
+    let guard = ip.lock();
+    let euid = CPU_MANAGER.my_proc().info().euid;
+    if euid != ROOT_UID && euid != ip.data().uid {
+        ip.unlock_put(guard);
+        log.end_op();
+        return u64::max_value();
+    }
+    ip.data().mode = mode & MODE_MASK;
+    ip.data().ctime = now();
+    ip.update();
+    ip.unlock_put(guard);
+    log.end_op();
+
+    return 0;
+}
+
+// Only root may give files away. A new owner
+// loses the setuid and setgid bits.
+pub fn sys_chown() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let path = read_arg_string(0);
+    if path.is_none() {
+        return u64::max_value();
+    }
+    let path = path.unwrap();
+    let uid = read_arg_usize(1) as u32;
+    let gid = read_arg_usize(2) as u32;
+
+    if CPU_MANAGER.my_proc().info().euid != ROOT_UID {
+        return u64::max_value();
+    }
+
+    log.begin_op();
+    let ip = match find_inode(&path) {
+        Some(ip) => { ip }
+        None => {
+            log.end_op();
+            return u64::max_value();
+        }
+    };
+    let guard = ip.lock();
+    ip.data().uid = uid;
+    ip.data().gid = gid;
+    ip.data().mode &= !(MODE_SETUID | MODE_SETGID);
+    ip.data().ctime = now();
+    ip.update();
+    ip.unlock_put(guard);
+    log.end_op();
+
+    return 0;
+}
+
 pub fn sys_close() -> u64 {
     let (fd, file) = match read_arg_fd(0) {
         Some(it) => { it }
@@ -101,6 +267,32 @@ pub fn sys_close() -> u64 {
     return 0;
 }
 
+// Commits are lazy, so a write is only sure to
+// survive a crash once fsync() returns.
+pub fn sys_fsync() -> u64 {
+    let file = match read_arg_fd(0) {
+        Some((_, file)) => { file }
+        None => {
+            return u64::max_value();
+        }
+    };
+    if file.data().types == PIPE {
+        return u64::max_value();
+    }
+    let log = unsafe { &mut LOG };
+    log.flush();
+
+    return 0;
+}
+
+// Commit and install everything, leaving the log empty.
+pub fn sys_sync() -> u64 {
+    let log = unsafe { &mut LOG };
+    log.sync();
+
+    return 0;
+}
+
 pub fn sys_fstat() -> u64 {
     let (_, file) = match read_arg_fd(0) {
         None => {
@@ -117,8 +309,353 @@ pub fn sys_fstat() -> u64 {
     };
 }
 
+// Create the path new as a link to the same inode as old.
+pub fn sys_link() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let old = read_arg_string(0);
+    let new = read_arg_string(1);
+    if old.is_none() || new.is_none() {
+        return u64::max_value();
+    }
+    let (old, new) = (old.unwrap(), new.unwrap());
+
+    log.begin_op();
 
-fn create(path: &String, types: u16, major: u16, minor: u16) -> Option<(&'static INode, SleepLockGuard<()>)> {
+    let ip = match find_inode(&old) {
+        Some(ip) => { ip }
+        None => {
+            log.end_op();
+            return u64::max_value();
+        }
+    };
+
+    let guard = ip.lock();
+    if ip.data().types == TYPE_DIR {
+        ip.unlock_put(guard);
+        log.end_op();
+        return u64::max_value();
+    }
+
+    ip.data().nlink += 1;
+    ip.data().ctime = now();
+    ip.update();
+    ip.unlock(guard);
+
+    let linked = match find_inode_parent(&new) {
+        Some((dp, name)) => {
+            let dp_guard = dp.lock();
+            let linked = dp.data().dev == ip.data().dev && dp.permitted(ACCESS_WRITE | ACCESS_EXEC) &&
+                dp.dir_link(&name, ip.data().inum, ip.data().types);
+            dp.unlock_put(dp_guard);
+            linked
+        }
+        None => { false }
+    };
+
+    if !linked {
+        let guard = ip.lock();
+        ip.data().nlink -= 1;
+        ip.update();
+        ip.unlock_put(guard);
+        log.end_op();
+        return u64::max_value();
+    }
+
+    ICACHE.put(ip);
+    log.end_op();
+
+    return 0;
+}
+
+// Remove a directory entry. The inode and its blocks are freed
+// by ICACHE.put() once no entry and no open file refer to it.
+pub fn sys_unlink() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let path = read_arg_string(0);
+    if path.is_none() {
+        return u64::max_value();
+    }
+    let path = path.unwrap();
+
+    log.begin_op();
+
+    let (dp, name) = match find_inode_parent(&path) {
+        Some(it) => { it }
+        None => {
+            log.end_op();
+            return u64::max_value();
+        }
+    };
+
+    let dp_guard = dp.lock();
+
+    // Cannot unlink "." or "..".
+    if name == "." || name == ".." || !dp.permitted(ACCESS_WRITE | ACCESS_EXEC) {
+        dp.unlock_put(dp_guard);
+        log.end_op();
+        return u64::max_value();
+    }
+
+    let mut off: u64 = 0;
+    let ip = match dp.dir_lookup(&name, &mut off as *mut u64) {
+        Some(ip) => { ip }
+        None => {
+            dp.unlock_put(dp_guard);
+            log.end_op();
+            return u64::max_value();
+        }
+    };
+    let guard = ip.lock();
+
+    if ip.data().nlink < 1 {
+        panic!("unlink: nlink < 1");
+    }
+    if ip.data().types == TYPE_DIR && !ip.is_dir_empty() {
+        ip.unlock_put(guard);
+        dp.unlock_put(dp_guard);
+        log.end_op();
+        return u64::max_value();
+    }
+
+    dp.dir_unlink(off);
+    if ip.data().types == TYPE_DIR {
+        dp.data().nlink -= 1; // for ".."
+        dp.update();
+    }
+    dp.unlock_put(dp_guard);
+
+    ip.data().nlink -= 1;
+    ip.data().ctime = now();
+    ip.update();
+    ip.unlock_put(guard);
+
+    log.end_op();
+
+    return 0;
+}
+
+// Renames are serialized, so that the directory tree cannot change
+// shape between the subtree check of sys_rename() and the rename itself.
+static RENAME_LOCK: SleepLock<()> = SleepLock::new(());
+
+// The inode numbers of dir and of each directory above it, up to the root.
+fn ancestors(dir: &INode) -> Vec<u32> {
+    let mut inums = Vec::new();
+    let mut ip = dir.dup();
+    loop {
+        let inum = ip.data().inum;
+        inums.push(inum);
+        if inum == ROOT_INO {
+            ICACHE.put(ip);
+            return inums;
+        }
+        let guard = ip.lock();
+        let parent = ip.dir_lookup(&String::from(".."), null_mut());
+        ip.unlock_put(guard);
+        match parent {
+            Some(parent) => { ip = parent; }
+            None => { return inums; }
+        }
+    }
+}
+
+// Move the entry old to new, replacing new if it exists, all in one
+// transaction: after a crash either name refers to the inode, never neither.
+pub fn sys_rename() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let old = read_arg_string(0);
+    let new = read_arg_string(1);
+    if old.is_none() || new.is_none() {
+        return u64::max_value();
+    }
+    let (old, new) = (old.unwrap(), new.unwrap());
+
+    let rename_guard = RENAME_LOCK.lock();
+    log.begin_op();
+    let result = rename(&old, &new);
+    log.end_op();
+    drop(rename_guard);
+
+    return if result {
+        0
+    } else {
+        u64::max_value()
+    };
+}
+
+fn rename(old: &String, new: &String) -> bool {
+    let (old_dp, old_name) = match find_inode_parent(old) {
+        Some(it) => { it }
+        None => { return false; }
+    };
+    let (new_dp, new_name) = match find_inode_parent(new) {
+        Some(it) => { it }
+        None => {
+            ICACHE.put(old_dp);
+            return false;
+        }
+    };
+    let put_dirs = || {
+        ICACHE.put(old_dp);
+        ICACHE.put(new_dp);
+    };
+    if old_name == "." || old_name == ".." || new_name == "." || new_name == ".." ||
+        old_dp.data().dev != new_dp.data().dev {
+        put_dirs();
+        return false;
+    }
+
+    let dp_guard = old_dp.lock();
+    let ip = old_dp.dir_lookup(&old_name, null_mut());
+    old_dp.unlock(dp_guard);
+    let ip = match ip {
+        Some(ip) => { ip }
+        None => {
+            put_dirs();
+            return false;
+        }
+    };
+    let guard = ip.lock();
+    let is_dir = ip.data().types == TYPE_DIR;
+    ip.unlock(guard);
+
+    // A directory can't move below itself. Directories are locked
+    // ancestor first, as lookups and unlink() do; with renames
+    // serialized, no ancestor of a non-empty directory can change.
+    let same_dir = old_dp.data().inum == new_dp.data().inum;
+    let new_ancestors = ancestors(new_dp);
+    let old_ancestors = if same_dir { new_ancestors.clone() } else { ancestors(old_dp) };
+    if is_dir && new_ancestors.contains(&ip.data().inum) {
+        ICACHE.put(ip);
+        put_dirs();
+        return false;
+    }
+    let old_first = new_ancestors.contains(&old_dp.data().inum);
+    let (old_dp_guard, new_dp_guard) = if same_dir {
+        (old_dp.lock(), None)
+    } else if old_first {
+        let old_dp_guard = old_dp.lock();
+        (old_dp_guard, Some(new_dp.lock()))
+    } else {
+        let new_dp_guard = new_dp.lock();
+        (old_dp.lock(), Some(new_dp_guard))
+    };
+    let unlock_dirs = |old_dp_guard, new_dp_guard: Option<SleepLockGuard<()>>| {
+        if let Some(new_dp_guard) = new_dp_guard {
+            new_dp.unlock_put(new_dp_guard);
+        } else {
+            ICACHE.put(new_dp);
+        }
+        old_dp.unlock_put(old_dp_guard);
+    };
+
+    if !old_dp.permitted(ACCESS_WRITE | ACCESS_EXEC) || !new_dp.permitted(ACCESS_WRITE | ACCESS_EXEC) {
+        ICACHE.put(ip);
+        unlock_dirs(old_dp_guard, new_dp_guard);
+        return false;
+    }
+
+    // old may have changed while no directory was locked.
+    let mut old_off: u64 = 0;
+    let still = old_dp.dir_lookup(&old_name, &mut old_off as *mut u64);
+    let moved = still.map_or(true, |it| it.data().inum != ip.data().inum);
+    if let Some(still) = still {
+        ICACHE.put(still);
+    }
+    if moved {
+        ICACHE.put(ip);
+        unlock_dirs(old_dp_guard, new_dp_guard);
+        return false;
+    }
+
+    let mut new_off: u64 = 0;
+    let target = new_dp.dir_lookup(&new_name, &mut new_off as *mut u64);
+    if let Some(target) = target {
+        // Renaming to another link of the same inode does nothing,
+        // and a directory above old is never empty.
+        let same = target.data().inum == ip.data().inum;
+        if same || old_ancestors.contains(&target.data().inum) {
+            ICACHE.put(target);
+            ICACHE.put(ip);
+            unlock_dirs(old_dp_guard, new_dp_guard);
+            return same;
+        }
+    }
+
+    let guard = ip.lock();
+    let target_guard = target.map(|target| target.lock());
+    if let Some(target) = target {
+        let target_is_dir = target.data().types == TYPE_DIR;
+        if target_is_dir != is_dir || target_is_dir && !target.is_dir_empty() {
+            target.unlock_put(target_guard.unwrap());
+            ip.unlock_put(guard);
+            unlock_dirs(old_dp_guard, new_dp_guard);
+            return false;
+        }
+    }
+
+    // A moved directory's ".." is pointed at new_dp below;
+    // find it first, so that a directory without one is left alone.
+    let mut dots_off: u64 = 0;
+    if is_dir && !same_dir {
+        match ip.dir_lookup(&String::from(".."), &mut dots_off as *mut u64) {
+            Some(parent) => { ICACHE.put(parent); }
+            None => {
+                if let Some(target) = target {
+                    target.unlock_put(target_guard.unwrap());
+                }
+                ip.unlock_put(guard);
+                unlock_dirs(old_dp_guard, new_dp_guard);
+                return false;
+            }
+        }
+    }
+
+    // Link the new name, replacing the target's entry in place.
+    match target {
+        Some(target) => {
+            new_dp.dir_set(new_off, ip.data().inum, ip.data().types);
+            if target.data().types == TYPE_DIR {
+                new_dp.data().nlink -= 1; // for the target's ".."
+            }
+            target.data().nlink -= 1;
+            target.data().ctime = now();
+            target.update();
+        }
+        None => {
+            if !new_dp.dir_link(&new_name, ip.data().inum, ip.data().types) {
+                ip.unlock_put(guard);
+                unlock_dirs(old_dp_guard, new_dp_guard);
+                return false;
+            }
+        }
+    }
+
+    // Then drop the old one.
+    old_dp.dir_unlink(old_off);
+    if is_dir && !same_dir {
+        ip.dir_set(dots_off, new_dp.data().inum, TYPE_DIR);
+        old_dp.data().nlink -= 1;
+        new_dp.data().nlink += 1;
+    }
+    old_dp.update();
+    new_dp.update();
+    ip.data().ctime = now();
+    ip.update();
+
+    if let Some(target) = target {
+        target.unlock_put(target_guard.unwrap());
+    }
+    ip.unlock_put(guard);
+    unlock_dirs(old_dp_guard, new_dp_guard);
+
+    true
+}
+
+fn create(path: &String, types: u16, major: u16, minor: u16) -> Option<(&'static INode, SleepLockGuard<'static, ()>)> {
     let dp = find_inode_parent(path);
     if dp.is_none() {
         return None;
@@ -140,25 +677,38 @@ fn create(path: &String, types: u16, major: u16, minor: u16) -> Option<(&'static
         }
         _ => {}
     }
+    if name.len() > max_name_length() || !dp.permitted(ACCESS_WRITE | ACCESS_EXEC) {
+        dp.unlock_put(dp_guard);
+        return None;
+    }
 
     let ip = ICACHE.alloc(dp.data().dev, types);
     let guard = ip.lock();
     ip.data().major = major;
     ip.data().minor = minor;
     ip.data().nlink = 1;
+    let info = CPU_MANAGER.my_proc().info();
+    ip.data().uid = info.euid;
+    ip.data().gid = info.egid;
+    ip.data().mode = match types {
+        TYPE_DIR => { DEFAULT_DIR_MODE }
+        TYPE_DEVICE => { DEFAULT_DEVICE_MODE }
+        TYPE_SYMLINK => { DEFAULT_SYMLINK_MODE }
+        _ => { DEFAULT_FILE_MODE }
+    };
     ip.update();
 
     if types == TYPE_DIR { // Create . and .. entries.
         dp.data().nlink += 1; // for ".."
         dp.update();
         // No ip->nlink++ for ".": avoid cyclic ref count.
-        if !ip.dir_link(&String::from("."), ip.data().inum) ||
-            !ip.dir_link(&String::from(".."), dp.data().inum) {
+        if !ip.dir_link(&String::from("."), ip.data().inum, TYPE_DIR) ||
+            !ip.dir_link(&String::from(".."), dp.data().inum, TYPE_DIR) {
             panic!("create dots");
         }
     }
 
-    if !dp.dir_link(&name, ip.data().inum) {
+    if !dp.dir_link(&name, ip.data().inum, types) {
         panic!("create: dirlink");
     }
 
@@ -179,27 +729,34 @@ pub fn sys_open() -> u64 {
 
     log.begin_op();
 
-    let (ip, guard) = if mode & OPEN_CREATE != 0 {
-        let result = create(&path, TYPE_FILE, 0, 0);
-        if result.is_none() {
-            log.end_op();
-            return u64::max_value();
+    // with OPEN_CREATE too, a symbolic link to an existing file opens that file,
+    // and one to a missing file creates it.
+    let result = if mode & OPEN_NOFOLLOW != 0 { find_inode_no_follow(&path) } else { find_inode(&path) };
+    let (ip, guard) = match result {
+        Some(ip) => {
+            let guard = ip.lock();
+            // directories and links can only be read.
+            let read_only = mode & !OPEN_NOFOLLOW == OPEN_READ_ONLY;
+            if (ip.data().types == TYPE_DIR || ip.data().types == TYPE_SYMLINK) && !read_only {
+                ip.unlock_put(guard);
+                log.end_op();
+                return u64::max_value();
+            }
+            (ip, guard)
         }
-        result.unwrap()
-    } else {
-        let result = find_inode(&path);
-        if result.is_none() {
-            log.end_op();
-            return u64::max_value();
+        None if mode & OPEN_CREATE != 0 => {
+            let create_path = if mode & OPEN_NOFOLLOW != 0 { Some(path.clone()) } else { find_create_path(&path) };
+            let result = create_path.as_ref().and_then(|path| create(path, TYPE_FILE, 0, 0));
+            if result.is_none() {
+                log.end_op();
+                return u64::max_value();
+            }
+            result.unwrap()
         }
-        let ip = result.unwrap();
-        let guard = ip.lock();
-        if ip.data().types == TYPE_DIR && mode != OPEN_READ_ONLY {
-            ip.unlock_put(guard);
+        None => {
             log.end_op();
             return u64::max_value();
         }
-        (ip, guard)
     };
 
     if ip.data().types == TYPE_DEVICE && ip.data().major >= MAX_DEV_NUMBER as u16 {
@@ -208,6 +765,18 @@ pub fn sys_open() -> u64 {
         return u64::max_value();
     }
 
+    let readable = mode & OPEN_WRITE_ONLY == 0;
+    let writable = (mode & OPEN_WRITE_ONLY != 0) || (mode & OPEN_READ_WRITE != 0);
+    let truncate = mode & OPEN_TRUNC != 0 && ip.data().types == TYPE_FILE;
+    // truncating writes the file, however it is opened.
+    let access = if readable { ACCESS_READ } else { 0 } | if writable || truncate { ACCESS_WRITE } else { 0 };
+    // a link's own mode doesn't matter.
+    if ip.data().types != TYPE_SYMLINK && !ip.permitted(access) {
+        ip.unlock_put(guard);
+        log.end_op();
+        return u64::max_value();
+    }
+
     let file = FILE_TABLE.alloc();
     if file.is_none() {
         ip.unlock_put(guard);
@@ -231,18 +800,21 @@ pub fn sys_open() -> u64 {
     } else {
         file.data().types = INODE;
         file.data().off = 0;
+        file.data().next_off = 0;
+        file.data().ahead = 0;
     }
     file.data().ip = Some(ip);
-    file.data().readable = mode & OPEN_WRITE_ONLY == 0;
-    file.data().writable = (mode & OPEN_WRITE_ONLY != 0) || (mode & OPEN_READ_WRITE != 0);
-
-    if mode & OPEN_TRUNC != 0 && ip.data().types == TYPE_FILE {
-        ip.truncate();
-    }
+    file.data().readable = readable;
+    file.data().writable = writable;
+    file.data().append = mode & OPEN_APPEND != 0;
 
     ip.unlock(guard);
     log.end_op();
 
+    if truncate {
+        ip.truncate();
+    }
+
     return fd as u64;
 }
 
@@ -284,6 +856,11 @@ pub fn sys_mknod() -> u64 {
     }
     let path = path.unwrap();
 
+    // only root may make device nodes.
+    if CPU_MANAGER.my_proc().info().euid != ROOT_UID {
+        return u64::max_value();
+    }
+
     log.begin_op();
 
     let result = create(&path, TYPE_DEVICE, major, minor);
@@ -300,6 +877,82 @@ pub fn sys_mknod() -> u64 {
     return 0;
 }
 
+// Create path as a symbolic link to target,
+// which need not exist.
+pub fn sys_symlink() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let target = read_arg_string(0);
+    let path = read_arg_string(1);
+    if target.is_none() || path.is_none() {
+        return u64::max_value();
+    }
+    let (target, path) = (target.unwrap(), path.unwrap());
+    if target.is_empty() || target.len() > BLOCK_SIZE {
+        return u64::max_value();
+    }
+
+    log.begin_op();
+
+    let result = create(&path, TYPE_SYMLINK, 0, 0);
+    if result.is_none() {
+        log.end_op();
+        return u64::max_value();
+    }
+    let (ip, guard) = result.unwrap();
+
+    let written = ip.write(false, target.as_ptr() as usize, 0, target.len() as u32);
+    ip.unlock_put(guard);
+    log.end_op();
+
+    return if written == target.len() as u32 {
+        0
+    } else {
+        u64::max_value()
+    };
+}
+
+// Copy the target of the symbolic link path into the user buffer,
+// without a terminating 0. Returns the number of bytes copied.
+pub fn sys_readlink() -> u64 {
+    let log = unsafe { &mut LOG };
+
+    let path = read_arg_string(0);
+    if path.is_none() {
+        return u64::max_value();
+    }
+    let path = path.unwrap();
+    let addr = read_arg_usize(1);
+    let size = read_arg_usize(2);
+
+    log.begin_op();
+
+    let ip = match find_inode_no_follow(&path) {
+        Some(ip) => { ip }
+        None => {
+            log.end_op();
+            return u64::max_value();
+        }
+    };
+    let guard = ip.lock();
+    if ip.data().types != TYPE_SYMLINK {
+        ip.unlock_put(guard);
+        log.end_op();
+        return u64::max_value();
+    }
+
+    let n = min(size, ip.data().size as usize) as u32;
+    let read = ip.read(true, addr, 0, n);
+    ip.unlock_put(guard);
+    log.end_op();
+
+    return if read == n {
+        n as u64
+    } else {
+        u64::max_value()
+    };
+}
+
 pub fn sys_chdir() -> u64 {
     let log = unsafe { &mut LOG };
 
@@ -321,7 +974,7 @@ pub fn sys_chdir() -> u64 {
     let ip = ip.unwrap();
     let guard = ip.lock();
 
-    if ip.data().types != TYPE_DIR {
+    if ip.data().types != TYPE_DIR || !ip.permitted(ACCESS_EXEC) {
         ip.unlock_put(guard);
         log.end_op();
         return u64::max_value();
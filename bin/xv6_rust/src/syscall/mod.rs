@@ -3,15 +3,18 @@ use alloc::string::String;
 
 use lazy_static::lazy_static;
 
+use crate::file_system::inode::ICACHE;
 use crate::memory::copy_in_string;
 use crate::process::CPU_MANAGER;
-use crate::syscall::exec::sys_exec;
-use crate::syscall::file::{sys_close, sys_dup, sys_mknod, sys_open, sys_chdir, sys_read, sys_write, sys_fstat, sys_mkdir};
-use crate::syscall::process::{sys_exit, sys_fork, sys_sbrk, sys_wait};
+use crate::syscall::exec::{sys_exec, sys_execve};
+use crate::syscall::file::{sys_close, sys_dup, sys_mknod, sys_open, sys_chdir, sys_read, sys_write, sys_fstat, sys_mkdir, sys_unlink, sys_link, sys_symlink, sys_readlink, sys_rename, sys_lseek, sys_pread, sys_pwrite, sys_utimes, sys_chmod, sys_chown, sys_fsync, sys_sync};
+use crate::syscall::process::{sys_exit, sys_fork, sys_getgid, sys_geteuid, sys_getrlimit, sys_getuid, sys_randomize, sys_sbrk, sys_setgid, sys_setrlimit, sys_setuid, sys_uptime, sys_vmprint, sys_wait};
+use crate::syscall::shared_memory::{sys_shmat, sys_shmctl, sys_shmdt, sys_shmget};
 
 pub mod exec;
 pub mod file;
 pub mod process;
+pub mod shared_memory;
 
 #[derive(Clone)]
 pub struct SystemCall {
@@ -29,11 +32,39 @@ static SYSCALL_FSTAT: SystemCall = SystemCall { name: "stat", id: 8, func: sys_f
 static SYSCALL_CHDIR: SystemCall = SystemCall { name: "chdir", id: 9, func: sys_chdir };
 static SYSCALL_DUP: SystemCall = SystemCall { name: "dup", id: 10, func: sys_dup };
 static SYSCALL_SBRK: SystemCall = SystemCall { name: "sbrk", id: 12, func: sys_sbrk };
+static SYSCALL_UPTIME: SystemCall = SystemCall { name: "uptime", id: 14, func: sys_uptime };
 static SYSCALL_OPEN: SystemCall = SystemCall { name: "open", id: 15, func: sys_open };
 static SYSCALL_WRITE: SystemCall = SystemCall { name: "write", id: 16, func: sys_write };
 static SYSCALL_MKNOD: SystemCall = SystemCall { name: "mknod", id: 17, func: sys_mknod };
+static SYSCALL_UNLINK: SystemCall = SystemCall { name: "unlink", id: 18, func: sys_unlink };
+static SYSCALL_LINK: SystemCall = SystemCall { name: "link", id: 19, func: sys_link };
 static SYSCALL_MKDIR: SystemCall = SystemCall { name: "mkdir", id: 20, func: sys_mkdir };
 static SYSCALL_CLOSE: SystemCall = SystemCall { name: "close", id: 21, func: sys_close };
+static SYSCALL_RANDOMIZE: SystemCall = SystemCall { name: "randomize", id: 22, func: sys_randomize };
+static SYSCALL_SHMGET: SystemCall = SystemCall { name: "shmget", id: 23, func: sys_shmget };
+static SYSCALL_SHMAT: SystemCall = SystemCall { name: "shmat", id: 24, func: sys_shmat };
+static SYSCALL_SHMDT: SystemCall = SystemCall { name: "shmdt", id: 25, func: sys_shmdt };
+static SYSCALL_SHMCTL: SystemCall = SystemCall { name: "shmctl", id: 26, func: sys_shmctl };
+static SYSCALL_VMPRINT: SystemCall = SystemCall { name: "vmprint", id: 27, func: sys_vmprint };
+static SYSCALL_GETRLIMIT: SystemCall = SystemCall { name: "getrlimit", id: 28, func: sys_getrlimit };
+static SYSCALL_SETRLIMIT: SystemCall = SystemCall { name: "setrlimit", id: 29, func: sys_setrlimit };
+static SYSCALL_EXECVE: SystemCall = SystemCall { name: "execve", id: 30, func: sys_execve };
+static SYSCALL_SYMLINK: SystemCall = SystemCall { name: "symlink", id: 31, func: sys_symlink };
+static SYSCALL_READLINK: SystemCall = SystemCall { name: "readlink", id: 32, func: sys_readlink };
+static SYSCALL_RENAME: SystemCall = SystemCall { name: "rename", id: 33, func: sys_rename };
+static SYSCALL_LSEEK: SystemCall = SystemCall { name: "lseek", id: 34, func: sys_lseek };
+static SYSCALL_PREAD: SystemCall = SystemCall { name: "pread", id: 35, func: sys_pread };
+static SYSCALL_PWRITE: SystemCall = SystemCall { name: "pwrite", id: 36, func: sys_pwrite };
+static SYSCALL_UTIMES: SystemCall = SystemCall { name: "utimes", id: 37, func: sys_utimes };
+static SYSCALL_SETUID: SystemCall = SystemCall { name: "setuid", id: 38, func: sys_setuid };
+static SYSCALL_GETUID: SystemCall = SystemCall { name: "getuid", id: 39, func: sys_getuid };
+static SYSCALL_GETEUID: SystemCall = SystemCall { name: "geteuid", id: 40, func: sys_geteuid };
+static SYSCALL_SETGID: SystemCall = SystemCall { name: "setgid", id: 41, func: sys_setgid };
+static SYSCALL_GETGID: SystemCall = SystemCall { name: "getgid", id: 42, func: sys_getgid };
+static SYSCALL_CHMOD: SystemCall = SystemCall { name: "chmod", id: 43, func: sys_chmod };
+static SYSCALL_CHOWN: SystemCall = SystemCall { name: "chown", id: 44, func: sys_chown };
+static SYSCALL_FSYNC: SystemCall = SystemCall { name: "fsync", id: 45, func: sys_fsync };
+static SYSCALL_SYNC: SystemCall = SystemCall { name: "sync", id: 46, func: sys_sync };
 
 lazy_static! {
     pub static ref SYSTEM_CALL: BTreeMap<usize, SystemCall> = {
@@ -51,11 +82,39 @@ lazy_static! {
         insert(SYSCALL_CHDIR.clone());
         insert(SYSCALL_DUP.clone());
         insert(SYSCALL_SBRK.clone());
+        insert(SYSCALL_UPTIME.clone());
         insert(SYSCALL_OPEN.clone());
         insert(SYSCALL_WRITE.clone());
         insert(SYSCALL_MKNOD.clone());
+        insert(SYSCALL_UNLINK.clone());
+        insert(SYSCALL_LINK.clone());
         insert(SYSCALL_MKDIR.clone());
         insert(SYSCALL_CLOSE.clone());
+        insert(SYSCALL_RANDOMIZE.clone());
+        insert(SYSCALL_SHMGET.clone());
+        insert(SYSCALL_SHMAT.clone());
+        insert(SYSCALL_SHMDT.clone());
+        insert(SYSCALL_SHMCTL.clone());
+        insert(SYSCALL_VMPRINT.clone());
+        insert(SYSCALL_GETRLIMIT.clone());
+        insert(SYSCALL_SETRLIMIT.clone());
+        insert(SYSCALL_EXECVE.clone());
+        insert(SYSCALL_SYMLINK.clone());
+        insert(SYSCALL_READLINK.clone());
+        insert(SYSCALL_RENAME.clone());
+        insert(SYSCALL_LSEEK.clone());
+        insert(SYSCALL_PREAD.clone());
+        insert(SYSCALL_PWRITE.clone());
+        insert(SYSCALL_UTIMES.clone());
+        insert(SYSCALL_SETUID.clone());
+        insert(SYSCALL_GETUID.clone());
+        insert(SYSCALL_GETEUID.clone());
+        insert(SYSCALL_SETGID.clone());
+        insert(SYSCALL_GETGID.clone());
+        insert(SYSCALL_CHMOD.clone());
+        insert(SYSCALL_CHOWN.clone());
+        insert(SYSCALL_FSYNC.clone());
+        insert(SYSCALL_SYNC.clone());
         map
     };
 }
@@ -78,6 +137,7 @@ pub fn system_call() {
             u64::max_value()
         }
     };
+    ICACHE.reclaim();
 }
 
 fn read_arg_content(pos: usize) -> u64 {
@@ -1,8 +1,12 @@
 use core::intrinsics::size_of;
 
-use crate::memory::either_copy_out;
+use file_system_lib::ROOT_UID;
+use param_lib::{ResourceLimit, RLIMIT_STACK};
+
+use crate::memory::{either_copy_in, either_copy_out};
 use crate::process::{CPU_MANAGER, PROCESS_MANAGER};
 use crate::syscall::read_arg_usize;
+use crate::trap::TICKS;
 
 pub fn sys_exit() -> u64 {
     let exit_code = read_arg_usize(0);
@@ -34,4 +38,109 @@ pub fn sys_sbrk() -> u64 {
     }
 
     return start as u64;
-}
\ No newline at end of file
+}
+
+// return how many clock tick interrupts have occurred
+// since start.
+pub fn sys_uptime() -> u64 {
+    let ticks = TICKS.lock();
+    let result = *ticks;
+    drop(ticks);
+    result as u64
+}
+
+// Print the page table of the calling process on the console.
+pub fn sys_vmprint() -> u64 {
+    let process = CPU_MANAGER.my_proc();
+    process.data().page_table.as_ref().unwrap().print();
+    0
+}
+
+// Turn layout randomization of later exec()s on or off,
+// for this process and the children it forks from now on.
+// Returns the previous setting.
+pub fn sys_randomize() -> u64 {
+    let data = CPU_MANAGER.my_proc().data();
+    let previous = data.randomize;
+    data.randomize = read_arg_usize(0) != 0;
+    previous as u64
+}
+
+// Root sets both the real and the effective user id;
+// anyone else may only switch back to the real one.
+pub fn sys_setuid() -> u64 {
+    let uid = read_arg_usize(0) as u32;
+    let info = CPU_MANAGER.my_proc().info();
+    if info.euid == ROOT_UID {
+        info.uid = uid;
+        info.euid = uid;
+    } else if uid == info.uid {
+        info.euid = uid;
+    } else {
+        return u64::max_value();
+    }
+    0
+}
+
+pub fn sys_getuid() -> u64 {
+    CPU_MANAGER.my_proc().info().uid as u64
+}
+
+pub fn sys_geteuid() -> u64 {
+    CPU_MANAGER.my_proc().info().euid as u64
+}
+
+pub fn sys_setgid() -> u64 {
+    let gid = read_arg_usize(0) as u32;
+    let info = CPU_MANAGER.my_proc().info();
+    if info.euid == ROOT_UID {
+        info.gid = gid;
+        info.egid = gid;
+    } else if gid == info.gid {
+        info.egid = gid;
+    } else {
+        return u64::max_value();
+    }
+    0
+}
+
+pub fn sys_getgid() -> u64 {
+    CPU_MANAGER.my_proc().info().gid as u64
+}
+
+pub fn sys_getrlimit() -> u64 {
+    let resource = read_arg_usize(0);
+    let addr = read_arg_usize(1);
+    if resource != RLIMIT_STACK {
+        return u64::max_value();
+    }
+
+    let limit = CPU_MANAGER.my_proc().data().stack_limit;
+    if either_copy_out(true, addr, &limit as *const _ as usize, size_of::<ResourceLimit>()) {
+        0
+    } else {
+        u64::max_value()
+    }
+}
+
+// The current limit can be set anywhere up to the maximum,
+// and the maximum can only be lowered.
+// A smaller stack limit doesn't shrink a stack that is already bigger.
+pub fn sys_setrlimit() -> u64 {
+    let resource = read_arg_usize(0);
+    let addr = read_arg_usize(1);
+    if resource != RLIMIT_STACK {
+        return u64::max_value();
+    }
+
+    let mut limit = ResourceLimit::new();
+    if !either_copy_in(true, &mut limit as *mut _ as usize, addr, size_of::<ResourceLimit>()) {
+        return u64::max_value();
+    }
+    let data = CPU_MANAGER.my_proc().data();
+    if limit.current > limit.maximum || limit.maximum > data.stack_limit.maximum {
+        return u64::max_value();
+    }
+    data.stack_limit = limit;
+    0
+}
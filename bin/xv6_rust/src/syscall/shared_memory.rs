@@ -0,0 +1,64 @@
+use core::intrinsics::size_of;
+
+use file_control_lib::{IPC_RMID, IPC_STAT, SharedMemoryStatus};
+
+use crate::memory::either_copy_out;
+use crate::memory::shared_memory::SHARED_MEMORY;
+use crate::process::CPU_MANAGER;
+use crate::syscall::read_arg_usize;
+
+pub fn sys_shmget() -> u64 {
+    let key = read_arg_usize(0);
+    let size = read_arg_usize(1);
+    let flags = read_arg_usize(2);
+
+    SHARED_MEMORY.get(key, size, flags).map_or(u64::max_value(), |it| it as u64)
+}
+
+// the kernel chooses where to attach; the address must be 0.
+pub fn sys_shmat() -> u64 {
+    let id = read_arg_usize(0);
+    let address = read_arg_usize(1);
+    let flags = read_arg_usize(2);
+    if address != 0 {
+        return u64::max_value();
+    }
+
+    let data = CPU_MANAGER.my_proc().data();
+    SHARED_MEMORY.attach(data, id, flags).map_or(u64::max_value(), |it| it as u64)
+}
+
+pub fn sys_shmdt() -> u64 {
+    let address = read_arg_usize(0);
+
+    let data = CPU_MANAGER.my_proc().data();
+    if SHARED_MEMORY.detach(data, address) {
+        0
+    } else {
+        u64::max_value()
+    }
+}
+
+pub fn sys_shmctl() -> u64 {
+    let id = read_arg_usize(0);
+    let command = read_arg_usize(1);
+    let addr = read_arg_usize(2);
+
+    match command {
+        IPC_RMID => {
+            if SHARED_MEMORY.remove(id) { 0 } else { u64::max_value() }
+        }
+        IPC_STAT => {
+            let status: SharedMemoryStatus = match SHARED_MEMORY.stat(id) {
+                Some(status) => { status }
+                None => { return u64::max_value(); }
+            };
+            if either_copy_out(true, addr, &status as *const _ as usize, size_of::<SharedMemoryStatus>()) {
+                0
+            } else {
+                u64::max_value()
+            }
+        }
+        _ => { u64::max_value() }
+    }
+}
@@ -1,11 +1,15 @@
 use core::mem::transmute;
 
 use crate::console::uart::uart_intr;
+use crate::device_tree::machine;
 use crate::driver::DISK;
-use crate::memory::layout::{TRAMPOLINE, TRAPFRAME, UART0_IRQ, VIRTIO0_IRQ};
+use crate::memory::layout::{TRAMPOLINE, TRAPFRAME};
+use crate::memory;
 use crate::memory::make_satp;
+use crate::memory::{kernel_heap, swap};
 use crate::plic::{plic_claim, plic_complete};
 use crate::process::{cpu_id, CPU_MANAGER, PROCESS_MANAGER};
+use crate::random;
 use crate::process::process::ProcessState::RUNNING;
 use crate::riscv::{intr_get, intr_off, intr_on, read_satp, read_scause, read_sepc, read_sip, read_sstatus, read_stval, read_tp, SSTATUS_SPIE, SSTATUS_SPP, write_sepc, write_sip, write_sstatus, write_stvec};
 use crate::spin_lock::SpinLock;
@@ -64,6 +68,8 @@ pub unsafe fn usertrap() {
         system_call();
     } else if which_dev != 0 {
         // ok
+    } else if is_page_fault(read_scause()) && swap::handle_page_fault(read_stval()) {
+        // the page was swapped back in; retry the instruction.
     } else {
         println!("unexpected scause {:x} pid={}", read_scause(), process.info().pid);
         println!("sepc={:x} stval={:x}", read_sepc(), read_stval());
@@ -129,7 +135,7 @@ pub unsafe fn user_trap_return() {
 
 #[no_mangle]
 pub unsafe fn kerneltrap() {
-    let sepc = read_sepc();
+    let mut sepc = read_sepc();
     let sstatus = read_sstatus();
     let scause = read_scause();
 
@@ -142,7 +148,13 @@ pub unsafe fn kerneltrap() {
     }
 
     let which_dev = dev_intr();
-    if which_dev == 0 {
+    if which_dev == 0 && is_page_fault(scause) && kernel_heap::handle_page_fault(read_stval()) {
+        // another hart grew the kernel heap; retry the instruction.
+    } else if which_dev == 0 && is_page_fault(scause) && memory::user_copy_fixup(sepc).is_some() {
+        // copy_in() or copy_out() hit a bad or swapped-out user page;
+        // fail the direct copy so they walk the page table instead.
+        sepc = memory::user_copy_fixup(sepc).unwrap();
+    } else if which_dev == 0 {
         println!("scause {}", scause);
         println!("sepc={:x} stval={:x}", read_sepc(), read_stval());
         panic!("kerneltrap");
@@ -158,6 +170,11 @@ pub unsafe fn kerneltrap() {
     write_sstatus(sstatus);
 }
 
+// instruction, load or store/AMO page fault.
+fn is_page_fault(scause: usize) -> bool {
+    scause == 12 || scause == 13 || scause == 15
+}
+
 unsafe fn clock_intr() {
     *TICKS.lock() += 1;
     PROCESS_MANAGER.wake_up(&TICKS as *const _ as usize)
@@ -175,10 +192,11 @@ unsafe fn dev_intr() -> usize {
 
         // irq indicates which device interrupted.
         let irq = plic_claim();
+        random::add_interrupt_entropy(irq);
 
-        if irq as usize == UART0_IRQ {
+        if irq as usize == machine().uart_irq {
             uart_intr();
-        } else if irq as usize == VIRTIO0_IRQ {
+        } else if irq as usize == machine().virtio_irq {
             DISK.intr();
         } else if irq != 0 {
             println!("unexpected interrupt irq={}", irq);
@@ -6,5 +6,59 @@ pub const OPEN_WRITE_ONLY: usize = 0x001;
 pub const OPEN_READ_WRITE: usize = 0x002;
 pub const OPEN_CREATE: usize = 0x200;
 pub const OPEN_TRUNC: usize = 0x400;
+// open a symbolic link itself instead of following it
+pub const OPEN_NOFOLLOW: usize = 0x800;
+// every write goes to the end of the file
+pub const OPEN_APPEND: usize = 0x1000;
 
-pub const CONSOLE_ID: usize = 1;
\ No newline at end of file
+// lseek() whence
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+pub const CONSOLE_ID: usize = 1;
+
+// shmget() key and flags
+pub const IPC_PRIVATE: usize = 0;
+pub const IPC_CREAT: usize = 0x200;
+pub const IPC_EXCL: usize = 0x400;
+
+// shmat() flags
+pub const SHM_RDONLY: usize = 0x001;
+
+// shmctl() commands
+pub const IPC_RMID: usize = 0;
+pub const IPC_STAT: usize = 2;
+
+#[repr(C)]
+pub struct SharedMemoryStatus {
+    pub key: usize,
+    pub size: usize,
+    // number of current attaches
+    pub attach_number: usize,
+}
+
+impl SharedMemoryStatus {
+    pub const fn new() -> SharedMemoryStatus {
+        SharedMemoryStatus {
+            key: 0,
+            size: 0,
+            attach_number: 0,
+        }
+    }
+}
+
+impl Default for SharedMemoryStatus {
+    fn default() -> SharedMemoryStatus {
+        SharedMemoryStatus::new()
+    }
+}
+
+// auxiliary vector entries on the initial stack
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
@@ -18,6 +18,10 @@ pub struct SuperBlock {
     pub log_start: u32,
     pub inode_start: u32,
     pub block_map_start: u32,
+    pub swap_start: u32,
+    pub swap_number: u32,
+    // FEATURE_* flags
+    pub features: u32,
 }
 
 impl SuperBlock {
@@ -31,13 +35,30 @@ impl SuperBlock {
             log_start: 0,
             inode_start: 0,
             block_map_start: 0,
+            swap_start: 0,
+            swap_number: 0,
+            features: 0,
         }
     }
+
+    pub fn long_names(&self) -> bool {
+        self.features & FEATURE_LONG_NAMES != 0
+    }
 }
 
-pub const DIRECT_COUNT: usize = 12;
+// Directories hold LongDirent records instead of Dirents.
+pub const FEATURE_LONG_NAMES: u32 = 0x1;
+
+// addr[] holds DIRECT_COUNT direct block numbers, then the number of
+// an indirect block, then that of a doubly-indirect block.
+pub const DIRECT_COUNT: usize = 11;
 pub const INDIRECT_COUNT: usize = BLOCK_SIZE / size_of::<u32>();
-pub const MAX_FILE_COUNT: usize = DIRECT_COUNT + INDIRECT_COUNT;
+pub const DOUBLE_INDIRECT_COUNT: usize = INDIRECT_COUNT * INDIRECT_COUNT;
+pub const MAX_FILE_COUNT: usize = DIRECT_COUNT + INDIRECT_COUNT + DOUBLE_INDIRECT_COUNT;
+// indexes in addr[]
+pub const INDIRECT: usize = DIRECT_COUNT;
+pub const DOUBLE_INDIRECT: usize = DIRECT_COUNT + 1;
+pub const ADDR_COUNT: usize = DIRECT_COUNT + 2;
 
 #[repr(C)]
 pub struct INodeDisk {
@@ -47,7 +68,19 @@ pub struct INodeDisk {
     pub nlink: u16,
 
     pub size: u32,
-    pub addr: [u32; DIRECT_COUNT + 1],
+    pub addr: [u32; ADDR_COUNT],
+
+    // seconds since the Unix epoch of the last access,
+    // the last change to the content and the last change to the inode
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    // permission bits, MODE_MASK of them
+    pub mode: u32,
+    // pads INodeDisk to 128 bytes, so that IPB is a whole number
+    pub spare: [u32; 7],
 }
 
 impl INodeDisk {
@@ -58,7 +91,14 @@ impl INodeDisk {
             minor: 0,
             nlink: 0,
             size: 0,
-            addr: [0; DIRECT_COUNT + 1],
+            addr: [0; ADDR_COUNT],
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0,
+            spare: [0; 7],
         }
     }
 }
@@ -99,9 +139,76 @@ impl Dirent {
     }
 }
 
+// With FEATURE_LONG_NAMES, a directory is a sequence of variable-length
+// records instead, each a LongDirent followed by name_length bytes of name.
+// Records are 4-byte aligned and never cross a block, the last one of
+// a block reaching to its end, so a directory is a whole number of blocks.
+// A free record has inum 0; a removed record's space joins the one before.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+#[repr(C)]
+pub struct LongDirent {
+    pub inum: u32,
+    // bytes from this record to the next
+    pub record_length: u16,
+    pub name_length: u8,
+    // the TYPE_* of the inode, truncated to a byte
+    pub types: u8,
+}
+
+impl LongDirent {
+    pub const fn new() -> LongDirent {
+        LongDirent {
+            inum: 0,
+            record_length: 0,
+            name_length: 0,
+            types: 0,
+        }
+    }
+}
+
+// Bytes a record with a name of name_length takes at least.
+pub const fn record_length(name_length: usize) -> usize {
+    (size_of::<LongDirent>() + name_length + 3) & !3
+}
+
+// Whether the directory that starts with data, whose first entry is
+// always ".", is in the long-name format. In the fixed format, the name
+// would be where record_length and name_length are.
+pub fn is_long_name_directory(data: &[u8]) -> bool {
+    let header = size_of::<LongDirent>();
+    data.len() > header &&
+        u16::from_le_bytes([data[4], data[5]]) as usize == record_length(1) &&
+        data[6] == 1 && data[header] == b'.'
+}
+
 pub const TYPE_DIR: u16 = 1;
 pub const TYPE_FILE: u16 = 2;
 pub const TYPE_DEVICE: u16 = 3;
+// the content is the path the link points to
+pub const TYPE_SYMLINK: u16 = 4;
+
+// The permission bits of an inode: rwx for the owner, the group and
+// others, from the high bits down, under the setuid and setgid bits,
+// which make exec() run the file as its owner or group.
+pub const MODE_SETUID: u32 = 0o4000;
+pub const MODE_SETGID: u32 = 0o2000;
+pub const MODE_MASK: u32 = 0o7777;
+
+// what a user asks to do with an inode, as one rwx group of the mode;
+// for a directory, exec means looking up names in it.
+pub const ACCESS_READ: u32 = 4;
+pub const ACCESS_WRITE: u32 = 2;
+pub const ACCESS_EXEC: u32 = 1;
+
+// modes of new inodes
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+pub const DEFAULT_DIR_MODE: u32 = 0o755;
+pub const DEFAULT_DEVICE_MODE: u32 = 0o666;
+pub const DEFAULT_SYMLINK_MODE: u32 = 0o777;
+
+// root passes every permission check
+pub const ROOT_UID: u32 = 0;
 
 #[repr(C)]
 pub struct FileStatus {
@@ -109,7 +216,13 @@ pub struct FileStatus {
     pub ino: u32,
     pub types: u16,
     pub nlink: u16,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
     pub size: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
 }
 
 impl FileStatus {
@@ -119,7 +232,13 @@ impl FileStatus {
             ino: 0,
             types: 0,
             nlink: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
             size: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
         }
     }
 }
@@ -12,10 +12,63 @@ pub const MAX_INODE_NUMBER: usize = 50;
 pub const MAX_DEV_NUMBER: usize = 10;
 
 pub const ROOT_DEV: u32 = 1;
+// most symbolic links followed in one path lookup
+pub const MAX_SYMLINK_DEPTH: usize = 10;
 
-pub const MAX_ARG: usize = 32;
+// most strings in each of the argv and envp vectors of exec
+pub const MAX_ARG: usize = 1024;
+// most bytes the exec arguments, environment and auxiliary vector may take on the stack
+pub const MAX_ARG_SIZE: usize = 128 * 1024;
+// most blocks any FS op writes, other than a write to a file
 pub const MAX_OP_BLOCKS: usize = 10;
-pub const LOG_SIZE: usize = 3 * MAX_OP_BLOCKS;
-pub const BUFFER_SIZE: usize = 3 * MAX_OP_BLOCKS;
+// blocks of the on-disk log, header included, that mkfs makes
+pub const LOG_SIZE: usize = 128;
+// buffers in the block cache, unless bootargs say buffers=N;
+// the log may pin up to three transactions' worth of them
+pub const BUFFER_SIZE: usize = 3 * LOG_SIZE + 3 * MAX_OP_BLOCKS;
+// fewest buffers the log can work with
+pub const MIN_BUFFER_SIZE: usize = 6 * MAX_OP_BLOCKS;
+// most blocks read ahead of a file being read sequentially
+pub const MAX_READ_AHEAD: usize = 8;
+// clock ticks between the flusher's commits, about 3 seconds
+pub const FLUSH_INTERVAL: usize = 30;
 
 pub const FILE_SYSTEM_SIZE: u32 = 200000;
+// blocks reserved for swapped-out user pages at the end of the disk
+pub const SWAP_SIZE: u32 = 8192;
+
+// shared memory segments per system
+pub const MAX_SHM_NUMBER: usize = 16;
+// shared memory segments attached per process
+pub const MAX_SHM_ATTACH: usize = 8;
+// largest shared memory segment
+pub const MAX_SHM_SIZE: usize = 4 * 1024 * 1024;
+// default limit on the size of a user stack
+pub const STACK_LIMIT: usize = 8 * 1024 * 1024;
+
+// getrlimit()/setrlimit() resources
+pub const RLIMIT_STACK: usize = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ResourceLimit {
+    // the limit in effect, at most maximum
+    pub current: usize,
+    // how far current may be raised; can only be lowered
+    pub maximum: usize,
+}
+
+impl ResourceLimit {
+    pub const fn new() -> ResourceLimit {
+        ResourceLimit {
+            current: 0,
+            maximum: 0,
+        }
+    }
+}
+
+impl Default for ResourceLimit {
+    fn default() -> ResourceLimit {
+        ResourceLimit::new()
+    }
+}